@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use arb_bot::calc::{evaluation_pool, find_optimal_cycles};
+use arb_bot::constants::WETH;
+use arb_bot::contract_modules::uniswap_v2::gas_model::GasModel;
+use arb_bot::contract_modules::uniswap_v2::types::UniV2Pool;
+use arb_bot::cycles::CycleSearch;
+use arb_bot::helpers::address;
+use arb_bot::state::{PendingState, State};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ethers::types::{Address, U256, U64};
+
+const POOLS: usize = 100_000;
+const TOKENS: usize = 20_000;
+
+// Deterministic reserves, so every run evaluates the same graph
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) % bound
+    }
+}
+
+// Two WETH pools per token, the rest joins tokens to each other
+fn synthetic_pools() -> Vec<UniV2Pool> {
+    let mut rng = Lcg(1);
+    let weth = address(WETH);
+    let token = |index: usize| Address::from_low_u64_be(0x10000 + index as u64);
+
+    let mut pool = |index: usize, token0: Address, token1: Address| {
+        let depth = U256::exp10(18 + rng.next(6) as usize);
+        UniV2Pool {
+            address: Address::from_low_u64_be(index as u64 + 1),
+            token0,
+            token1,
+            reserve0: depth,
+            // up to 3% apart, so some cycles pay
+            reserve1: depth * (985 + rng.next(31)) / 1000,
+            router_fee: U256::from(997),
+            router_fee_denominator: U256::from(1000),
+            fees0: U256::zero(),
+            fees1: U256::zero(),
+            tradeable: true,
+        }
+    };
+
+    (0..POOLS)
+        .map(|index| {
+            if index < 2 * TOKENS {
+                pool(index, weth, token(index % TOKENS))
+            } else {
+                let a = index % TOKENS;
+                let b = (a * 7919 + index / TOKENS) % TOKENS;
+                pool(index, token(a), token(if a == b { (b + 1) % TOKENS } else { b }))
+            }
+        })
+        .collect()
+}
+
+fn evaluation(c: &mut Criterion) {
+    let pools = synthetic_pools();
+    // pinned to 3 hops whatever the default is: every cycle is copied under each of its
+    // pairs, longer cycles on this graph take several GB before the first iteration
+    let search = CycleSearch {
+        max_hops: 3,
+        start_tokens: vec![address(WETH)],
+        ..Default::default()
+    };
+    let state = State::new_state(&pools, U64::zero(), search);
+    let pending = PendingState::new(&state);
+    let inventory = HashMap::from([(address(WETH), U256::exp10(22))]);
+    let gas_model = GasModel::default();
+
+    // a cycle is listed under each of its pairs, count it under its first
+    let cycles = state
+        .cycles_mapping
+        .iter()
+        .flat_map(|(pair, cycles)| cycles.iter().map(move |cycle| (pair, cycle)))
+        .filter(|(pair, cycle)| state.index_mapping[&cycle[0].address] == **pair)
+        .count();
+    let mut group = c.benchmark_group("find_optimal_cycles");
+    group.sample_size(10);
+    // both directions of each cycle are evaluated
+    group.throughput(Throughput::Elements(cycles as u64 * 2));
+
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    let mut thread_counts = vec![1];
+    if cores > 1 {
+        thread_counts.push(cores);
+    }
+
+    for threads in thread_counts {
+        let pool = evaluation_pool(threads).unwrap();
+        let name = if threads == 1 { "serial" } else { "parallel" };
+
+        group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, _| {
+            b.iter(|| {
+                pool.install(|| {
+                    find_optimal_cycles(&pending, None, &inventory, &gas_model, U256::zero())
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, evaluation);
+criterion_main!(benches);
@@ -1,8 +1,12 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use crate::utils;
-use ethers::prelude::{k256::ecdsa::SigningKey, *};
-use tokio::sync::RwLock;
+use crate::bribe::{BribeStats, BribeStrategy};
+use crate::config::BotConfig;
+use crate::egress::EgressClient;
+use crate::signer::BotSigner;
+use crate::simulation::SimulationCache;
+use crate::states::wallet_state::WalletPool;
+use ethers::prelude::*;
 
 
 
@@ -10,33 +14,46 @@ use tokio::sync::RwLock;
 #[derive(Debug, Clone)]
 pub struct BackRunner {
     pub multicall_address: Address,
-    pub searcher_wallet: Wallet<SigningKey>,
-    pub nonce: Arc<RwLock<U256>>,
+    pub wallets: Arc<WalletPool>,
+    /// Flashbots identity signing relay requests
+    pub bundle_signer: BotSigner,
+    pub egress: EgressClient,
+    pub settings: Arc<BotConfig>,
+    pub simulation: SimulationCache,
+    /// Prices the bribe of each arbitrage, from `[bribe]`
+    pub bribe: Arc<dyn BribeStrategy>,
+    pub bribe_stats: Arc<Mutex<BribeStats>>,
 }
 
 impl BackRunner {
     // Create a new `SandwichMaker` instance
-    pub async fn new() -> Self {
-        let multicall_address = utils::dotenv::get_multicall_contract_address();
-        let searcher_wallet = utils::dotenv::get_searcher_wallet();
+    pub async fn new(
+        settings: Arc<BotConfig>,
+        client: &Arc<Provider<Ws>>,
+        egress: EgressClient,
+        searchers: Vec<BotSigner>,
+        bundle_signer: BotSigner,
+        bribe_stats: Arc<Mutex<BribeStats>>,
+    ) -> Self {
+        let multicall_address = settings.multicall_contract;
 
-        let client = utils::create_websocket_client().await.unwrap();
-
-        let nonce = if let Ok(n) = client
-            .get_transaction_count(searcher_wallet.address(), None)
-            .await
-        {
-            n
+        let wallets = if let Ok(wallets) = WalletPool::connect(searchers, client.as_ref()).await {
+            wallets
         } else {
-            panic!("Failed to get searcher wallet nonce...");
+            panic!("Failed to get searcher wallet nonces...");
         };
 
-        let nonce = Arc::new(RwLock::new(nonce));
+        let bribe = Arc::from(settings.bribe.strategy());
 
         Self {
             multicall_address,
-            searcher_wallet,
-            nonce,
+            wallets: Arc::new(wallets),
+            bundle_signer,
+            egress,
+            settings,
+            simulation: SimulationCache::default(),
+            bribe,
+            bribe_stats,
         }
     }
 }
@@ -0,0 +1,47 @@
+use arb_bot::config::BotConfig;
+use arb_bot::contract_modules::uniswap_v2::checkpoint::{
+    binary_to_json, json_to_binary, registry_hash,
+};
+use arb_bot::contract_modules::uniswap_v2::get_uni_v2;
+
+const USAGE: &str = "usage: convert_checkpoint <to-json|to-binary> <input> <output>";
+
+/// Convert checkpoints between the binary format the bot writes and JSON
+///
+/// `to-binary` stamps the chain id and dex registry of the current config into the header.
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let (input, output) = (args[2].as_str(), args[3].as_str());
+
+    match args[1].as_str() {
+        "to-json" => match binary_to_json(input, output) {
+            Ok(header) => println!(
+                "Wrote {} (format v{}, chain {}, block {}, registry {:?})",
+                output, header.version, header.chain_id, header.block, header.registry_hash
+            ),
+            Err(e) => fail(e),
+        },
+        "to-binary" => {
+            let settings = BotConfig::load().unwrap_or_else(|e| fail(e));
+            let registry = registry_hash(&get_uni_v2(&settings));
+
+            match json_to_binary(input, output, settings.chain_id, registry) {
+                Ok(_) => println!("Wrote {} for chain {}", output, settings.chain_id),
+                Err(e) => fail(e),
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn fail(e: impl std::fmt::Display) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1);
+}
@@ -0,0 +1,579 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::prelude::*;
+use std::sync::Mutex;
+
+use ethers::prelude::rand::{self, Rng};
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+
+use crate::bundle_errors::SendBundleError;
+
+/// Stored next to `gas_model.json`
+pub const BRIBE_STATS_PATH: &str = "./bribe_stats.json";
+
+/// Shares are in basis points of the revenue left after the base fee
+pub const BPS: u32 = 10_000;
+
+/// How an arbitrage pays the builder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PaymentMode {
+    /// Through the priority fee, paid even when the arbitrage reverts
+    #[default]
+    PriorityFee,
+    /// The executor transfers a share of the realized profit to `block.coinbase`, the
+    /// transaction only pays the base fee
+    Coinbase,
+}
+
+/// What an arbitrage leaves to split with the builder
+#[derive(Debug, Clone, Copy)]
+pub struct BribeInput {
+    pub gas_used: U256,
+    /// WETH the simulation made
+    pub revenue: U256,
+    /// Base fee of the target block
+    pub base_fee: U256,
+    pub payment: PaymentMode,
+}
+
+impl BribeInput {
+    fn gas_cost(&self) -> U256 {
+        self.gas_used * self.base_fee
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bribe {
+    /// Max fee of the transaction, the priority fee too unless paying the coinbase
+    pub max_fee: U256,
+    /// Share of the realized profit the executor pays the coinbase, in basis points
+    pub coinbase_bps: u32,
+    /// Strategy that priced it, outcomes are recorded under it
+    pub strategy: String,
+}
+
+impl Bribe {
+    /// Priority fee of the transaction
+    pub fn priority_fee(&self, input: &BribeInput) -> U256 {
+        match input.payment {
+            PaymentMode::PriorityFee => self.max_fee,
+            PaymentMode::Coinbase => U256::zero(),
+        }
+    }
+
+    /// What the builder gets above the base fee, at the simulated revenue
+    pub fn tip(&self, input: &BribeInput) -> U256 {
+        let priority = self.max_fee.saturating_sub(input.base_fee).min(self.priority_fee(input));
+        priority * input.gas_used + input.revenue * self.coinbase_bps / BPS
+    }
+}
+
+/// Prices the bribe of an arbitrage for the relay the bundle goes to
+pub trait BribeStrategy: Debug + Send + Sync {
+    fn price(&self, relay: &str, input: &BribeInput) -> Result<Bribe, SendBundleError>;
+
+    /// Whether the bundle this strategy priced for `relay` landed
+    fn record(&self, _relay: &str, _included: bool) {}
+}
+
+// Spend `share_ppb` of the revenue left after the base fee on the transaction, either as its
+// max fee or as the base fee and a coinbase transfer of what is left above it
+fn pay_share(input: &BribeInput, share_ppb: u64, strategy: String) -> Result<Bribe, SendBundleError> {
+    // arbitrage txfee is fixed, exclude it from bribe calculations
+    let revenue_minus_tx_fee = match input.revenue.checked_sub(input.gas_cost()) {
+        Some(revenue) => revenue,
+        None => return Err(SendBundleError::GasFeesNotCovered()),
+    };
+
+    let bribe_amount = revenue_minus_tx_fee * share_ppb / 1_000_000_000u64;
+    let max_fee = bribe_amount / input.gas_used;
+
+    if max_fee < input.base_fee {
+        return Err(SendBundleError::MaxFeeLessThanNextBaseFee());
+    }
+
+    let (max_fee, coinbase_bps) = match input.payment {
+        PaymentMode::PriorityFee => (max_fee, 0),
+        PaymentMode::Coinbase => {
+            let coinbase = bribe_amount - input.gas_cost();
+            (input.base_fee, (coinbase * BPS / input.revenue).as_u32())
+        }
+    };
+
+    Ok(Bribe {
+        max_fee,
+        coinbase_bps,
+        strategy,
+    })
+}
+
+/// The same share every time, plus up to `jitter_bps` at random
+///
+/// The jitter overpays to get dust onto the contract, more info:
+/// https://twitter.com/libevm/status/1474870661373779969
+#[derive(Debug, Clone)]
+pub struct FixedShare {
+    pub share_bps: u32,
+    pub jitter_bps: u32,
+}
+
+impl BribeStrategy for FixedShare {
+    fn price(&self, _relay: &str, input: &BribeInput) -> Result<Bribe, SendBundleError> {
+        let jitter = match self.jitter_bps {
+            0 => 0,
+            jitter => rand::thread_rng().gen_range(0..jitter as u64 * 100_000),
+        };
+
+        pay_share(
+            input,
+            self.share_bps as u64 * 100_000 + jitter,
+            format!("fixed-{}bps", self.share_bps),
+        )
+    }
+}
+
+/// Bids higher for relays its bundles keep missing on
+///
+/// The share moves between `min_bps` and `max_bps` with the miss rate of the relay's last
+/// `window` bundles, a relay without any yet gets `max_bps`.
+#[derive(Debug)]
+pub struct Adaptive {
+    pub min_bps: u32,
+    pub max_bps: u32,
+    pub window: usize,
+    outcomes: Mutex<HashMap<String, VecDeque<bool>>>,
+}
+
+impl Adaptive {
+    pub fn new(min_bps: u32, max_bps: u32, window: usize) -> Self {
+        Self {
+            min_bps,
+            max_bps,
+            window,
+            outcomes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Current share for `relay`
+    pub fn share_bps(&self, relay: &str) -> u32 {
+        let outcomes = self.outcomes.lock().unwrap_or_else(|e| e.into_inner());
+        let recent = match outcomes.get(relay) {
+            Some(recent) if !recent.is_empty() => recent,
+            _ => return self.max_bps,
+        };
+
+        let misses = recent.iter().filter(|included| !**included).count() as u64;
+        let range = (self.max_bps - self.min_bps) as u64;
+        self.min_bps + (range * misses / recent.len() as u64) as u32
+    }
+}
+
+impl BribeStrategy for Adaptive {
+    fn price(&self, relay: &str, input: &BribeInput) -> Result<Bribe, SendBundleError> {
+        pay_share(
+            input,
+            self.share_bps(relay) as u64 * 100_000,
+            format!("adaptive-{}..{}bps", self.min_bps, self.max_bps),
+        )
+    }
+
+    fn record(&self, relay: &str, included: bool) {
+        let mut outcomes = self.outcomes.lock().unwrap_or_else(|e| e.into_inner());
+        let recent = outcomes.entry(relay.to_string()).or_default();
+        recent.push_back(included);
+        while recent.len() > self.window {
+            recent.pop_front();
+        }
+    }
+}
+
+/// Own strategy for some relays, `default` for the rest
+#[derive(Debug)]
+pub struct PerRelay {
+    pub default: Box<dyn BribeStrategy>,
+    pub relays: HashMap<String, Box<dyn BribeStrategy>>,
+}
+
+impl BribeStrategy for PerRelay {
+    fn price(&self, relay: &str, input: &BribeInput) -> Result<Bribe, SendBundleError> {
+        match self.relays.get(relay) {
+            Some(strategy) => {
+                let bribe = strategy.price(relay, input)?;
+                Ok(Bribe {
+                    strategy: format!("{}:{}", relay, bribe.strategy),
+                    ..bribe
+                })
+            }
+            None => self.default.price(relay, input),
+        }
+    }
+
+    fn record(&self, relay: &str, included: bool) {
+        self.relays.get(relay).unwrap_or(&self.default).record(relay, included)
+    }
+}
+
+/// Caps the bribe of `inner` so at least `min_margin` wei of the revenue is kept
+#[derive(Debug)]
+pub struct MarginFloor {
+    pub inner: Box<dyn BribeStrategy>,
+    pub min_margin: U256,
+}
+
+impl BribeStrategy for MarginFloor {
+    fn price(&self, relay: &str, input: &BribeInput) -> Result<Bribe, SendBundleError> {
+        let bribe = self.inner.price(relay, input)?;
+        let strategy = format!("{}+floor", bribe.strategy);
+
+        let spendable = match input.revenue.checked_sub(self.min_margin) {
+            Some(spendable) => spendable,
+            None => return Err(SendBundleError::MarginNotCovered()),
+        };
+
+        match input.payment {
+            PaymentMode::PriorityFee => {
+                let highest = spendable / input.gas_used;
+                if highest < input.base_fee {
+                    return Err(SendBundleError::MarginNotCovered());
+                }
+                Ok(Bribe {
+                    max_fee: bribe.max_fee.min(highest),
+                    strategy,
+                    ..bribe
+                })
+            }
+            PaymentMode::Coinbase => {
+                let highest = match spendable.checked_sub(input.gas_cost()) {
+                    Some(coinbase) => (coinbase * BPS / input.revenue).as_u32(),
+                    None => 0,
+                };
+                if highest == 0 {
+                    return Err(SendBundleError::MarginNotCovered());
+                }
+                Ok(Bribe {
+                    coinbase_bps: bribe.coinbase_bps.min(highest),
+                    strategy,
+                    ..bribe
+                })
+            }
+        }
+    }
+
+    fn record(&self, relay: &str, included: bool) {
+        self.inner.record(relay, included)
+    }
+}
+
+/// How the builder's share is picked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShareRule {
+    Fixed { share_bps: u32, jitter_bps: u32 },
+    Adaptive { min_bps: u32, max_bps: u32, window: usize },
+}
+
+impl ShareRule {
+    fn strategy(&self) -> Box<dyn BribeStrategy> {
+        match self {
+            ShareRule::Fixed {
+                share_bps,
+                jitter_bps,
+            } => Box::new(FixedShare {
+                share_bps: *share_bps,
+                jitter_bps: *jitter_bps,
+            }),
+            ShareRule::Adaptive {
+                min_bps,
+                max_bps,
+                window,
+            } => Box::new(Adaptive::new(*min_bps, *max_bps, *window)),
+        }
+    }
+}
+
+/// Validated `[bribe]` table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BribeSettings {
+    pub rule: ShareRule,
+    /// Relays priced by their own rule, by name
+    pub relays: Vec<(String, ShareRule)>,
+    /// Wei of each arbitrage's revenue never bribed away, 0 for no floor
+    pub min_margin: U256,
+    pub payment: PaymentMode,
+    /// Relays paid another way than `payment`, by name
+    pub relay_payments: Vec<(String, PaymentMode)>,
+}
+
+impl Default for BribeSettings {
+    // 70% plus up to 1%
+    fn default() -> Self {
+        Self {
+            rule: ShareRule::Fixed {
+                share_bps: 7_000,
+                jitter_bps: 100,
+            },
+            relays: Vec::new(),
+            min_margin: U256::zero(),
+            payment: PaymentMode::PriorityFee,
+            relay_payments: Vec::new(),
+        }
+    }
+}
+
+impl BribeSettings {
+    /// How arbitrages sent to `relay` pay its builder
+    pub fn payment(&self, relay: &str) -> PaymentMode {
+        self.relay_payments
+            .iter()
+            .find(|(name, _)| name == relay)
+            .map_or(self.payment, |(_, payment)| *payment)
+    }
+
+    pub fn strategy(&self) -> Box<dyn BribeStrategy> {
+        let mut strategy = self.rule.strategy();
+
+        if !self.relays.is_empty() {
+            strategy = Box::new(PerRelay {
+                default: strategy,
+                relays: self
+                    .relays
+                    .iter()
+                    .map(|(relay, rule)| (relay.clone(), rule.strategy()))
+                    .collect(),
+            });
+        }
+
+        if !self.min_margin.is_zero() {
+            strategy = Box::new(MarginFloor {
+                inner: strategy,
+                min_margin: self.min_margin,
+            });
+        }
+
+        strategy
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StrategyStats {
+    pub strategy: String,
+    pub sent: u64,
+    pub included: u64,
+    /// Tips above the base fee of the included bundles
+    pub paid: U256,
+}
+
+/// Bundles sent and landed per strategy, to compare them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BribeStats {
+    pub strategies: Vec<StrategyStats>,
+}
+
+impl BribeStats {
+    pub fn save_to_file(&self, file_path: &str) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let serialized = serde_json::to_string(self)?;
+        file.write_all(serialized.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn load_from_file(file_path: &str) -> std::io::Result<BribeStats> {
+        let file = File::open(file_path)?;
+        let reader = std::io::BufReader::new(file);
+        let stats: BribeStats = serde_json::from_reader(reader)?;
+        Ok(stats)
+    }
+
+    /// Missing or unreadable files start over
+    pub fn load_or_default(file_path: &str) -> BribeStats {
+        match Self::load_from_file(file_path) {
+            Ok(stats) => stats,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Ignoring bribe stats {}: {}", file_path, e);
+                }
+                BribeStats::default()
+            }
+        }
+    }
+
+    /// Add the outcome of a bundle priced by `strategy`, tipping `tip` if it landed
+    pub fn record(&mut self, strategy: &str, included: bool, tip: U256) {
+        let index = match self.strategies.iter().position(|known| known.strategy == strategy) {
+            Some(index) => index,
+            None => {
+                self.strategies.push(StrategyStats {
+                    strategy: strategy.to_string(),
+                    sent: 0,
+                    included: 0,
+                    paid: U256::zero(),
+                });
+                self.strategies.len() - 1
+            }
+        };
+
+        let stats = &mut self.strategies[index];
+        stats.sent += 1;
+        if included {
+            stats.included += 1;
+            stats.paid += tip;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GWEI: u64 = 1_000_000_000;
+
+    // 0.1 ETH made for 100k gas at 10 gwei, 0.099 ETH left to share
+    fn input() -> BribeInput {
+        BribeInput {
+            gas_used: U256::from(100_000),
+            revenue: U256::exp10(17),
+            base_fee: U256::from(10 * GWEI),
+            payment: PaymentMode::PriorityFee,
+        }
+    }
+
+    #[test]
+    fn fixed_share_pays_its_share_of_what_is_left() {
+        let strategy = FixedShare {
+            share_bps: 7_000,
+            jitter_bps: 0,
+        };
+        let bribe = strategy.price("flashbots", &input()).unwrap();
+
+        // 70% of 0.099 ETH over 100k gas
+        assert_eq!(bribe.max_fee, U256::from(693 * GWEI));
+        assert_eq!(bribe.strategy, "fixed-7000bps");
+
+        let unprofitable = BribeInput {
+            revenue: U256::from(GWEI),
+            ..input()
+        };
+        assert!(matches!(
+            strategy.price("flashbots", &unprofitable),
+            Err(SendBundleError::GasFeesNotCovered())
+        ));
+    }
+
+    #[test]
+    fn adaptive_share_follows_the_relays_misses() {
+        let strategy = Adaptive::new(5_000, 9_000, 4);
+        assert_eq!(strategy.share_bps("flashbots"), 9_000);
+
+        for included in [true, true, true, false] {
+            strategy.record("flashbots", included);
+        }
+        assert_eq!(strategy.share_bps("flashbots"), 6_000);
+
+        // older outcomes leave the window
+        for _ in 0..4 {
+            strategy.record("flashbots", true);
+        }
+        assert_eq!(strategy.share_bps("flashbots"), 5_000);
+        assert_eq!(strategy.share_bps("beaverbuild"), 9_000);
+        assert!(
+            strategy.price("flashbots", &input()).unwrap().max_fee
+                < strategy.price("beaverbuild", &input()).unwrap().max_fee
+        );
+    }
+
+    #[test]
+    fn relays_and_the_floor_wrap_the_default() {
+        let settings = BribeSettings {
+            rule: ShareRule::Fixed {
+                share_bps: 5_000,
+                jitter_bps: 0,
+            },
+            relays: vec![(
+                "flashbots".to_string(),
+                ShareRule::Fixed {
+                    share_bps: 9_900,
+                    jitter_bps: 0,
+                },
+            )],
+            // keeps 0.01 ETH, at most 900 gwei a gas
+            min_margin: U256::exp10(16),
+            ..Default::default()
+        };
+        let strategy = settings.strategy();
+
+        let flashbots = strategy.price("flashbots", &input()).unwrap();
+        assert_eq!(flashbots.max_fee, U256::from(900 * GWEI));
+        assert_eq!(flashbots.strategy, "flashbots:fixed-9900bps+floor");
+
+        let other = strategy.price("beaverbuild", &input()).unwrap();
+        assert_eq!(other.max_fee, U256::from(495 * GWEI));
+        assert_eq!(other.strategy, "fixed-5000bps+floor");
+
+        let thin = BribeInput {
+            revenue: U256::exp10(16),
+            ..input()
+        };
+        assert!(matches!(
+            strategy.price("beaverbuild", &thin),
+            Err(SendBundleError::MarginNotCovered())
+        ));
+    }
+
+    #[test]
+    fn coinbase_payment_tips_the_same_at_the_base_fee() {
+        let strategy = FixedShare {
+            share_bps: 7_000,
+            jitter_bps: 0,
+        };
+        let coinbase = BribeInput {
+            payment: PaymentMode::Coinbase,
+            ..input()
+        };
+
+        let by_fee = strategy.price("flashbots", &input()).unwrap();
+        let by_transfer = strategy.price("flashbots", &coinbase).unwrap();
+        assert_eq!(by_transfer.max_fee, coinbase.base_fee);
+        assert!(by_transfer.priority_fee(&coinbase).is_zero());
+        // 0.0693 ETH bribed, less the 0.001 ETH base fee, out of 0.1 ETH made
+        assert_eq!(by_transfer.coinbase_bps, 6_830);
+        assert_eq!(by_transfer.tip(&coinbase), by_fee.tip(&input()));
+
+        // the floor keeps 0.05 ETH, 0.049 ETH is left for the builder
+        let floored = MarginFloor {
+            inner: Box::new(strategy),
+            min_margin: U256::exp10(16) * 5,
+        };
+        assert_eq!(floored.price("flashbots", &coinbase).unwrap().coinbase_bps, 4_900);
+    }
+
+    #[test]
+    fn payment_mode_can_differ_per_relay() {
+        let settings = BribeSettings {
+            payment: PaymentMode::Coinbase,
+            relay_payments: vec![("beaverbuild".to_string(), PaymentMode::PriorityFee)],
+            ..Default::default()
+        };
+        assert_eq!(settings.payment("flashbots"), PaymentMode::Coinbase);
+        assert_eq!(settings.payment("beaverbuild"), PaymentMode::PriorityFee);
+    }
+
+    #[test]
+    fn stats_round_trip_through_file() {
+        let path = std::env::temp_dir().join(format!("bribe_stats_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut stats = BribeStats::default();
+        stats.record("fixed-7000bps", true, U256::from(5));
+        stats.record("fixed-7000bps", false, U256::from(7));
+        stats.record("adaptive-5000..9000bps", false, U256::from(7));
+        stats.save_to_file(path).unwrap();
+
+        let loaded = BribeStats::load_or_default(path);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.strategies.len(), 2);
+        let fixed = &loaded.strategies[0];
+        assert_eq!((fixed.sent, fixed.included, fixed.paid), (2, 1, U256::from(5)));
+    }
+}
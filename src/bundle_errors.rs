@@ -1,12 +1,14 @@
-use ethers::signers::WalletError;
 use thiserror::Error;
 
+use crate::signer::SignerError;
+use crate::simulation::SimulationError;
+
 
 
 #[derive(Error, Debug)]
 pub enum SendBundleError {
     #[error("Failed to sign transaction")]
-    SigningError(#[from] WalletError),
+    SigningError(#[from] SignerError),
     #[error("Max fee is less than next base fee")]
     MaxFeeLessThanNextBaseFee(),
     #[error("Negative miner tip")]
@@ -19,4 +21,16 @@ pub enum SendBundleError {
     GasFeesNotCovered(),
     #[error("Gas estimation error")]
     GasEstimateError(),
+    #[error("Bundle simulation failed")]
+    SimulationFailed(#[from] SimulationError),
+    #[error("Victim transaction reverted: {0}")]
+    VictimReverted(String),
+    #[error("Arbitrage reverted: {0}")]
+    ArbitrageReverted(String),
+    #[error("Simulated arbitrage is not profitable")]
+    NotProfitable(),
+    #[error("No idle searcher wallet can pay for the bundle")]
+    NoIdleWallet(),
+    #[error("Revenue does not cover the minimum margin")]
+    MarginNotCovered(),
 }
\ No newline at end of file
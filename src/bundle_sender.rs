@@ -1,132 +1,316 @@
 use std::sync::Arc;
-use ethers::prelude::{rand::Rng, BlockId, *};
+use ethers::prelude::*;
 use crate::states::block_state::BlockInfo;
 use crate::backrunner::BackRunner;
 use crate::calc::NetPositiveCycle;
 use crate::bundle_errors::SendBundleError;
 use crate::utils;
-use crate::relay;
-
+use crate::relay::{self, BundleRelay};
+use crate::states::nonce_state::RelayedReservation;
+use crate::bribe::{Bribe, BribeInput, PaymentMode};
+use crate::constants::WETH;
+use crate::helpers;
+use crate::simulation::{is_transfer_revert, simulate_bundle, BundleTx};
+use crate::state::SharedState;
+use crate::contract_modules::uniswap_v2::discovery;
+use crate::contract_modules::uniswap_v2::gas_model::{CycleShape, GasModel};
+use crate::contract_modules::uniswap_v2::tax_cache::{TaxCache, TAX_CACHE_PATH};
+use tokio::sync::RwLock;
+
+
+// times a coinbase paying bundle is simulated and priced again before its relay is given up
+const MAX_COINBASE_ROUNDS: usize = 3;
 
 // Construct and send bundle based on recipe
 //
 // Arguments:
 // * `&recipe`: information on how to construct sandwich bundle
 // * `target_block`: holds basefee and timestamp of target block
-// * `sandwich_maker`: holds signer, bot address for constructing frontslice and backslice
+// * `sandwich_maker`: holds the searcher wallets, bot address for constructing frontslice and backslice
+// * `state`: pools of a cycle reverting on a transfer stop trading until their taxes are re-checked
+// * `tax_cache`: token taxes, tokens of a cycle reverting on a transfer are marked for a re-check
+// * `gas_model`: expected gas per cycle shape, calibrated with each simulation
 //
 // Returns:
 // Ok(()): return nothing if sent succesful
 // Err(SendBundleError): return error if send bundle fails
+#[allow(clippy::too_many_arguments)]
 pub async fn send_bundle(
     backrun_tx: Transaction,
     recipes: Vec<Bytes>,
     net_positive: Vec<NetPositiveCycle>,
     backrunner: Arc<BackRunner>,
     target_block: BlockInfo,
-    client: Arc<Provider<Ws>>
+    client: Arc<Provider<Ws>>,
+    state: Arc<SharedState>,
+    tax_cache: Arc<RwLock<TaxCache>>,
+    gas_model: Arc<RwLock<GasModel>>,
 ) -> Result<(), SendBundleError> {
 
-   
-    let nonce = {
-        let read_lock = backrunner.nonce.read().await;
-        (*read_lock).clone()
-    };
 
     let backrun_tx_byte: Bytes =  backrun_tx.rlp();
-    let mut raw_signed_txs = Vec::new();
-    let mut count = U256::zero();
-
-    for (idx, recipe) in recipes.clone().into_iter().enumerate() {
 
-        let revenue: U256 = net_positive[idx].profit.into_raw();
+    // simulated from an idle wallet credited its gas, the balance is only checked once a wallet
+    // is claimed for the bundle's real cost
+    let sender = match backrunner.wallets.idle(U256::zero()) {
+        Some(wallet) => wallet.address(),
+        None => return Err(SendBundleError::NoIdleWallet()),
+    };
 
+    // the victim and the arbitrages run in bundle order, on the state the target block builds on
+    let mut bundle = vec![BundleTx::from(&backrun_tx)];
+    for (recipe, cycle) in recipes.iter().zip(net_positive.iter()) {
+        bundle.push(BundleTx::arbitrage(
+            sender,
+            backrunner.multicall_address,
+            recipe.clone(),
+            cycle.base_token,
+            target_block.base_fee,
+        ));
+    }
+    let sim_state = backrunner.simulation.at(&client, target_block.number - 1)?;
+    let outcomes = simulate_bundle(sim_state.as_ref(), &target_block, &bundle)?;
 
-        let arbitrage_request = Eip1559TransactionRequest {
-            to: Some(NameOrAddress::Address(backrunner.multicall_address)),
-            from: Some(backrunner.searcher_wallet.address()),
-            data: Some(recipe.clone()),
-            chain_id: Some(U64::from(1)),
-            ..Default::default()
-        };
+    if let Some(reason) = outcomes[0].revert_reason.clone() {
+        return Err(SendBundleError::VictimReverted(reason));
+    }
 
-        let arb_tx = transaction::eip2718::TypedTransaction::Eip1559(arbitrage_request);
-        let block = Some(BlockId::Number(BlockNumber::from(target_block.number-1)));
+    let mut arbitrages = Vec::new();
+    for (idx, recipe) in recipes.clone().into_iter().enumerate() {
 
-        let gas_used = match client.estimate_gas(&arb_tx, block).await {
+        let outcome = &outcomes[idx + 1];
+        if let Some(reason) = outcome.revert_reason.clone() {
+            // a transfer failing in the cycle means a token tax changed since it was measured
+            if is_transfer_revert(&reason) {
+                let tokens = net_positive[idx].cycle_tokens.clone();
+                invalidate_taxes(&tax_cache, &tokens).await;
+                tokio::spawn(discovery::recheck_taxes(
+                    client.clone(),
+                    state.clone(),
+                    tax_cache.clone(),
+                    tokens,
+                    backrunner.settings.tax_cache_max_age,
+                    target_block.number - 1,
+                ));
+            }
+            return Err(SendBundleError::ArbitrageReverted(reason));
+        }
+        if outcome.profit <= I256::zero() {
+            return Err(SendBundleError::NotProfitable());
+        }
+
+        let gas_used = U256::from(outcome.gas_used);
+        record_gas(&gas_model, net_positive[idx].shape, gas_used).await;
+
+        let revenue: U256 = simulated_revenue(&net_positive[idx], outcome.profit);
+
+        arbitrages.push((
+            recipe,
+            BribeInput {
+                gas_used,
+                revenue,
+                base_fee: target_block.base_fee,
+                payment: PaymentMode::PriorityFee,
+            },
+        ));
+    }
 
-            Ok(value) => { value },
-            Err(_e)  => { return Err(SendBundleError::GasEstimateError())},
-        };
-       
-
-        let max_fee = calculate_bribe_for_max_fee(gas_used, revenue, &target_block)?;
-
-        let arbitrage_request = Eip1559TransactionRequest {
-            to: Some(NameOrAddress::Address(backrunner.multicall_address)),
-            from: Some(backrunner.searcher_wallet.address()),
-            data: Some(recipe.clone()),
-            chain_id: Some(U64::from(1)),
-            max_priority_fee_per_gas: Some(max_fee),
-            max_fee_per_gas: Some(max_fee),
-            gas: Some((U256::from(gas_used) * 10) / 7),
-            nonce: Some(nonce + count), // gasused = 70% gaslimit
-           ..Default::default()
+    // each relay gets the arbitrages priced by its own strategy, relays none of them pays for are skipped
+    let mut priced = Vec::new();
+    let mut last_error = None;
+    'relays: for (relay_name, url) in relay::allowed_relays(&backrunner.egress) {
+        let payment = backrunner.settings.bribe.payment(relay_name);
+        let bribes = arbitrages
+            .iter()
+            .zip(net_positive.iter())
+            .map(|((_, input), cycle)| {
+                // the executor pays the coinbase in ETH, only WETH profits can be unwrapped for it
+                let input = match payment {
+                    PaymentMode::Coinbase if cycle.base_token == helpers::address(WETH) => BribeInput {
+                        payment,
+                        ..*input
+                    },
+                    _ => *input,
+                };
+                backrunner.bribe.price(relay_name, &input).map(|bribe| (input, bribe))
+            })
+            .collect::<Result<Vec<(BribeInput, Bribe)>, SendBundleError>>();
+
+        let mut bribes = match bribes {
+            Ok(bribes) => bribes,
+            Err(e) => {
+                last_error = Some(e);
+                continue;
+            }
         };
 
+        // paying the coinbase changes the calldata, that variant is simulated before it is signed and
+        // priced again on the gas it uses, until no arbitrage pays more than what was simulated
+        let mut calldata: Vec<Bytes> = arbitrages.iter().map(|(recipe, _)| recipe.clone()).collect();
+        let mut simulated_bps = vec![0; bribes.len()];
+        let mut rounds = 0;
+        while bribes
+            .iter()
+            .zip(simulated_bps.iter())
+            .any(|((_, bribe), bps)| bribe.coinbase_bps != *bps)
+        {
+            if rounds == MAX_COINBASE_ROUNDS {
+                log::warn!("Coinbase payment for {} did not settle", relay_name);
+                last_error = Some(SendBundleError::FailedToCreateBundle());
+                continue 'relays;
+            }
+            rounds += 1;
+
+            let mut variant = vec![BundleTx::from(&backrun_tx)];
+            for (idx, (_, bribe)) in bribes.iter().enumerate() {
+                calldata[idx] = match bribe.coinbase_bps {
+                    0 => arbitrages[idx].0.clone(),
+                    coinbase_bps => net_positive[idx].encode_data(backrunner.multicall_address, coinbase_bps),
+                };
+                simulated_bps[idx] = bribe.coinbase_bps;
+                variant.push(BundleTx::arbitrage(
+                    sender,
+                    backrunner.multicall_address,
+                    calldata[idx].clone(),
+                    net_positive[idx].base_token,
+                    target_block.base_fee,
+                ));
+            }
+
+            let outcomes = simulate_bundle(sim_state.as_ref(), &target_block, &variant)?;
+            if let Some(reason) = outcomes[1..].iter().find_map(|outcome| outcome.revert_reason.clone()) {
+                log::warn!("Coinbase payment for {} reverts: {}", relay_name, reason);
+                last_error = Some(SendBundleError::ArbitrageReverted(reason));
+                continue 'relays;
+            }
+            if outcomes[1..].iter().any(|outcome| outcome.profit <= I256::zero()) {
+                last_error = Some(SendBundleError::NotProfitable());
+                continue 'relays;
+            }
 
-        let arbitrage_tx =
-            utils::sign_eip1559(arbitrage_request, &backrunner.searcher_wallet).await?;
+            // the gas limit and the bribe are sized from the calldata that is signed
+            let repriced = bribes
+                .iter()
+                .zip(outcomes[1..].iter())
+                .zip(simulated_bps.iter())
+                .map(|(((input, _), outcome), bps)| {
+                    let input = BribeInput {
+                        gas_used: U256::from(outcome.gas_used),
+                        ..*input
+                    };
+                    let mut bribe = backrunner.bribe.price(relay_name, &input)?;
+                    bribe.coinbase_bps = bribe.coinbase_bps.min(*bps);
+                    Ok((input, bribe))
+                })
+                .collect::<Result<Vec<(BribeInput, Bribe)>, SendBundleError>>();
+
+            bribes = match repriced {
+                Ok(bribes) => bribes,
+                Err(e) => {
+                    last_error = Some(e);
+                    continue 'relays;
+                }
+            };
+        }
 
-        raw_signed_txs.push(arbitrage_tx);
-        count += U256::from(1);
+        priced.push((relay_name, url, bribes, calldata));
+    }
+    if priced.is_empty() {
+        return Err(last_error.unwrap_or(SendBundleError::FailedToCreateBundle()));
     }
 
+    // a wallet and its nonces are only taken once every arbitrage is known to be worth sending
+    let cost = priced
+        .iter()
+        .map(|(_, _, bribes, _)| {
+            bribes.iter().fold(U256::zero(), |cost, (input, bribe)| {
+                cost + gas_limit(input.gas_used) * bribe.max_fee
+            })
+        })
+        .max()
+        .unwrap_or_default();
+    let (wallet, reservation) =
+        match backrunner.wallets.claim(arbitrages.len(), target_block.number, cost) {
+            Some(claimed) => claimed,
+            None => return Err(SendBundleError::NoIdleWallet()),
+        };
 
-    let nonce = (nonce + count).checked_sub(U256::from(1)).unwrap();
-    
+    // every bundle is signed before any is sent, a signing failure gives the nonces back untouched
+    let mut signed = Vec::new();
+    for (relay_name, url, bribes, calldata) in priced {
+        let mut bundled_transactions: Vec<Bytes> = vec![backrun_tx_byte.clone()];
+
+        for (idx, (data, (input, bribe))) in calldata.into_iter().zip(bribes.iter()).enumerate() {
+            let arbitrage_request = Eip1559TransactionRequest {
+                from: Some(wallet.address()),
+                to: Some(NameOrAddress::Address(backrunner.multicall_address)),
+                data: Some(data),
+                nonce: Some(reservation.nonce(idx)),
+                chain_id: Some(U64::from(backrunner.settings.chain_id)),
+                max_priority_fee_per_gas: Some(bribe.priority_fee(input)),
+                max_fee_per_gas: Some(bribe.max_fee),
+                gas: Some(gas_limit(input.gas_used)),
+               ..Default::default()
+            };
 
-    let bundle = relay::construct_bundle(
-        {
-            let mut bundled_transactions: Vec<Bytes> = vec![backrun_tx_byte];
-            for meat in raw_signed_txs {
-                bundled_transactions.push(meat.clone());
+            match utils::sign_eip1559(arbitrage_request, &wallet.signer).await {
+                Ok(tx) => bundled_transactions.push(tx),
+                Err(e) => {
+                    wallet.nonces.release(&reservation);
+                    return Err(e.into());
+                }
             }
-            bundled_transactions
-        },
-        target_block.number,
-        target_block.timestamp.as_u64(),
-    );
+        }
+
+        let bundle = relay::construct_bundle(
+            bundled_transactions.clone(),
+            target_block.number,
+            target_block.timestamp.as_u64(),
+        );
+        let tip = bribes.iter().fold(U256::zero(), |tip, (input, bribe)| tip + bribe.tip(input));
+        let relay = BundleRelay::new(
+            backrunner.egress.clone(),
+            url,
+            relay_name.into(),
+            backrunner.bundle_signer.clone(),
+        );
+
+        signed.push((relay, bundle, bundled_transactions, bribes[0].1.strategy.clone(), tip));
+    }
 
 
-    // send bundle to all relay endpoints (concurrently)
-    for relay in relay::get_all_relay_endpoints().await {
-        let bundle = bundle.clone();
+    // send bundle to all relay endpoints (concurrently), the nonces resolve once every relay answered
+    let reservation = Arc::new(RelayedReservation::new(reservation, signed.len()));
+    for (relay, bundle, bundled_transactions, strategy, tip) in signed {
         let recipes = recipes.clone();
+        let wallet = wallet.clone();
+        let reservation = reservation.clone();
         let backrunner = backrunner.clone();
+        let client = client.clone();
+        let block = target_block.number;
 
         tokio::spawn(async move {
-            let pending_bundle = match relay.flashbots_client.inner().send_bundle(&bundle).await {
-                Ok(pb) => pb,
-                Err(_) => {
-                    //log::error!("Failed to send bundle: {:?}", e);
+            let bundle_hash = match relay.send_bundle(&bundle).await {
+                Ok(bundle_hash) => bundle_hash,
+                Err(e) => {
+                    log::error!("Failed to send bundle to {}: {:?}", relay.relay_name, e);
+                    if reservation.missed(&wallet.nonces) {
+                        log::info!("bundle not included in block, nonces released");
+                    }
                     return;
                 }
             };
 
             log::info!(
                 "{:?}",
-                format!("Bundle sent to {}", relay.relay_name)
+                format!("Bundle sent to {}, priced by {}", relay.relay_name, strategy)
             );
 
-            let bundle_hash = pending_bundle.bundle_hash;
-
-            let is_bundle_included = match pending_bundle.await {
-                Ok(_) => true,
-                Err(ethers_flashbots::PendingBundleError::BundleNotIncluded) => false,
+            let is_bundle_included = match relay::bundle_included(&client, block, &bundled_transactions).await {
+                Ok(included) => included,
                 Err(e) => {
                     log::error!(
-                        "{:?} Bundle rejected due to error : {:?}",
+                        "{:?} Bundle inclusion unknown due to error : {:?}",
                         recipes,
                         e
                     );
@@ -136,9 +320,18 @@ pub async fn send_bundle(
 
             log::info!("bundle hash: {:?} ", bundle_hash);
 
+            backrunner.bribe.record(&relay.relay_name, is_bundle_included);
+            record_bribe(&backrunner, &strategy, is_bundle_included, tip);
+
             match is_bundle_included {
-                true => {backrunner.nonce.write().await.checked_add(nonce).unwrap();}
-                false => {log::info!("bundle not included in block, nonce not increased")}
+                true => {
+                    reservation.landed(&wallet.nonces);
+                }
+                false => {
+                    if reservation.missed(&wallet.nonces) {
+                        log::info!("bundle not included in block, nonces released");
+                    }
+                }
             }
 
             
@@ -150,53 +343,46 @@ pub async fn send_bundle(
 
 
 
-// calculates the optimal bribe for a given opportunity
-//
-// Arguments
-// * `recipe`: information on sandwich bundle
-// * `target_block`: information on target_block
-//
-// Returns:
-// Ok(U256) -> The maximum fee for opportunity if calculated succesfully
-// Err(SendBundleError) -> Error in bribe amount calculation
-fn calculate_bribe_for_max_fee(
-    gas_used: U256,
-    revenue: U256,
-    target_block: &BlockInfo,
-) -> Result<U256, SendBundleError> {
-    // frontrun txfee is fixed, exclude it from bribe calculations
-    let revenue_minus_tx_fee = match 
-        revenue
-        .checked_sub(gas_used * target_block.base_fee)
-    {
-        Some(revenue) => revenue,
-        None => return Err(SendBundleError::GasFeesNotCovered()),
-    };
+// Mark tokens for a tax re-check and persist the cache
+async fn invalidate_taxes(tax_cache: &Arc<RwLock<TaxCache>>, tokens: &[Address]) {
+    let mut cache = tax_cache.write().await;
+    if cache.invalidate(tokens) == 0 {
+        return;
+    }
 
+    if let Err(e) = cache.save_to_file(TAX_CACHE_PATH) {
+        log::warn!("Failed to save tax cache: {}", e);
+    }
+}
 
 
-    // overpay to get dust onto sandwich contractIf
-    // more info: https://twitter.com/libevm/status/1474870661373779969
-    let bribe_amount = {
-        let mut rng = rand::thread_rng();
+// Calibrate the gas model with the simulated gas used, the checkpointer persists it
+async fn record_gas(gas_model: &Arc<RwLock<GasModel>>, shape: CycleShape, gas_used: U256) {
+    gas_model.write().await.record(shape, gas_used);
+}
 
-        // enchanement: make bribe adaptive based on competitors    
-        (revenue_minus_tx_fee * (700000000 + rng.gen_range(0..10000000)))
-                    / 1000000000
-    };
 
-    // calculating bribe amount
-    let max_fee: U256 = bribe_amount / gas_used;
+// The cycle's WETH profit, cut down to what the simulation made of it
+fn simulated_revenue(cycle: &NetPositiveCycle, simulated_profit: I256) -> U256 {
+    let expected = cycle.profit.into_raw();
+    let simulated = simulated_profit.into_raw();
+    let profit_weth = cycle.profit_weth.into_raw();
 
-    if max_fee < target_block.base_fee {
-        return Err(SendBundleError::MaxFeeLessThanNextBaseFee());
+    if simulated >= expected || expected.is_zero() {
+        return profit_weth;
     }
+    U256::try_from(profit_weth.full_mul(simulated) / U512::from(expected)).unwrap_or(profit_weth)
+}
 
-    let effective_miner_tip = max_fee.checked_sub(target_block.base_fee);
 
-    if effective_miner_tip.is_none() {
-        return Err(SendBundleError::NegativeMinerTip());
-    }
+// gasused = 70% gaslimit
+fn gas_limit(gas_used: U256) -> U256 {
+    (gas_used * 10) / 7
+}
+
 
-    Ok(max_fee)
+// Add a bundle's outcome to the stats of the strategy that priced it, the checkpointer persists them
+fn record_bribe(backrunner: &BackRunner, strategy: &str, included: bool, tip: U256) {
+    let mut stats = backrunner.bribe_stats.lock().unwrap_or_else(|e| e.into_inner());
+    stats.record(strategy, included, tip);
 }
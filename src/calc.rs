@@ -1,31 +1,41 @@
 use ethers::types::I256;
+use rayon::prelude::*;
 use serde::Deserialize;
-use std::cell::RefCell;
-use std::str::FromStr;
-use tokio::sync::MutexGuard;
+use std::collections::HashMap;
 
 // use crate::contract_modules::uniswap_v2::swap_math::get_amount_out;
-use crate::constants::WETH;
+use crate::contract_modules::uniswap_v2::gas_model::{CycleShape, GasModel};
 use crate::contract_modules::uniswap_v2::types::UniV2Pool;
 use crate::contract_modules::uniswap_v2::bindings::uni_v2_pair;
-use crate::state::State;
-use crate::utils::dotenv::get_multicall_contract_address;
-use ethers::types::{Address, U256, Bytes};
+use crate::state::PendingState;
+use ethers::types::{Address, U256, U512, Bytes};
 use std::cmp::Ordering;
 use ethers::abi::{Token, encode};
 
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct NetPositiveCycle {
+    /// Token the cycle starts and ends in
+    pub base_token: Address,
+    /// In `base_token`
     pub profit: I256,
+    /// Gross `profit` priced in WETH, bribes are paid out of this
+    pub profit_weth: I256,
+    pub shape: CycleShape,
+    /// Expected gas of the cycle's shape
+    pub gas_estimate: U256,
+    /// `profit_weth` less the gas at the next base fee, cycles are ranked on this
+    pub net_profit: I256,
     pub optimal_in: U256,
     pub swap_amounts: Vec<(U256, bool)>,
     pub cycle_addresses: Vec<Address>,
+    // tokens the cycle passes through, excluding the input token
+    pub cycle_tokens: Vec<Address>,
 }
 
 impl Ord for NetPositiveCycle {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.profit.cmp(&self.profit)
+        other.net_profit.cmp(&self.net_profit)
     }
 }
 
@@ -40,16 +50,20 @@ impl Eq for NetPositiveCycle {}
 // Ordering based on profit
 impl PartialEq for NetPositiveCycle {
     fn eq(&self, other: &Self) -> bool {
-        self.profit == other.profit
+        self.net_profit == other.net_profit
     }
 }
 
 impl NetPositiveCycle
 {
-    pub fn encode_data(&self) -> Bytes
+    /// Calldata of the cycle's first swap, the executor runs the rest in the flash swap callback
+    ///
+    /// A non zero `coinbase_bps` is appended to the callback data, the executor then pays
+    /// `block.coinbase` that share of the cycle's realized profit.
+    pub fn encode_data(&self, executor: Address, coinbase_bps: u32) -> Bytes
     {
 
-        let weth = Token::Address(Address::from_str(WETH).unwrap());
+        let base_token = Token::Address(self.base_token);
         let optimal = Token::Uint(self.optimal_in);
         let mut flashswap_calldata = Vec::<Token>::new();
         let mut pools = Vec::<Token>::new();
@@ -63,7 +77,7 @@ impl NetPositiveCycle
         
          let next = if  idx > self.swap_amounts.len()  {
               self.cycle_addresses[idx+1]
-            } else { get_multicall_contract_address() }; 
+            } else { executor }; 
 
 
            let data = match flag 
@@ -90,22 +104,25 @@ impl NetPositiveCycle
         }
 
 
-        let tokens = vec![
-            weth,
+        let mut tokens = vec![
+            base_token,
             optimal,
             Token::Array(pools),
             Token::Array(flashswap_calldata),
         ];
+        if coinbase_bps > 0 {
+            tokens.push(Token::Uint(U256::from(coinbase_bps)));
+        }
 
         let swap_data = match self.swap_amounts[0].1
         {
             true => {
-                swap_calldata( self.optimal_in, U256::zero(), get_multicall_contract_address(), Bytes::from(encode(&tokens)).to_vec())
+                swap_calldata( self.optimal_in, U256::zero(), executor, Bytes::from(encode(&tokens)).to_vec())
 
                 
             },
             false => {
-                swap_calldata(U256::zero(), self.optimal_in, get_multicall_contract_address(),Bytes::from(encode(&tokens)).to_vec())
+                swap_calldata(U256::zero(), self.optimal_in, executor,Bytes::from(encode(&tokens)).to_vec())
             }
         };
 
@@ -115,72 +132,380 @@ impl NetPositiveCycle
     }
 }
 
+/// Most cycles put in one bundle
+pub const MAX_BUNDLE_CYCLES: usize = 5;
+
+/// Threads evaluating cycles, `threads` of 0 lets rayon pick one per core
+pub fn evaluation_pool(threads: usize) -> Result<rayon::ThreadPool, rayon::ThreadPoolBuildError> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|index| format!("evaluator-{}", index))
+        .build()
+}
+
+/// Most profitable cycles through `affected_pairs` (or all), each starting from its root
+/// token with at most the executor's `inventory` of it
+///
+/// Cycles are ranked on their profit net of the gas `gas_model` expects at `base_fee`, those
+/// that don't cover it are dropped.
+///
+/// Cycles are evaluated in parallel on the current rayon pool, run it inside
+/// `evaluation_pool(..).install` to bound the threads used.
 pub fn find_optimal_cycles(
-    state: &MutexGuard<State>,
+    pending: &PendingState,
     affected_pairs: Option<Vec<Address>>,
+    inventory: &HashMap<Address, U256>,
+    gas_model: &GasModel,
+    base_fee: U256,
 ) -> Vec<NetPositiveCycle> {
+    let state = pending.base();
     let mut pointers: Vec<&Vec<crate::state::IndexedPair>> = Vec::new();
 
+    // a cycle is listed under each of its pairs, take it from the first one that lists it
     match affected_pairs {
         Some(affected_pairs) => {
-            affected_pairs.iter().for_each(|pair_address| {
-                if let Some(cycle) = state.cycles_mapping.get(pair_address) {
-                    pointers.extend(cycle.iter());
-                }                
-            });   
+            let mut seen: Vec<usize> = Vec::new();
+            for pair_address in affected_pairs.iter() {
+                let pointer = match state.address_mapping.get(pair_address) {
+                    Some(pointer) if !seen.contains(pointer) => *pointer,
+                    _ => continue,
+                };
+
+                if let Some(cycles) = state.cycles_mapping.get(pair_address) {
+                    pointers.extend(
+                        cycles
+                            .iter()
+                            .filter(|cycle| !cycle.iter().any(|pair| seen.contains(&pair.address))),
+                    );
+                }
+                seen.push(pointer);
+            }
         }
         None => {
-            for (_, cycles) in &state.cycles_mapping {
-                pointers.extend(cycles.iter());
+            for (pair_address, cycles) in state.cycles_mapping.iter() {
+                pointers.extend(
+                    cycles
+                        .iter()
+                        .filter(|cycle| state.index_mapping[&cycle[0].address] == *pair_address),
+                );
             }
         }
     }
 
-    let mut net_profit_cycles = Vec::new();
+    let mut net_profit_cycles: Vec<NetPositiveCycle> = pointers
+        .par_iter()
+        .flat_map_iter(|cycle| evaluate_both_directions(pending, cycle, inventory))
+        .filter_map(|net_positive| net_of_gas(net_positive, gas_model, base_fee))
+        .collect();
+
+    net_profit_cycles.sort();
+    net_profit_cycles.into_iter().take(MAX_BUNDLE_CYCLES).collect()
+}
+
+/// The cycles of one bundle, in execution order
+///
+/// Every cycle of `candidates` is priced against the same reserves, once a cycle trades a
+/// pool the later ones through it see less of the gap. When candidates share a pool each
+/// execution order is simulated against `pending`, a cycle being resized to the reserves
+/// the earlier ones leave, or dropped when it no longer covers its gas. The order with the
+/// highest total net profit is kept.
+pub fn select_cycles(
+    pending: &PendingState,
+    candidates: Vec<NetPositiveCycle>,
+    inventory: &HashMap<Address, U256>,
+    gas_model: &GasModel,
+    base_fee: U256,
+) -> Vec<NetPositiveCycle> {
+    let candidates: Vec<NetPositiveCycle> = candidates.into_iter().take(MAX_BUNDLE_CYCLES).collect();
+
+    let shares_pool = candidates.iter().enumerate().any(|(position, cycle)| {
+        candidates[position + 1..].iter().any(|later| {
+            later
+                .cycle_addresses
+                .iter()
+                .any(|pool| cycle.cycle_addresses.contains(pool))
+        })
+    });
+    if !shares_pool {
+        return candidates;
+    }
+
+    let mut best = (I256::zero(), Vec::new());
+    // ties keep the earliest order, the ranking
+    for order in permutations(candidates.len()) {
+        let selected = simulate_in_order(
+            pending,
+            order.iter().map(|position| &candidates[*position]),
+            inventory,
+            gas_model,
+            base_fee,
+        );
+        let total = selected
+            .iter()
+            .fold(I256::zero(), |total, cycle| total + cycle.net_profit);
+
+        if total > best.0 {
+            best = (total, selected);
+        }
+    }
+
+    best.1
+}
+
+// Re-evaluate cycles one after another, each trading against the reserves the previous left
+fn simulate_in_order<'a>(
+    pending: &PendingState,
+    cycles: impl Iterator<Item = &'a NetPositiveCycle>,
+    inventory: &HashMap<Address, U256>,
+    gas_model: &GasModel,
+    base_fee: U256,
+) -> Vec<NetPositiveCycle> {
+    let state = pending.base();
+    let mut overlay = pending.clone();
+    let mut selected = Vec::new();
 
-    let weth = Address::from_str(WETH).unwrap();
-    for cycle in pointers {
-        let pairs = cycle
+    for candidate in cycles {
+        let pairs = candidate
+            .cycle_addresses
             .iter()
-            .filter_map(|pair| state.pairs_mapping.get(&pair.address))
-            .collect::<Vec<&RefCell<UniV2Pool>>>();
-
-        let pairs_clone = pairs.clone();
-        let profit_function =
-            move |amount_in: U256| -> I256 { get_profit(weth, amount_in, &pairs_clone) };
-
-        let optimal = maximize_profit(
-            U256::one(),
-            U256::from_dec_str("10000000000000000000000").unwrap(),
-            U256::from_dec_str("10").unwrap(),
-            profit_function,
+            .map(|address| overlay.pool(*state.address_mapping.get(address)?))
+            .collect::<Option<Vec<UniV2Pool>>>();
+        let (pairs, max_in) = match (pairs, inventory.get(&candidate.base_token)) {
+            (Some(pairs), Some(max_in)) if pairs.iter().all(|pair| pair.tradeable) => (pairs, *max_in),
+            _ => continue,
+        };
+
+        let resized = evaluate_cycle(candidate.base_token, &pairs, max_in).and_then(|mut net_positive| {
+            let profit_weth = overlay.weth_value(net_positive.base_token, net_positive.profit.into_raw())?;
+            net_positive.profit_weth = I256::from_raw(profit_weth);
+            net_of_gas(net_positive, gas_model, base_fee)
+        });
+
+        if let Some(net_positive) = resized {
+            apply_swaps(&mut overlay, net_positive.base_token, &pairs, &net_positive.swap_amounts);
+            selected.push(net_positive);
+        }
+    }
+
+    selected
+}
+
+// Move the reserves of `pairs` by a cycle's swaps, `swap_amounts` starts with the input of
+// the first hop and follows with what each hop hands to the next
+fn apply_swaps(overlay: &mut PendingState, token_in: Address, pairs: &[UniV2Pool], swap_amounts: &[(U256, bool)]) {
+    let mut token_in = token_in;
+
+    for (pair, (amount_in, _)) in pairs.iter().zip(swap_amounts) {
+        let zero_for_one = pair.token0 == token_in;
+        let (reserve_in, reserve_out) = if zero_for_one {
+            (pair.reserve0, pair.reserve1)
+        } else {
+            (pair.reserve1, pair.reserve0)
+        };
+
+        // the pool pays out the full amount, the token takes its tax on the way
+        let amount_out = get_amount_out(
+            *amount_in,
+            reserve_in,
+            reserve_out,
+            U256::zero(),
+            pair.router_fee,
+            pair.router_fee_denominator,
         );
+        let reserve_in = reserve_in.saturating_add(*amount_in);
+        let reserve_out = reserve_out - amount_out;
+
+        let pointer = overlay.base().address_mapping[&pair.address];
+        if zero_for_one {
+            overlay.set_reserves(pointer, reserve_in, reserve_out);
+            token_in = pair.token1;
+        } else {
+            overlay.set_reserves(pointer, reserve_out, reserve_in);
+            token_in = pair.token0;
+        }
+    }
+}
 
-        let (profit, swap_amounts) = get_profit_with_amount(weth, optimal, &pairs);
+// Every order of `0..count`, starting with the identity
+fn permutations(count: usize) -> Vec<Vec<usize>> {
+    if count == 0 {
+        return vec![Vec::new()];
+    }
 
-        let mut cycle_internal = Vec::new();
-        for pair in pairs {
-            cycle_internal.push(pair.borrow().address);
+    let mut orders = Vec::new();
+    for first in 0..count {
+        for rest in permutations(count - 1) {
+            let mut order = vec![first];
+            order.extend(rest.into_iter().map(|position| position + (position >= first) as usize));
+            orders.push(order);
         }
+    }
+    orders
+}
 
-        if profit > I256::one() {
-            let net_positive_cycle = NetPositiveCycle {
-                profit,
-                optimal_in: optimal,
-                cycle_addresses: cycle_internal,
-                swap_amounts,
-            };
+// Price a cycle's expected gas at `base_fee`, `None` when the profit doesn't cover it
+fn net_of_gas(mut net_positive: NetPositiveCycle, gas_model: &GasModel, base_fee: U256) -> Option<NetPositiveCycle> {
+    net_positive.gas_estimate = gas_model.estimate(net_positive.shape);
+    let gas_cost = net_positive.gas_estimate.checked_mul(base_fee)?;
+    net_positive.net_profit = net_positive.profit_weth - I256::from_raw(gas_cost);
+    (net_positive.net_profit > I256::zero()).then_some(net_positive)
+}
+
+fn evaluate_both_directions(
+    pending: &PendingState,
+    cycle: &[crate::state::IndexedPair],
+    inventory: &HashMap<Address, U256>,
+) -> Vec<NetPositiveCycle> {
+    let state = pending.base();
+    let mut net_positive_cycles = Vec::new();
+
+    let base_token = match state.cycle_root(cycle) {
+        Some(root) => state.index_mapping[&root],
+        None => return net_positive_cycles,
+    };
+    let max_in = match inventory.get(&base_token) {
+        Some(balance) if *balance > U256::one() => *balance,
+        _ => return net_positive_cycles,
+    };
+
+    let mut pairs = cycle
+        .iter()
+        .filter_map(|pair| pending.pool(pair.address))
+        .collect::<Vec<UniV2Pool>>();
+    // a pool whose tax is being measured again stays out until it is done
+    if pairs.iter().any(|pair| !pair.tradeable) {
+        return net_positive_cycles;
+    }
 
-            net_profit_cycles.push(net_positive_cycle);
+    // cycles are indexed in one direction only, the reverse trades the same pools
+    for _ in 0..2 {
+        if let Some(mut net_positive) = evaluate_cycle(base_token, &pairs, max_in) {
+            // a base token without a WETH pair cannot be ranked or pay a bribe
+            if let Some(profit_weth) = pending.weth_value(base_token, net_positive.profit.into_raw()) {
+                net_positive.profit_weth = I256::from_raw(profit_weth);
+                net_positive_cycles.push(net_positive);
+            }
         }
+        pairs.reverse();
     }
 
-    net_profit_cycles.sort();
-    net_profit_cycles.into_iter().take(5).collect()
+    net_positive_cycles
+}
+
+fn evaluate_cycle(base_token: Address, pairs: &[UniV2Pool], max_in: U256) -> Option<NetPositiveCycle> {
+    // the search only runs for cycles the closed form cannot fold
+    let optimal = match closed_form_optimal_in(base_token, pairs) {
+        Some(optimal) => optimal.min(max_in),
+        None => {
+            let profit_function =
+                |amount_in: U256| -> I256 { get_profit(base_token, amount_in, pairs) };
+
+            maximize_profit(
+                U256::one(),
+                max_in,
+                U256::from_dec_str("10").unwrap(),
+                profit_function,
+            )
+        }
+    };
+
+    let (profit, swap_amounts) = get_profit_with_amount(base_token, optimal, pairs);
+
+    let mut cycle_internal = Vec::new();
+    let mut cycle_tokens = Vec::new();
+    let mut shape = CycleShape {
+        hops: pairs.len() as u8,
+        taxed_hops: 0,
+    };
+    let mut token = base_token;
+    for pair in pairs {
+        cycle_internal.push(pair.address);
+
+        let tax = if pair.token0 == token { pair.fees1 } else { pair.fees0 };
+        if !tax.is_zero() {
+            shape.taxed_hops += 1;
+        }
+
+        token = if pair.token0 == token { pair.token1 } else { pair.token0 };
+        if token != base_token {
+            cycle_tokens.push(token);
+        }
+    }
+
+    if profit > I256::one() {
+        Some(NetPositiveCycle {
+            base_token,
+            profit,
+            // priced by the caller
+            profit_weth: I256::zero(),
+            shape,
+            gas_estimate: U256::zero(),
+            net_profit: I256::zero(),
+            optimal_in: optimal,
+            cycle_addresses: cycle_internal,
+            cycle_tokens,
+            swap_amounts,
+        })
+    } else {
+        None
+    }
+}
+
+/// Input maximizing the profit of a cycle of constant product pools, `None` if it can't be folded
+///
+/// The pools are folded into one virtual pool `out = e_out * x / (e_in + x)`, a token tax
+/// scales the output reserve of its hop. Profit `out - x` peaks at `x = sqrt(e_in * e_out) - e_in`,
+/// zero is returned when the cycle loses at any size.
+pub fn closed_form_optimal_in(token_in: Address, pairs: &[UniV2Pool]) -> Option<U256> {
+    let tax_denominator = U512::from(TAX_DENOMINATOR);
+    let mut token_in = token_in;
+    // (e_in, e_out) of the pools folded so far
+    let mut folded: Option<(U512, U512)> = None;
+
+    for pair in pairs {
+        let (reserve_in, reserve_out, tax) = if pair.token0 == token_in {
+            (pair.reserve0, pair.reserve1, pair.fees1)
+        } else {
+            (pair.reserve1, pair.reserve0, pair.fees0)
+        };
+        if reserve_in.is_zero()
+            || reserve_out.is_zero()
+            || pair.router_fee.is_zero()
+            || pair.router_fee > pair.router_fee_denominator
+            || tax > U256::from(TAX_DENOMINATOR)
+        {
+            return None;
+        }
+
+        let reserve_in = U512::from(reserve_in);
+        let reserve_out = U512::from(reserve_out) * (tax_denominator - U512::from(tax)) / tax_denominator;
+        let fee = U512::from(pair.router_fee);
+        let fee_denominator = U512::from(pair.router_fee_denominator);
+
+        folded = Some(match folded {
+            None => (reserve_in * fee_denominator / fee, reserve_out),
+            Some((e_in, e_out)) => {
+                let denominator = reserve_in * fee_denominator + e_out * fee;
+                (
+                    reserve_in * fee_denominator * e_in / denominator,
+                    reserve_out * fee * e_out / denominator,
+                )
+            }
+        });
+
+        token_in = if pair.token0 == token_in { pair.token1 } else { pair.token0 };
+    }
+
+    let (e_in, e_out) = folded?;
+    if e_in.is_zero() || e_out <= e_in {
+        return Some(U256::zero());
+    }
+
+    U256::try_from((e_in * e_out).integer_sqrt() - e_in).ok()
 }
 
 // find optimal input before uni fees eats away our profits
-// Quadratic search
+// Quadratic search, only for cycles the closed form can't fold
 fn maximize_profit(
     mut domain_min: U256,
     mut domain_max: U256,
@@ -215,11 +540,10 @@ fn maximize_profit(
 }
 
 /// Calculates profit given (state updated) pairs
-pub fn get_profit(token_in: Address, amount_in: U256, pairs: &Vec<&RefCell<UniV2Pool>>) -> I256 {
+pub fn get_profit(token_in: Address, amount_in: U256, pairs: &[UniV2Pool]) -> I256 {
     let mut amount_out: U256 = amount_in;
     let mut token_in = token_in;
     for pair in pairs {
-        let pair = pair.borrow();
         let fees;
         let (reserve0, reserve1) = if pair.token0 == token_in {
             fees = pair.fees1;
@@ -228,7 +552,14 @@ pub fn get_profit(token_in: Address, amount_in: U256, pairs: &Vec<&RefCell<UniV2
             fees = pair.fees0;
             (pair.reserve1, pair.reserve0)
         };
-        amount_out = get_amount_out(amount_out, reserve0, reserve1, fees, pair.router_fee);
+        amount_out = get_amount_out(
+            amount_out,
+            reserve0,
+            reserve1,
+            fees,
+            pair.router_fee,
+            pair.router_fee_denominator,
+        );
         token_in = if pair.token0 == token_in {
             pair.token1
         } else {
@@ -242,16 +573,15 @@ pub fn get_profit(token_in: Address, amount_in: U256, pairs: &Vec<&RefCell<UniV2
 pub fn get_profit_with_amount(
     token_in: Address,
     amount_in: U256,
-    pairs: &Vec<&RefCell<UniV2Pool>>,
+    pairs: &[UniV2Pool],
 ) -> (I256, Vec<(U256, bool)>) {
     let mut amount_out: U256 = amount_in;
     let mut token_in = token_in;
     let mut amounts = Vec::with_capacity(pairs.len() + 1);
-    let first_value = &token_in == &pairs[0].borrow().token0;
+    let first_value = &token_in == &pairs[0].token0;
     amounts.push((amount_in, first_value));
 
     for pair in pairs {
-        let pair = pair.borrow();
         let fees;
         let (reserve0, reserve1) = if pair.token0 == token_in {
             fees = pair.fees1;
@@ -260,7 +590,14 @@ pub fn get_profit_with_amount(
             fees = pair.fees0;
             (pair.reserve1, pair.reserve0)
         };
-        amount_out = get_amount_out(amount_out, reserve0, reserve1, fees, pair.router_fee);
+        amount_out = get_amount_out(
+            amount_out,
+            reserve0,
+            reserve1,
+            fees,
+            pair.router_fee,
+            pair.router_fee_denominator,
+        );
         
         token_in = if pair.token0 == token_in {
             amounts.push((amount_out, true));
@@ -278,24 +615,37 @@ pub fn get_profit_with_amount(
     )
 }
 
+/// Token taxes (`fees0` / `fees1`) are measured out of this
+pub const TAX_DENOMINATOR: u64 = 10000;
+
 // We don't want overflow / underflow at runtime + need to be a bit fast
+// Same rounding as UniswapV2Library.getAmountOut, intermediates are 512 bit so it is exact
+// for any fee numerator / denominator
 pub fn get_amount_out(
     a_in: U256,
     reserve_in: U256,
     reserve_out: U256,
     fees: U256,
     router_fee: U256,
+    router_fee_denominator: U256,
 ) -> U256 {
-    if a_in == U256::zero() {
+    if a_in.is_zero() || reserve_in.is_zero() || reserve_out.is_zero() {
         return U256::zero();
     }
-    let a_in_with_fee = a_in.saturating_mul(router_fee);
-    let a_out = a_in_with_fee.saturating_mul(reserve_out)
-        / U256::from(10000)
-            .saturating_mul(reserve_in)
-            .saturating_add(a_in_with_fee);
 
-    a_out - a_out.saturating_mul(fees) / U256::from(10000)
+    let a_in_with_fee = a_in.full_mul(router_fee);
+    let numerator = a_in_with_fee * U512::from(reserve_out);
+    let denominator = reserve_in.full_mul(router_fee_denominator) + a_in_with_fee;
+
+    // out < reserve_out, so it always fits back into 256 bits
+    let a_out = U256::try_from(numerator / denominator).unwrap_or(U256::MAX);
+
+    if fees.is_zero() {
+        return a_out;
+    }
+
+    let tax = U256::try_from(a_out.full_mul(fees) / U512::from(TAX_DENOMINATOR)).unwrap_or(a_out);
+    a_out.saturating_sub(tax)
 }
 
 
@@ -336,3 +686,440 @@ pub fn transfer_calldata(
             .expect("Could not encode transfer calldata").into()
 
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u(value: &str) -> U256 {
+        U256::from_dec_str(value).unwrap()
+    }
+
+    // (amount in, reserve in, reserve out, fee, fee denominator, UniswapV2Library.getAmountOut)
+    const REFERENCE: [(&str, &str, &str, u64, u64, &str); 6] = [
+        ("1000000000000000000", "5000000000000000000000", "10000000000000", 997, 1000, "1993602475"),
+        ("123456789", "100000000000000000000", "3000000000000000000000", 9975, 10000, "3694444410"),
+        (
+            "1000000000000000000000",
+            "2596148429267413814265248164610048",
+            "1298074214633706907132624082305024",
+            998,
+            1000,
+            "498999999999808176607",
+        ),
+        (
+            "1606938044258990275541962092341162602522202993782792835301376",
+            "5192296858534827628530496329220095",
+            "5192296858534827628530496329220095",
+            997,
+            1000,
+            "5192296858534827628530496312392395",
+        ),
+        ("1", "1000", "1000", 997, 1000, "0"),
+        ("1000000000000000000", "1000000000000000000", "1000000000000000000", 9970, 10000, "499248873309964947"),
+    ];
+
+    #[test]
+    fn amount_out_matches_uniswap_v2_library() {
+        for (a_in, reserve_in, reserve_out, fee, denominator, expected) in REFERENCE {
+            assert_eq!(
+                get_amount_out(
+                    u(a_in),
+                    u(reserve_in),
+                    u(reserve_out),
+                    U256::zero(),
+                    U256::from(fee),
+                    U256::from(denominator),
+                ),
+                u(expected),
+                "{} in against {}/{} at {}/{}",
+                a_in,
+                reserve_in,
+                reserve_out,
+                fee,
+                denominator
+            );
+        }
+    }
+
+    #[test]
+    fn equivalent_fee_fractions_agree() {
+        let reserve_in = u("81234567890123456789012");
+        let reserve_out = u("45678901234567890123");
+
+        for a_in in ["1", "999", "1000000000000000", "77777777777777777777", "1000000000000000000000000"] {
+            let amount_out = |fee: u64, denominator: u64| {
+                get_amount_out(
+                    u(a_in),
+                    reserve_in,
+                    reserve_out,
+                    U256::zero(),
+                    U256::from(fee),
+                    U256::from(denominator),
+                )
+            };
+            assert_eq!(amount_out(997, 1000), amount_out(9970, 10000));
+        }
+    }
+
+    #[test]
+    fn token_tax_is_taken_from_output() {
+        let one = u("1000000000000000000");
+        let amount_out = |tax: u64| {
+            get_amount_out(one, one, one, U256::from(tax), U256::from(9970), U256::from(10000))
+        };
+        let no_tax = amount_out(0);
+
+        assert_eq!(amount_out(500), no_tax - no_tax * 500 / 10000);
+        assert!(amount_out(10000).is_zero());
+    }
+
+    fn pool(address: u64, token0: Address, token1: Address, reserve0: &str, reserve1: &str) -> UniV2Pool {
+        UniV2Pool {
+            address: Address::from_low_u64_be(address),
+            token0,
+            token1,
+            reserve0: u(reserve0),
+            reserve1: u(reserve1),
+            router_fee: U256::from(997),
+            router_fee_denominator: U256::from(1000),
+            fees0: U256::zero(),
+            fees1: U256::zero(),
+            tradeable: true,
+        }
+    }
+
+    #[test]
+    fn cycles_start_from_any_base_token_with_inventory() {
+        use crate::cycles::CycleSearch;
+        use crate::state::State;
+
+        let weth = crate::helpers::address(crate::constants::WETH);
+        let usdc = Address::from_low_u64_be(0xc);
+        let token = Address::from_low_u64_be(0xa);
+        // 2000 usdc (6 decimals) per WETH, and a usdc / token price gap between two pools
+        let pools = [
+            pool(1, weth, usdc, "1000000000000000000000", "2000000000000"),
+            pool(2, usdc, token, "1000000000000", "1000000000000000000000000"),
+            pool(3, token, usdc, "1000000000000000000000000", "1100000000000"),
+        ];
+        let search = CycleSearch {
+            start_tokens: vec![weth, usdc],
+            ..Default::default()
+        };
+        let state = State::new_state(&pools, ethers::types::U64::zero(), search);
+        let pending = PendingState::new(&state);
+
+        let inventory = HashMap::from([(usdc, u("10000000000"))]);
+        // both pairs are affected, the cycle is still evaluated once
+        let affected = Some(vec![pools[1].address, pools[2].address]);
+        let cycles = find_optimal_cycles(&pending, affected, &inventory, &GasModel::default(), U256::zero());
+
+        assert_eq!(cycles.len(), 1);
+        let cycle = &cycles[0];
+        assert_eq!(cycle.base_token, usdc);
+        assert_eq!(cycle.cycle_addresses, vec![pools[1].address, pools[2].address]);
+        assert!(cycle.optimal_in <= inventory[&usdc]);
+        assert!(cycle.profit > I256::zero());
+        assert_eq!(
+            cycle.profit_weth.into_raw(),
+            cycle.profit.into_raw() * u("1000000000000000000000") / u("2000000000000")
+        );
+
+        // nothing to start the cycle with
+        let weth_only = HashMap::from([(weth, u("1000000000000000000000"))]);
+        let find = |inventory: &HashMap<Address, U256>| {
+            find_optimal_cycles(&pending, None, inventory, &GasModel::default(), U256::zero())
+        };
+        assert!(find(&weth_only).is_empty());
+        assert_eq!(find(&inventory).len(), 1);
+    }
+
+    // Deterministic stand-in for a random source, the tests don't pull in `rand`
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % bound
+        }
+    }
+
+    // a cycle token_in -> 1 -> .. -> token_in over `hops` pools with random depth, fee and tax
+    fn random_cycle(rng: &mut Lcg, hops: usize) -> (Address, Vec<UniV2Pool>) {
+        let token = |index: usize| Address::from_low_u64_be(0x100 + (index % hops) as u64);
+        let fees = [(997, 1000), (9975, 10000), (998, 1000), (9970, 10000)];
+
+        let pools = (0..hops)
+            .map(|hop| {
+                let depth = U256::exp10(15 + rng.next(10) as usize);
+                // up to 5% price gap per hop, so some cycles pay and some lose
+                let skew = U256::from(950 + rng.next(101));
+                let (fee, fee_denominator) = fees[rng.next(fees.len() as u64) as usize];
+                let tax = if rng.next(3) == 0 { rng.next(300) } else { 0 };
+
+                // the pools alternate which side token_in sits on
+                let (token0, token1) = (token(hop), token(hop + 1));
+                let (reserve0, reserve1) = (depth, depth * skew / 1000);
+                let (token0, token1, reserve0, reserve1) = if hop % 2 == 0 {
+                    (token0, token1, reserve0, reserve1)
+                } else {
+                    (token1, token0, reserve1, reserve0)
+                };
+
+                UniV2Pool {
+                    address: Address::from_low_u64_be(hop as u64 + 1),
+                    token0,
+                    token1,
+                    reserve0,
+                    reserve1,
+                    router_fee: U256::from(fee),
+                    router_fee_denominator: U256::from(fee_denominator),
+                    fees0: U256::from(tax),
+                    fees1: U256::from(tax),
+                    tradeable: true,
+                }
+            })
+            .collect();
+
+        (token(0), pools)
+    }
+
+    #[test]
+    fn closed_form_agrees_with_search() {
+        let mut rng = Lcg(7);
+        let mut profitable = 0;
+
+        for case in 0..600 {
+            let hops = 2 + case % 4;
+            let (token_in, pools) = random_cycle(&mut rng, hops);
+
+            let closed_form = closed_form_optimal_in(token_in, &pools).unwrap();
+            let searched = maximize_profit(
+                U256::one(),
+                U256::exp10(30),
+                U256::from(10),
+                |amount_in| get_profit(token_in, amount_in, &pools),
+            );
+
+            let closed_form_profit = get_profit(token_in, closed_form, &pools);
+            let searched_profit = get_profit(token_in, searched, &pools);
+
+            if searched_profit <= I256::zero() {
+                assert!(closed_form_profit <= I256::one(), "case {}: {} lost", case, closed_form);
+                continue;
+            }
+            profitable += 1;
+
+            // flooring in get_amount_out moves the optimum by a few wei per hop
+            let tolerance = searched_profit / I256::from(1_000_000_000) + I256::from(10 * hops);
+            assert!(
+                (closed_form_profit - searched_profit).abs() <= tolerance,
+                "case {}: closed form {} makes {}, search {} makes {}",
+                case,
+                closed_form,
+                closed_form_profit,
+                searched,
+                searched_profit
+            );
+        }
+
+        assert!(profitable > 50, "only {} profitable cases", profitable);
+    }
+
+    #[test]
+    fn closed_form_needs_non_empty_pools() {
+        let mut rng = Lcg(11);
+        let (token_in, mut pools) = random_cycle(&mut rng, 3);
+        pools[1].reserve0 = U256::zero();
+
+        assert_eq!(closed_form_optimal_in(token_in, &pools), None);
+    }
+
+    #[test]
+    fn cycles_are_ranked_net_of_gas() {
+        use crate::cycles::CycleSearch;
+        use crate::state::State;
+
+        let weth = crate::helpers::address(crate::constants::WETH);
+        let (a, b, c) = (
+            Address::from_low_u64_be(0xa),
+            Address::from_low_u64_be(0xb),
+            Address::from_low_u64_be(0xc),
+        );
+        // a 5% gap over two hops, a 6.5% gap over three
+        let pools = [
+            pool(1, weth, a, "1000000000000000000000", "1000000000000000000000"),
+            pool(2, a, weth, "1000000000000000000000", "1050000000000000000000"),
+            pool(3, weth, b, "1000000000000000000000", "1000000000000000000000"),
+            pool(4, b, c, "1000000000000000000000", "1000000000000000000000"),
+            pool(5, c, weth, "1000000000000000000000", "1065000000000000000000"),
+        ];
+        let search = CycleSearch {
+            start_tokens: vec![weth],
+            ..Default::default()
+        };
+        let state = State::new_state(&pools, ethers::types::U64::zero(), search);
+        let pending = PendingState::new(&state);
+        let inventory = HashMap::from([(weth, u("100000000000000000000"))]);
+        let model = GasModel::default();
+        let find = |base_fee: U256| find_optimal_cycles(&pending, None, &inventory, &model, base_fee);
+
+        let free = find(U256::zero());
+        assert_eq!(free.len(), 2);
+        let (long, short) = (&free[0], &free[1]);
+        assert_eq!((long.shape.hops, short.shape.hops), (3, 2));
+        assert!(long.gas_estimate > short.gas_estimate);
+
+        // a base fee at which the extra hop costs more than the wider gap pays
+        let base_fee = (long.profit_weth - short.profit_weth).into_raw()
+            / (long.gas_estimate - short.gas_estimate)
+            + 1;
+        let priced = find(base_fee);
+        assert_eq!(priced[0].shape.hops, 2);
+        assert_eq!(
+            priced[0].net_profit,
+            priced[0].profit_weth - I256::from_raw(priced[0].gas_estimate * base_fee)
+        );
+
+        // nothing covers its gas
+        assert!(find(short.profit_weth.into_raw() / short.gas_estimate + 1).is_empty());
+    }
+
+    #[test]
+    fn cycles_sharing_a_pool_are_resized_in_sequence() {
+        use crate::cycles::CycleSearch;
+        use crate::state::State;
+
+        let weth = crate::helpers::address(crate::constants::WETH);
+        let (a, b) = (Address::from_low_u64_be(0xa), Address::from_low_u64_be(0xb));
+        // two gaps back to WETH behind pool 1, and a cycle of its own through b
+        let pools = [
+            pool(1, weth, a, "1000000000000000000000", "1000000000000000000000"),
+            pool(2, a, weth, "1000000000000000000000", "1050000000000000000000"),
+            pool(3, a, weth, "1000000000000000000000", "1040000000000000000000"),
+            pool(4, weth, b, "1000000000000000000000", "1000000000000000000000"),
+            pool(5, b, weth, "1000000000000000000000", "1030000000000000000000"),
+        ];
+        let search = CycleSearch {
+            start_tokens: vec![weth],
+            ..Default::default()
+        };
+        let state = State::new_state(&pools, ethers::types::U64::zero(), search);
+        let pending = PendingState::new(&state);
+        let inventory = HashMap::from([(weth, u("100000000000000000000"))]);
+        let model = GasModel::default();
+
+        let candidates = find_optimal_cycles(&pending, None, &inventory, &model, U256::zero());
+        let selected = select_cycles(&pending, candidates.clone(), &inventory, &model, U256::zero());
+        assert!(!selected.is_empty());
+
+        // replayed in order, each cycle earns what it was sized for
+        let replay = |cycles: &[NetPositiveCycle], resize: bool| {
+            let mut overlay = pending.clone();
+            let mut total = I256::zero();
+            for cycle in cycles {
+                let pairs: Vec<UniV2Pool> = cycle
+                    .cycle_addresses
+                    .iter()
+                    .map(|address| overlay.pool(state.address_mapping[address]).unwrap())
+                    .collect();
+                let (profit, swap_amounts) = get_profit_with_amount(cycle.base_token, cycle.optimal_in, &pairs);
+                if resize {
+                    assert_eq!(profit, cycle.profit);
+                }
+                apply_swaps(&mut overlay, cycle.base_token, &pairs, &swap_amounts);
+                total += profit;
+            }
+            total
+        };
+        let selected_total = replay(&selected, true);
+        assert!(selected_total > replay(&candidates, false));
+
+        // the cycle through b shares no pool and keeps its size
+        let independent = |cycles: &[NetPositiveCycle]| {
+            cycles
+                .iter()
+                .find(|cycle| cycle.cycle_addresses.contains(&pools[3].address))
+                .map(|cycle| cycle.optimal_in)
+        };
+        assert!(independent(&candidates).is_some());
+        assert_eq!(independent(&selected), independent(&candidates));
+
+        // the second cycle through pool 1 is left a sliver of its gap
+        let addresses = |cycle: &NetPositiveCycle| cycle.cycle_addresses.clone();
+        let second = selected
+            .iter()
+            .find(|cycle| addresses(cycle) == vec![pools[0].address, pools[2].address])
+            .unwrap();
+        let alone = candidates.iter().find(|cycle| addresses(cycle) == addresses(second)).unwrap();
+        assert!(second.optimal_in < alone.optimal_in);
+
+        // at a base fee it covers alone but not resized, it is dropped
+        let base_fee = second.profit_weth.into_raw() / second.gas_estimate + 1;
+        let candidates = find_optimal_cycles(&pending, None, &inventory, &model, base_fee);
+        assert!(candidates.iter().any(|cycle| addresses(cycle) == addresses(second)));
+        let priced = select_cycles(&pending, candidates, &inventory, &model, base_fee);
+        assert_eq!(
+            priced.iter().map(addresses).collect::<Vec<_>>(),
+            vec![
+                vec![pools[0].address, pools[1].address],
+                vec![pools[3].address, pools[4].address],
+            ]
+        );
+    }
+
+    #[test]
+    fn coinbase_share_is_appended_to_the_callback_data() {
+        use ethers::abi::{decode, ParamType};
+
+        let weth = crate::helpers::address(crate::constants::WETH);
+        let executor = Address::from_low_u64_be(0xe);
+        let cycle = NetPositiveCycle {
+            base_token: weth,
+            profit: I256::from(1000),
+            profit_weth: I256::from(1000),
+            shape: CycleShape { hops: 2, taxed_hops: 0 },
+            gas_estimate: U256::from(165_000),
+            net_profit: I256::from(1000),
+            optimal_in: U256::from(50_000),
+            swap_amounts: vec![(U256::from(49_000), true), (U256::from(51_000), false)],
+            cycle_addresses: vec![Address::from_low_u64_be(1), Address::from_low_u64_be(2)],
+            cycle_tokens: vec![Address::from_low_u64_be(0xa), weth],
+        };
+        let callback = |calldata: Bytes| {
+            let swap = [ParamType::Uint(256), ParamType::Uint(256), ParamType::Address, ParamType::Bytes];
+            decode(&swap, &calldata[4..]).unwrap()[3].clone().into_bytes().unwrap()
+        };
+        let fields = vec![
+            ParamType::Address,
+            ParamType::Uint(256),
+            ParamType::Array(Box::new(ParamType::Address)),
+            ParamType::Array(Box::new(ParamType::Bytes)),
+        ];
+
+        let plain = callback(cycle.encode_data(executor, 0));
+        let paying = callback(cycle.encode_data(executor, 2_500));
+        assert_eq!(encode(&decode(&fields, &plain).unwrap()), plain);
+
+        // the four fields read the same, the share follows them
+        let mut with_share = fields.clone();
+        with_share.push(ParamType::Uint(256));
+        let decoded = decode(&with_share, &paying).unwrap();
+        assert_eq!(decoded[..4], decode(&fields, &plain).unwrap()[..]);
+        assert_eq!(decoded[4], Token::Uint(U256::from(2_500)));
+    }
+
+    #[test]
+    fn permutations_start_with_the_ranking() {
+        let orders = permutations(3);
+        assert_eq!(orders.len(), 6);
+        assert_eq!(orders[0], vec![0, 1, 2]);
+        assert!(orders.iter().all(|order| {
+            let mut sorted = order.clone();
+            sorted.sort();
+            sorted == vec![0, 1, 2]
+        }));
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::ops::Index;
+use std::sync::Arc;
+
+// a write copies the entries of one chunk, about len / MAP_CHUNKS of them
+const MAP_CHUNKS: usize = 4096;
+
+/// Hash map split in chunks behind `Arc`s, so a clone shares everything it does not write
+///
+/// Values sit behind their own `Arc` as well: writing one copies the pointers of its chunk and
+/// that value only. Snapshots of `State` keep the maps of the last version alive, this keeps
+/// the next version from copying them whole.
+#[derive(Debug)]
+pub struct ChunkedMap<K, V> {
+    chunks: Vec<Arc<HashMap<K, Arc<V>>>>,
+    len: usize,
+}
+
+impl<K, V> Clone for ChunkedMap<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            chunks: self.chunks.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Default for ChunkedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            chunks: (0..MAP_CHUNKS).map(|_| Arc::new(HashMap::new())).collect(),
+            len: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> ChunkedMap<K, V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.chunks[chunk_of(key)].get(key).map(Arc::as_ref)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.chunks[chunk_of(key)].contains_key(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.chunks
+            .iter()
+            .flat_map(|chunk| chunk.iter().map(|(key, value)| (key, value.as_ref())))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.chunks.iter().flat_map(|chunk| chunk.keys())
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ChunkedMap<K, V> {
+    pub fn insert(&mut self, key: K, value: V) {
+        let chunk = Arc::make_mut(&mut self.chunks[chunk_of(&key)]);
+        if chunk.insert(key, Arc::new(value)).is_none() {
+            self.len += 1;
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let chunk = &mut self.chunks[chunk_of(key)];
+        // only copied when the key is there to be written
+        if !chunk.contains_key(key) {
+            return None;
+        }
+        Arc::make_mut(chunk).get_mut(key).map(Arc::make_mut)
+    }
+
+    /// The value of `key`, inserted as `V::default()` if missing
+    pub fn get_or_default(&mut self, key: K) -> &mut V
+    where
+        V: Default,
+    {
+        let chunk = Arc::make_mut(&mut self.chunks[chunk_of(&key)]);
+        let len = &mut self.len;
+        let value = chunk.entry(key).or_insert_with(|| {
+            *len += 1;
+            Arc::new(V::default())
+        });
+        Arc::make_mut(value)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> FromIterator<(K, V)> for ChunkedMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::default();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl<K: Hash + Eq, V> Index<&K> for ChunkedMap<K, V> {
+    type Output = V;
+
+    fn index(&self, key: &K) -> &V {
+        self.get(key).expect("key not in map")
+    }
+}
+
+// Same chunk for a key in every map and every run
+fn chunk_of<K: Hash>(key: &K) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % MAP_CHUNKS as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_only_copy_their_chunk() {
+        let base: ChunkedMap<usize, Vec<usize>> = (0..10_000).map(|key| (key, vec![key])).collect();
+        assert_eq!(base.len(), 10_000);
+
+        let mut next = base.clone();
+        next.get_or_default(7).push(8);
+        next.get_or_default(10_000).push(1);
+        *next.get_mut(&42).unwrap() = Vec::new();
+        assert!(next.get_mut(&20_000).is_none());
+
+        // the base version is untouched
+        assert_eq!(base[&7], vec![7]);
+        assert_eq!(base[&42], vec![42]);
+        assert!(!base.contains_key(&10_000));
+        assert_eq!((next[&7].as_slice(), next.len()), (&[7, 8][..], 10_001));
+
+        // every chunk nothing was written to is still shared
+        let written = [7, 10_000, 42].map(|key| chunk_of(&key));
+        let shared = (0..MAP_CHUNKS)
+            .filter(|chunk| Arc::ptr_eq(&base.chunks[*chunk], &next.chunks[*chunk]))
+            .count();
+        let distinct_written = (0..MAP_CHUNKS).filter(|chunk| written.contains(chunk)).count();
+        assert_eq!(shared, MAP_CHUNKS - distinct_written);
+
+        // values in a copied chunk that were not written are shared too
+        let neighbour = base
+            .keys()
+            .find(|key| chunk_of(*key) == chunk_of(&7) && **key != 7)
+            .copied();
+        if let Some(key) = neighbour {
+            assert!(Arc::ptr_eq(
+                &base.chunks[chunk_of(&key)][&key],
+                &next.chunks[chunk_of(&key)][&key]
+            ));
+        }
+    }
+}
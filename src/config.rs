@@ -1,74 +1,976 @@
-use ethers::prelude::k256::ecdsa::SigningKey;
 use ethers::prelude::LocalWallet;
-use ethers::prelude::Wallet;
-//use ethers::ethers_signers::Signer;
+use ethers::prelude::{coins_bip39::English, MnemonicBuilder};
+use ethers::signers::Signer;
 use ethers::providers::{Provider, Ws};
+use ethers::types::{Address, H256, U256};
+use reqwest::Url;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use ethers::prelude::MnemonicBuilder;
-use ethers::signers::coins_bip39::English;
-use crate::contract_modules::uniswap_v2::data_collector::throttle::convert;
+use crate::bribe::{BribeSettings, PaymentMode, ShareRule, BPS};
+use crate::contract_modules::uniswap_v2::types::UniV2;
+use crate::cycles::{CycleSearch, DEFAULT_MAX_CYCLE_HOPS, MAX_CYCLE_HOPS};
+use crate::egress::EgressClient;
+use crate::relay::{get_relay_hosts, RELAY_ENDPOINTS};
+use crate::secrets::SecretString;
+use crate::signer::{BotSigner, SignerError, SignerSource};
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
+use thiserror::Error;
 
+/// Default location of the config file, overridden by `BOT_CONFIG_PATH`
+pub const DEFAULT_CONFIG_PATH: &str = ".env.toml";
 
-#[derive(Deserialize)]
-pub struct Env {
-    pub private_key: String,
-    pub mnemonic: String,
-    pub https: String,
+/// About a week of blocks
+pub const DEFAULT_TAX_CACHE_MAX_AGE: u64 = 50_400;
+
+/// Most searcher wallets derived from the mnemonic
+pub const MAX_SEARCHER_WALLETS: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Could not read config file {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Could not parse config file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Invalid configuration:\n  - {}", .0.join("\n  - "))]
+    Invalid(Vec<String>),
+}
+
+// Config file as written by the user, every field optional so validation can report all of them at once
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    private_key: Option<SecretString>,
+    flashbots_auth_key: Option<SecretString>,
+    mnemonic: Option<SecretString>,
+    https: Option<String>,
+    wss: Option<String>,
+    multicall_contract: Option<String>,
+    chain_id: Option<u64>,
+    egress_allowlist: Option<Vec<String>>,
+    tax_cache_max_age: Option<u64>,
+    max_cycle_hops: Option<usize>,
+    min_cycle_reserve: Option<u64>,
+    evaluation_threads: Option<usize>,
+    searcher_wallets: Option<usize>,
+    searcher_signer: Option<RawSigner>,
+    flashbots_signer: Option<RawSigner>,
+    bribe: Option<RawBribe>,
+    #[serde(rename = "dex")]
+    dexes: Option<Vec<RawDex>>,
+}
+
+// One `[[dex]]` table
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct RawDex {
+    name: Option<String>,
+    factory: Option<String>,
+    router: Option<String>,
+    init_code_hash: Option<String>,
+    fee_numerator: Option<u32>,
+    fee_denominator: Option<u32>,
+    enabled: Option<bool>,
+}
+
+// A `[searcher_signer]` or `[flashbots_signer]` table, for a key not given in hex
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct RawSigner {
+    keystore: Option<String>,
+    passphrase_file: Option<String>,
+    remote_url: Option<String>,
+    address: Option<String>,
+}
+
+// The `[bribe]` table, `[bribe.relays.<name>]` tables take the same share fields
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct RawBribe {
+    strategy: Option<String>,
+    share_bps: Option<u32>,
+    jitter_bps: Option<u32>,
+    min_share_bps: Option<u32>,
+    max_share_bps: Option<u32>,
+    window: Option<usize>,
+    min_margin: Option<u64>,
+    payment: Option<String>,
+    relays: Option<BTreeMap<String, RawBribe>>,
+}
+
+impl RawBribe {
+    // A relay table may only change how the builder is paid
+    fn has_share_rule(&self) -> bool {
+        self.strategy.is_some()
+            || self.share_bps.is_some()
+            || self.jitter_bps.is_some()
+            || self.min_share_bps.is_some()
+            || self.max_share_bps.is_some()
+            || self.window.is_some()
+    }
+}
+
+/// Validated bot configuration, loaded once at startup and passed down
+#[derive(Debug, Clone)]
+pub struct BotConfig {
+    /// Searcher wallet that signs the arbitrage transactions, from `private_key` or
+    /// `[searcher_signer]`
+    pub searcher_signer: SignerSource,
+    /// Flashbots identity used to sign relay requests, from `flashbots_auth_key` or
+    /// `[flashbots_signer]`
+    pub flashbots_signer: SignerSource,
+    pub mnemonic: Option<SecretString>,
+    pub https: Option<String>,
     pub wss: String,
+    /// Executor contract holding the inventory
+    pub multicall_contract: Address,
+    pub chain_id: u64,
+    /// Hosts the bot may send HTTP requests to, defaults to the bundle relays
+    pub egress_allowlist: Vec<String>,
+    /// Blocks after which a cached token tax is measured again
+    pub tax_cache_max_age: u64,
+    /// Longest arbitrage cycle searched for, in pairs
+    pub max_cycle_hops: usize,
+    /// Pairs with less of either token, in its smallest unit, are left out of cycles
+    pub min_cycle_reserve: U256,
+    /// Threads evaluating cycles, 0 for one per core
+    pub evaluation_threads: usize,
+    /// Wallets derived from `mnemonic` to sign bundles with, 1 uses `searcher_signer` when no
+    /// mnemonic is set
+    pub searcher_wallets: usize,
+    /// How arbitrages bribe the builders, from `[bribe]`
+    pub bribe: BribeSettings,
+    /// Uniswap V2 forks to trade on, from the `[[dex]]` tables
+    pub dexes: Vec<UniV2>,
+}
+
+// (field, environment variable overriding it)
+const ENV_OVERRIDES: [(&str, &str); 7] = [
+    ("private_key", "SEARCHER_PRIVATE_KEY"),
+    ("flashbots_auth_key", "FLASHBOTS_AUTH_KEY"),
+    ("mnemonic", "SEARCHER_MNEMONIC"),
+    ("https", "RPC_URL_HTTPS"),
+    ("wss", "RPC_URL_WSS"),
+    ("multicall_contract", "SANDWICH_CONTRACT"),
+    ("chain_id", "CHAIN_ID"),
+];
+
+impl BotConfig {
+    /// Load the config file from `BOT_CONFIG_PATH` (or `.env.toml`), apply env overrides and validate
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = std::env::var("BOT_CONFIG_PATH").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+        let contents = fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+
+        Self::from_sources(&contents, |key| std::env::var(key).ok())
+    }
+
+    /// Build config from toml contents and an environment lookup
+    pub fn from_sources(
+        contents: &str,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let mut raw: RawConfig = toml::from_str(contents)?;
+        let mut errors = Vec::new();
+
+        for (field, var) in ENV_OVERRIDES {
+            let value = match env(var) {
+                Some(value) if !value.trim().is_empty() => value.trim().to_string(),
+                _ => continue,
+            };
+
+            match field {
+                "private_key" => raw.private_key = Some(SecretString::new(value)),
+                "flashbots_auth_key" => raw.flashbots_auth_key = Some(SecretString::new(value)),
+                "mnemonic" => raw.mnemonic = Some(SecretString::new(value)),
+                "https" => raw.https = Some(value),
+                "wss" => raw.wss = Some(value),
+                "multicall_contract" => raw.multicall_contract = Some(value),
+                "chain_id" => match value.parse() {
+                    Ok(chain_id) => raw.chain_id = Some(chain_id),
+                    Err(_) => errors.push(format!("{}: `{}` is not a valid chain id", var, value)),
+                },
+                _ => unreachable!(),
+            }
+        }
+
+        Self::validate(raw, errors)
+    }
+
+    fn validate(raw: RawConfig, mut errors: Vec<String>) -> Result<Self, ConfigError> {
+        let searcher_signer = check_signer(
+            raw.private_key,
+            raw.searcher_signer,
+            ("private_key", "SEARCHER_PRIVATE_KEY", "searcher_signer"),
+            &mut errors,
+        );
+        let flashbots_signer = check_signer(
+            raw.flashbots_auth_key,
+            raw.flashbots_signer,
+            ("flashbots_auth_key", "FLASHBOTS_AUTH_KEY", "flashbots_signer"),
+            &mut errors,
+        );
+
+        let wss = match raw.wss {
+            Some(wss) => {
+                if !check_url(&wss, &["ws", "wss"]) {
+                    errors.push(format!("wss: `{}` is not a ws:// or wss:// url", wss));
+                }
+                Some(wss)
+            }
+            None => {
+                errors.push("wss: missing (set in config or RPC_URL_WSS)".to_string());
+                None
+            }
+        };
+
+        if let Some(https) = &raw.https {
+            if !check_url(https, &["http", "https"]) {
+                errors.push(format!("https: `{}` is not a http:// or https:// url", https));
+            }
+        }
+
+        if let Some(mnemonic) = &raw.mnemonic {
+            if mnemonic.expose_secret().split_whitespace().count() < 12 {
+                errors.push("mnemonic: expected at least 12 words".to_string());
+            } else if derive_wallet(mnemonic, 0).is_none() {
+                errors.push("mnemonic: not a valid BIP-39 phrase".to_string());
+            }
+        }
+
+        let searcher_wallets = raw.searcher_wallets.unwrap_or(1);
+        if !(1..=MAX_SEARCHER_WALLETS).contains(&searcher_wallets) {
+            errors.push(format!("searcher_wallets: must be in 1..={}", MAX_SEARCHER_WALLETS));
+        } else if searcher_wallets > 1 && raw.mnemonic.is_none() {
+            errors.push("searcher_wallets: more than one wallet needs a mnemonic".to_string());
+        }
+
+        let multicall_contract = match raw.multicall_contract {
+            Some(addr) => match addr.parse::<Address>() {
+                Ok(addr) if !addr.is_zero() => Some(addr),
+                _ => {
+                    errors.push(format!("multicall_contract: `{}` is not a valid address", addr));
+                    None
+                }
+            },
+            None => {
+                errors.push("multicall_contract: missing (set in config or SANDWICH_CONTRACT)".to_string());
+                None
+            }
+        };
+
+        let chain_id = raw.chain_id.unwrap_or(1);
+        if chain_id == 0 {
+            errors.push("chain_id: must be non zero".to_string());
+        }
+
+        let egress_allowlist = raw.egress_allowlist.unwrap_or_else(get_relay_hosts);
+        if egress_allowlist.iter().any(|host| host.trim().is_empty() || host.contains('/')) {
+            errors.push("egress_allowlist: entries must be bare host names".to_string());
+        }
+
+        let tax_cache_max_age = raw.tax_cache_max_age.unwrap_or(DEFAULT_TAX_CACHE_MAX_AGE);
+        if tax_cache_max_age == 0 {
+            errors.push("tax_cache_max_age: must be non zero".to_string());
+        }
+
+        let max_cycle_hops = raw.max_cycle_hops.unwrap_or(DEFAULT_MAX_CYCLE_HOPS);
+        if !(2..=MAX_CYCLE_HOPS).contains(&max_cycle_hops) {
+            errors.push(format!("max_cycle_hops: must be in 2..={}", MAX_CYCLE_HOPS));
+        }
+
+        let bribe = check_bribe(raw.bribe.unwrap_or_default(), &mut errors);
+        let dexes = check_dexes(raw.dexes.unwrap_or_default(), &mut errors);
+
+        if !errors.is_empty() {
+            return Err(ConfigError::Invalid(errors));
+        }
+
+        Ok(Self {
+            searcher_signer: searcher_signer.unwrap(),
+            flashbots_signer: flashbots_signer.unwrap(),
+            mnemonic: raw.mnemonic,
+            https: raw.https,
+            wss: wss.unwrap(),
+            multicall_contract: multicall_contract.unwrap(),
+            chain_id,
+            egress_allowlist,
+            tax_cache_max_age,
+            max_cycle_hops,
+            min_cycle_reserve: U256::from(raw.min_cycle_reserve.unwrap_or(0)),
+            evaluation_threads: raw.evaluation_threads.unwrap_or(0),
+            searcher_wallets,
+            bribe,
+            dexes,
+        })
+    }
+
+    /// Signer of the arbitrage transactions, unlocked or looked up on the remote signer
+    pub async fn searcher_signer(&self, egress: &EgressClient) -> Result<BotSigner, SignerError> {
+        self.searcher_signer.load(self.chain_id, egress).await
+    }
+
+    /// Signers of the arbitrage transactions, `searcher_wallets` of them derived from the
+    /// mnemonic at indices 0.., or the `searcher_signer` alone without one
+    pub async fn searcher_signers(&self, egress: &EgressClient) -> Result<Vec<BotSigner>, SignerError> {
+        match &self.mnemonic {
+            Some(mnemonic) => Ok((0..self.searcher_wallets as u32)
+                .map(|index| {
+                    derive_wallet(mnemonic, index)
+                        .expect("validated at load")
+                        .with_chain_id(self.chain_id)
+                        .into()
+                })
+                .collect()),
+            None => Ok(vec![self.searcher_signer(egress).await?]),
+        }
+    }
+
+    /// This is your flashbots searcher identity
+    pub async fn bundle_signer(&self, egress: &EgressClient) -> Result<BotSigner, SignerError> {
+        self.flashbots_signer.load(self.chain_id, egress).await
+    }
+
+    /// Egress to the allowlist, and to the remote signers the config points at
+    pub fn egress_client(&self) -> EgressClient {
+        let signer_hosts = [&self.searcher_signer, &self.flashbots_signer]
+            .into_iter()
+            .filter_map(SignerSource::remote_host);
+        EgressClient::new(self.egress_allowlist.iter().cloned().chain(signer_hosts))
+    }
+
+    pub fn cycle_search(&self) -> CycleSearch {
+        CycleSearch {
+            max_hops: self.max_cycle_hops,
+            min_reserve: self.min_cycle_reserve,
+            ..Default::default()
+        }
+    }
 }
 
+fn parse_wallet(key: &SecretString) -> Option<LocalWallet> {
+    key.expose_secret().parse::<LocalWallet>().ok()
+}
 
-pub fn get_env() -> Env {
+// Wallet at `m/44'/60'/0'/0/{index}`
+fn derive_wallet(mnemonic: &SecretString, index: u32) -> Option<LocalWallet> {
+    MnemonicBuilder::<English>::default()
+        .phrase(mnemonic.expose_secret())
+        .index(index)
+        .ok()?
+        .build()
+        .ok()
+}
 
-  let config_contents = fs::read_to_string(".env.toml").expect("error finding env.toml");
+// A hex key or a signer table, never both
+//
+// Arguments:
+// * `names`: the key's field, the env variable overriding it and the table's name
+fn check_signer(
+    key: Option<SecretString>,
+    table: Option<RawSigner>,
+    names: (&str, &str, &str),
+    errors: &mut Vec<String>,
+) -> Option<SignerSource> {
+    let (field, var, table_name) = names;
 
-  let config: Env  = toml::from_str(config_contents.clone().as_str()).expect("Env failure");
+    match (key, table) {
+        (Some(_), Some(_)) => {
+            errors.push(format!("{}: set either the key or [{}], not both", field, table_name));
+            None
+        }
+        (Some(key), None) => check_key(key, field, errors).map(SignerSource::PrivateKey),
+        (None, Some(table)) => check_signer_table(table, table_name, errors),
+        (None, None) => {
+            errors.push(format!(
+                "{}: missing (set in config, {} or a [{}] table)",
+                field, var, table_name
+            ));
+            None
+        }
+    }
+}
 
-  config	
+// Never echo the key itself into the error
+fn check_key(key: SecretString, field: &str, errors: &mut Vec<String>) -> Option<SecretString> {
+    if parse_wallet(&key).is_some() {
+        Some(key)
+    } else {
+        errors.push(format!("{}: not a valid hex private key", field));
+        None
+    }
+}
+
+// Files are checked here, the keystore is only decrypted once the bot starts
+fn check_signer_table(table: RawSigner, name: &str, errors: &mut Vec<String>) -> Option<SignerSource> {
+    let errors_before = errors.len();
+
+    let source = match (table.keystore, table.remote_url) {
+        (Some(keystore), None) => {
+            if table.address.is_some() {
+                errors.push(format!("{}: address is only used with remote_url", name));
+            }
+            for file in std::iter::once(&keystore).chain(&table.passphrase_file) {
+                if !Path::new(file).is_file() {
+                    errors.push(format!("{}: `{}` is not a file", name, file));
+                }
+            }
+            SignerSource::Keystore {
+                path: PathBuf::from(keystore),
+                passphrase_file: table.passphrase_file.map(PathBuf::from),
+            }
+        }
+        (None, Some(url)) => {
+            if !check_url(&url, &["http", "https"]) {
+                errors.push(format!("{}: `{}` is not a http:// or https:// url", name, url));
+            }
+            if table.passphrase_file.is_some() {
+                errors.push(format!("{}: passphrase_file is only used with keystore", name));
+            }
+            let address = match table.address.map(|address| address.parse::<Address>()) {
+                Some(Ok(address)) if !address.is_zero() => address,
+                Some(_) => {
+                    errors.push(format!("{}: address is not a valid address", name));
+                    Address::zero()
+                }
+                None => {
+                    errors.push(format!("{}: remote_url needs the address of its key", name));
+                    Address::zero()
+                }
+            };
+            SignerSource::Remote { url, address }
+        }
+        (Some(_), Some(_)) => {
+            errors.push(format!("{}: set either keystore or remote_url, not both", name));
+            return None;
+        }
+        (None, None) => {
+            errors.push(format!("{}: missing keystore or remote_url", name));
+            return None;
+        }
+    };
+
+    (errors.len() == errors_before).then_some(source)
+}
+
+fn check_bribe(raw: RawBribe, errors: &mut Vec<String>) -> BribeSettings {
+    let default = BribeSettings::default();
+    let rule = check_share_rule(&raw, "bribe", errors).unwrap_or(default.rule);
+    let payment = check_payment(&raw, "bribe", errors).unwrap_or(default.payment);
+
+    let mut relays = Vec::new();
+    let mut relay_payments = Vec::new();
+    for (name, relay) in raw.relays.unwrap_or_default() {
+        let label = format!("bribe.relays.{}", name);
+        if !RELAY_ENDPOINTS.iter().any(|(known, _)| *known == name) {
+            errors.push(format!("{}: not a known relay", label));
+        }
+        if relay.min_margin.is_some() || relay.relays.is_some() {
+            errors.push(format!("{}: min_margin and relays only go in [bribe]", label));
+        }
+        if let Some(payment) = check_payment(&relay, &label, errors) {
+            relay_payments.push((name.clone(), payment));
+        }
+        if !relay.has_share_rule() {
+            continue;
+        }
+        if let Some(rule) = check_share_rule(&relay, &label, errors) {
+            relays.push((name, rule));
+        }
+    }
+
+    BribeSettings {
+        rule,
+        relays,
+        min_margin: U256::from(raw.min_margin.unwrap_or(0)),
+        payment,
+        relay_payments,
+    }
+}
+
+fn check_payment(raw: &RawBribe, label: &str, errors: &mut Vec<String>) -> Option<PaymentMode> {
+    match raw.payment.as_deref()? {
+        "priority_fee" => Some(PaymentMode::PriorityFee),
+        "coinbase" => Some(PaymentMode::Coinbase),
+        other => {
+            errors.push(format!(
+                "{}: unknown payment `{}`, expected priority_fee or coinbase",
+                label, other
+            ));
+            None
+        }
+    }
+}
+
+fn check_share_rule(raw: &RawBribe, label: &str, errors: &mut Vec<String>) -> Option<ShareRule> {
+    let in_range = |field: &str, value: u32, errors: &mut Vec<String>| {
+        if value > BPS {
+            errors.push(format!("{}: {} must be at most {}", label, field, BPS));
+        }
+    };
+
+    match raw.strategy.as_deref().unwrap_or("fixed") {
+        "fixed" => {
+            if raw.min_share_bps.is_some() || raw.max_share_bps.is_some() || raw.window.is_some() {
+                errors.push(format!("{}: min_share_bps, max_share_bps and window are for adaptive", label));
+            }
+            let share_bps = raw.share_bps.unwrap_or(7_000);
+            let jitter_bps = raw.jitter_bps.unwrap_or(100);
+            in_range("share_bps", share_bps + jitter_bps, errors);
+            Some(ShareRule::Fixed {
+                share_bps,
+                jitter_bps,
+            })
+        }
+        "adaptive" => {
+            if raw.share_bps.is_some() || raw.jitter_bps.is_some() {
+                errors.push(format!("{}: share_bps and jitter_bps are for fixed", label));
+            }
+            let min_bps = raw.min_share_bps.unwrap_or(5_000);
+            let max_bps = raw.max_share_bps.unwrap_or(9_000);
+            in_range("max_share_bps", max_bps, errors);
+            if min_bps > max_bps {
+                errors.push(format!("{}: min_share_bps is above max_share_bps", label));
+            }
+            let window = raw.window.unwrap_or(20);
+            if window == 0 {
+                errors.push(format!("{}: window must be non zero", label));
+            }
+            Some(ShareRule::Adaptive {
+                min_bps: min_bps.min(max_bps),
+                max_bps,
+                window,
+            })
+        }
+        other => {
+            errors.push(format!("{}: unknown strategy `{}`, expected fixed or adaptive", label, other));
+            None
+        }
+    }
+}
+
+fn check_dexes(raw: Vec<RawDex>, errors: &mut Vec<String>) -> Vec<UniV2> {
+    let mut dexes = Vec::new();
+
+    if raw.is_empty() {
+        errors.push("dex: at least one [[dex]] entry is required".to_string());
+    }
+
+    for (idx, dex) in raw.into_iter().enumerate() {
+        let name = dex.name.clone().unwrap_or_default();
+        let label = format!("dex[{}] ({})", idx, name);
+        let errors_before = errors.len();
+
+        if name.trim().is_empty() {
+            errors.push(format!("{}: missing name", label));
+        } else if dexes.iter().any(|known: &UniV2| known.name == name) {
+            errors.push(format!("{}: duplicate name", label));
+        }
+
+        let mut address = |field: &str, value: Option<String>| match value.map(|v| v.parse::<Address>()) {
+            Some(Ok(addr)) if !addr.is_zero() => addr,
+            Some(_) => {
+                errors.push(format!("{}: {} is not a valid address", label, field));
+                Address::zero()
+            }
+            None => {
+                errors.push(format!("{}: missing {}", label, field));
+                Address::zero()
+            }
+        };
+        let factory = address("factory", dex.factory);
+        let router = address("router", dex.router);
+
+        let init_code_hash = match dex.init_code_hash.map(|v| v.parse::<H256>()) {
+            Some(Ok(hash)) => hash,
+            Some(Err(_)) => {
+                errors.push(format!("{}: init_code_hash is not a 32 byte hex string", label));
+                H256::zero()
+            }
+            None => {
+                errors.push(format!("{}: missing init_code_hash", label));
+                H256::zero()
+            }
+        };
+
+        match (dex.fee_numerator, dex.fee_denominator) {
+            (Some(num), Some(den)) if num > 0 && num <= den => {}
+            (Some(_), Some(_)) => errors.push(format!(
+                "{}: fee_numerator must be in 1..=fee_denominator",
+                label
+            )),
+            _ => errors.push(format!("{}: missing fee_numerator or fee_denominator", label)),
+        }
+
+        if errors.len() == errors_before {
+            dexes.push(UniV2 {
+                name,
+                factory,
+                router,
+                fee: U256::from(dex.fee_numerator.unwrap()),
+                fee_denominator: U256::from(dex.fee_denominator.unwrap()),
+                init_code_hash,
+                enabled: dex.enabled.unwrap_or(true),
+            });
+        }
+    }
+
+    dexes
+}
+
+fn check_url(url: &str, schemes: &[&str]) -> bool {
+    match Url::parse(url) {
+        Ok(url) => schemes.contains(&url.scheme()) && url.host_str().is_some(),
+        Err(_) => false,
+    }
 }
 
 
 // Main Config
 pub struct Config {
+    pub settings: Arc<BotConfig>,
     pub wss: Arc<Provider<Ws>>,
-    pub wallet: Arc<Wallet<SigningKey>>,
+    /// Signers of the searcher wallets
+    pub searchers: Vec<BotSigner>,
+    pub bundle_signer: BotSigner,
+    pub egress: EgressClient,
 }
 
 impl Config {
-    // Implement a constructor for the configuration struct
-    pub async fn new() -> Self {
-        
+    // Connect everything the validated config points at
+    pub async fn new(settings: Arc<BotConfig>) -> Self {
+        let ws_provider: Provider<Ws> = Provider::<Ws>::connect(settings.wss.clone())
+            .await
+            .expect("RPC Connection Error");
 
+        let egress = settings.egress_client();
+        let searchers = match settings.searcher_signers(&egress).await {
+            Ok(searchers) => searchers,
+            Err(e) => panic!("Could not load the searcher signer: {}", e),
+        };
+        let bundle_signer = match settings.bundle_signer(&egress).await {
+            Ok(signer) => signer,
+            Err(e) => panic!("Could not load the flashbots signer: {}", e),
+        };
 
-	let env = get_env();
+        Self {
+            settings,
+            wss: Arc::new(ws_provider),
+            searchers,
+            bundle_signer,
+            egress,
+        }
+    }
+}
 
-        let _chain_id = 1_u64; //provider.get_chainid().await.unwrap().as_u64();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
 
-        let private_key = env.private_key.clone();
-        convert(private_key.as_str()).await;
+    const SEARCHER: &str = "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+    const AUTH: &str = "0x8da4ef21b864d2cc526dbdb2a120bd2874c36c9d0a1fb7f8c63d7f7a8b41de8f";
 
+    fn valid_toml() -> String {
+        format!(
+            r#"
+            private_key = "{}"
+            flashbots_auth_key = "{}"
+            wss = "wss://node.example"
+            https = "https://node.example"
+            multicall_contract = "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f"
 
-        let wss_url = env.wss.clone();
-        let ws_provider: Provider<Ws> = Provider::<Ws>::connect(wss_url).await.unwrap();
+            [[dex]]
+            name = "uniswap"
+            factory = "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f"
+            router = "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D"
+            init_code_hash = "0x96e8ac4277198ff8b6f785478aa9a39f403cb768dd02cbee326c3e7da348845f"
+            fee_numerator = 997
+            fee_denominator = 1000
+            "#,
+            SEARCHER, AUTH
+        )
+    }
 
-        /*let _wallet_result = MnemonicBuilder::<English>::default()
-                .phrase(phrase.as_str())
-                .index(0_u32)
-                .unwrap()
-                .build().expect("Wallet error");*/
-                
-        let wallet = private_key
-            .parse::<LocalWallet>()
-            .expect("invalid PRIVATE_KEY")
-            .with_chain_id(_chain_id);
+    fn without_keys(fields: &[&str]) -> String {
+        valid_toml()
+            .lines()
+            .filter(|line| !fields.iter().any(|field| line.trim_start().starts_with(field)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 
-        //let middleware = Arc::new(SignerMiddleware::new(provider, wallet.clone()));
-        Self {
-            //http: middleware,
-            wss: Arc::new(ws_provider),
-            wallet: Arc::new(wallet),
+    #[test]
+    fn loads_valid_config() {
+        let config = BotConfig::from_sources(&valid_toml(), |_| None).unwrap();
+
+        assert_eq!(config.chain_id, 1);
+        assert_eq!(config.wss, "wss://node.example");
+        assert_eq!(config.egress_allowlist, get_relay_hosts());
+        assert_eq!(config.max_cycle_hops, DEFAULT_MAX_CYCLE_HOPS);
+        assert_eq!(config.dexes.len(), 1);
+        assert_eq!(config.dexes[0].fee, U256::from(997));
+        assert!(config.dexes[0].enabled);
+    }
+
+    #[test]
+    fn reports_invalid_dex_entries() {
+        let contents = format!(
+            r#"{}
+            [[dex]]
+            name = "uniswap"
+            factory = "0x1234"
+            init_code_hash = "0xe18a"
+            fee_numerator = 1001
+            fee_denominator = 1000
+            enabled = false
+            "#,
+            valid_toml()
+        );
+
+        let err = BotConfig::from_sources(&contents, |_| None).unwrap_err();
+        let message = err.to_string();
+
+        for problem in [
+            "dex[1] (uniswap): duplicate name",
+            "dex[1] (uniswap): factory is not a valid address",
+            "dex[1] (uniswap): missing router",
+            "dex[1] (uniswap): init_code_hash",
+            "dex[1] (uniswap): fee_numerator",
+        ] {
+            assert!(message.contains(problem), "{} missing from {}", problem, message);
+        }
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let env = HashMap::from([
+            ("RPC_URL_WSS", "ws://127.0.0.1:8546"),
+            ("SANDWICH_CONTRACT", "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D"),
+        ]);
+        let config =
+            BotConfig::from_sources(&valid_toml(), |k| env.get(k).map(|v| v.to_string())).unwrap();
+
+        assert_eq!(config.wss, "ws://127.0.0.1:8546");
+        assert_eq!(
+            config.multicall_contract,
+            "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D".parse::<Address>().unwrap()
+        );
+    }
+
+    #[test]
+    fn reports_every_invalid_field_without_leaking_keys() {
+        let contents = r#"
+            private_key = "0xnot-a-key-but-secret-looking-value"
+            wss = "https://wrong.scheme"
+            multicall_contract = "0x0"
+        "#;
+
+        let err = BotConfig::from_sources(contents, |_| None).unwrap_err();
+        let message = err.to_string();
+
+        match err {
+            ConfigError::Invalid(errors) => assert_eq!(errors.len(), 5),
+            other => panic!("unexpected error {:?}", other),
+        }
+        for field in ["private_key", "flashbots_auth_key", "wss", "multicall_contract", "dex"] {
+            assert!(message.contains(field), "{} missing from {}", field, message);
+        }
+        assert!(!message.contains("secret-looking"));
+    }
+
+    #[test]
+    fn rejects_out_of_range_cycle_length() {
+        let contents = format!("max_cycle_hops = 6\n{}", valid_toml());
+
+        let err = BotConfig::from_sources(&contents, |_| None).unwrap_err();
+        assert!(err.to_string().contains("max_cycle_hops"));
+    }
+
+    #[tokio::test]
+    async fn derives_searcher_wallets_from_the_mnemonic() {
+        let mnemonic = "test test test test test test test test test test test junk";
+        let contents = format!("mnemonic = \"{}\"\nsearcher_wallets = 2\n{}", mnemonic, valid_toml());
+        let egress = EgressClient::new(Vec::<String>::new());
+
+        let config = BotConfig::from_sources(&contents, |_| None).unwrap();
+        let signers = config.searcher_signers(&egress).await.unwrap();
+        let addresses: Vec<Address> = signers.iter().map(|w| w.address()).collect();
+        assert_eq!(
+            addresses,
+            vec![
+                "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266".parse::<Address>().unwrap(),
+                "0x70997970C51812dc3A010C7d01b50e0d17dc79C8".parse::<Address>().unwrap(),
+            ]
+        );
+
+        // without a mnemonic only the private key signs
+        let single = BotConfig::from_sources(&valid_toml(), |_| None).unwrap();
+        let signers = single.searcher_signers(&egress).await.unwrap();
+        assert_eq!(signers.len(), 1);
+        assert_eq!(signers[0].address(), SEARCHER.parse::<LocalWallet>().unwrap().address());
+
+        let contents = format!("searcher_wallets = 2\n{}", valid_toml());
+        let err = BotConfig::from_sources(&contents, |_| None).unwrap_err();
+        assert!(err.to_string().contains("searcher_wallets: more than one wallet needs a mnemonic"));
+    }
+
+    #[test]
+    fn signer_tables_replace_the_hex_keys() {
+        let keystore = std::env::temp_dir().join(format!("searcher_keystore_{}.json", std::process::id()));
+        std::fs::write(&keystore, "{}").unwrap();
+        let rest = without_keys(&["private_key", "flashbots_auth_key"]);
+
+        let contents = format!(
+            r#"{}
+            [searcher_signer]
+            keystore = "{}"
+
+            [flashbots_signer]
+            remote_url = "http://signer.internal:9000"
+            address = "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D"
+            "#,
+            rest,
+            keystore.display()
+        );
+        let config = BotConfig::from_sources(&contents, |_| None);
+        std::fs::remove_file(&keystore).unwrap();
+        let config = config.unwrap();
+
+        assert!(matches!(
+            &config.searcher_signer,
+            SignerSource::Keystore { path, passphrase_file: None } if *path == keystore
+        ));
+        assert!(matches!(config.flashbots_signer, SignerSource::Remote { .. }));
+        assert!(config.egress_client().check_url("http://signer.internal:9000/").is_ok());
+
+        // a key and a table for the same identity, and tables missing what they need
+        let contents = format!(
+            r#"{}
+            [searcher_signer]
+            remote_url = "http://signer.internal:9000"
+
+            [flashbots_signer]
+            keystore = "./does_not_exist.json"
+            "#,
+            without_keys(&["flashbots_auth_key"])
+        );
+        let message = BotConfig::from_sources(&contents, |_| None).unwrap_err().to_string();
+        for problem in [
+            "private_key: set either the key or [searcher_signer], not both",
+            "flashbots_signer: `./does_not_exist.json` is not a file",
+        ] {
+            assert!(message.contains(problem), "{} missing from {}", problem, message);
         }
     }
+
+    #[test]
+    fn bribe_table_selects_the_strategy() {
+        let config = BotConfig::from_sources(&valid_toml(), |_| None).unwrap();
+        assert_eq!(config.bribe, BribeSettings::default());
+
+        let contents = format!(
+            r#"{}
+            [bribe]
+            strategy = "adaptive"
+            max_share_bps = 9500
+            min_margin = 1000000000000000
+
+            [bribe.relays.flashbots]
+            share_bps = 9000
+            jitter_bps = 0
+            "#,
+            valid_toml()
+        );
+        let config = BotConfig::from_sources(&contents, |_| None).unwrap();
+        assert_eq!(
+            config.bribe,
+            BribeSettings {
+                rule: ShareRule::Adaptive {
+                    min_bps: 5_000,
+                    max_bps: 9_500,
+                    window: 20
+                },
+                relays: vec![(
+                    "flashbots".to_string(),
+                    ShareRule::Fixed {
+                        share_bps: 9_000,
+                        jitter_bps: 0
+                    }
+                )],
+                min_margin: U256::exp10(15),
+                ..Default::default()
+            }
+        );
+
+        let contents = format!(
+            r#"{}
+            [bribe]
+            strategy = "auction"
+
+            [bribe.relays.nowhere]
+            strategy = "adaptive"
+            min_share_bps = 9000
+            max_share_bps = 8000
+            "#,
+            valid_toml()
+        );
+        let message = BotConfig::from_sources(&contents, |_| None).unwrap_err().to_string();
+        for problem in [
+            "bribe: unknown strategy `auction`",
+            "bribe.relays.nowhere: not a known relay",
+            "bribe.relays.nowhere: min_share_bps is above max_share_bps",
+        ] {
+            assert!(message.contains(problem), "{} missing from {}", problem, message);
+        }
+    }
+
+    #[test]
+    fn bribe_payment_is_set_per_relay() {
+        let config = BotConfig::from_sources(&valid_toml(), |_| None).unwrap();
+        assert_eq!(config.bribe.payment("flashbots"), PaymentMode::PriorityFee);
+
+        // a relay table changing only the payment keeps the default's share
+        let contents = format!(
+            r#"{}
+            [bribe]
+            payment = "coinbase"
+
+            [bribe.relays.beaverbuild]
+            payment = "priority_fee"
+            "#,
+            valid_toml()
+        );
+        let config = BotConfig::from_sources(&contents, |_| None).unwrap();
+        assert!(config.bribe.relays.is_empty());
+        assert_eq!(config.bribe.payment("flashbots"), PaymentMode::Coinbase);
+        assert_eq!(config.bribe.payment("beaverbuild"), PaymentMode::PriorityFee);
+
+        let contents = format!(
+            r#"{}
+            [bribe]
+            payment = "transfer"
+
+            [bribe.relays.flashbots]
+            payment = "Coinbase"
+            "#,
+            valid_toml()
+        );
+        let message = BotConfig::from_sources(&contents, |_| None).unwrap_err().to_string();
+        for problem in [
+            "bribe: unknown payment `transfer`, expected priority_fee or coinbase",
+            "bribe.relays.flashbots: unknown payment `Coinbase`",
+        ] {
+            assert!(message.contains(problem), "{} missing from {}", problem, message);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let contents = format!("{}\nmax_hop = 3", valid_toml());
+        assert!(matches!(
+            BotConfig::from_sources(&contents, |_| None),
+            Err(ConfigError::Parse(_))
+        ));
+    }
 }
@@ -1,13 +1,56 @@
-use ethers::types::U256;
+use ethers::types::{Address, H256, U256};
+use ethers::utils::keccak256;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::prelude::*;
+use thiserror::Error;
 
-use super::types::UniV2Pool;
+use super::types::{UniV2, UniV2Pool};
+
+pub const CHECKPOINT_PATH: &str = "./db.bin";
+/// Seconds between periodic checkpoints, one is also written on shutdown
+pub const CHECKPOINT_INTERVAL: u64 = 300;
+
+/// Bump when the binary layout changes, older files are rejected
+pub const CHECKPOINT_VERSION: u16 = 1;
+const CHECKPOINT_MAGIC: &[u8; 4] = b"UV2C";
+// magic, version, chain id, block, registry hash, pool count
+const HEADER_LEN: usize = 4 + 2 + 8 + 8 + 32 + 8;
+const CHECKSUM_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("Checkpoint io failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Checkpoint json is invalid: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Not a binary checkpoint")]
+    BadMagic(),
+    #[error("Checkpoint format version {0} is not supported (expected {CHECKPOINT_VERSION})")]
+    UnsupportedVersion(u16),
+    #[error("Checkpoint is truncated or corrupted")]
+    Corrupted(),
+    #[error("Checkpoint checksum does not match its contents")]
+    ChecksumMismatch(),
+    #[error("Checkpoint is for chain {found}, running on chain {expected}")]
+    ChainMismatch { expected: u64, found: u64 },
+    #[error("Checkpoint was written with a different dex registry")]
+    RegistryMismatch(),
+}
+
+/// Identifies what a binary checkpoint was written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointHeader {
+    pub version: u16,
+    pub chain_id: u64,
+    pub block: u64,
+    pub registry_hash: H256,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Storage {
     pub pools: Vec<UniV2Pool>,
+    // last block whose Sync logs are applied to the reserves
     pub block: U256,
 }
 
@@ -29,4 +72,319 @@ impl Storage {
         let storage: Storage = serde_json::from_reader(reader)?;
         Ok(storage)
     }
+
+    /// Write the binary checkpoint, the file is replaced only once it is fully written
+    pub fn save_binary(
+        &self,
+        file_path: &str,
+        chain_id: u64,
+        registry_hash: H256,
+    ) -> Result<(), CheckpointError> {
+        let encoded = self.encode(chain_id, registry_hash);
+
+        let tmp_path = format!("{}.tmp", file_path);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&encoded)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, file_path)?;
+        Ok(())
+    }
+
+    /// Load a binary checkpoint, rejecting one written for another chain or registry
+    pub fn load_binary(
+        file_path: &str,
+        chain_id: u64,
+        registry_hash: H256,
+    ) -> Result<Storage, CheckpointError> {
+        let (header, storage) = Self::read_binary(file_path)?;
+
+        if header.chain_id != chain_id {
+            return Err(CheckpointError::ChainMismatch {
+                expected: chain_id,
+                found: header.chain_id,
+            });
+        }
+        if header.registry_hash != registry_hash {
+            return Err(CheckpointError::RegistryMismatch());
+        }
+
+        Ok(storage)
+    }
+
+    /// Read a binary checkpoint and its header, only the checksum and version are checked
+    pub fn read_binary(file_path: &str) -> Result<(CheckpointHeader, Storage), CheckpointError> {
+        let mut bytes = Vec::new();
+        File::open(file_path)?.read_to_end(&mut bytes)?;
+        Self::decode(&bytes)
+    }
+
+    pub fn encode(&self, chain_id: u64, registry_hash: H256) -> Vec<u8> {
+        // a pool is at most 3 addresses, 6 length prefixed words and a flag
+        let mut out = Vec::with_capacity(HEADER_LEN + self.pools.len() * 80 + CHECKSUM_LEN);
+
+        out.extend_from_slice(CHECKPOINT_MAGIC);
+        out.extend_from_slice(&CHECKPOINT_VERSION.to_be_bytes());
+        out.extend_from_slice(&chain_id.to_be_bytes());
+        out.extend_from_slice(&self.block.low_u64().to_be_bytes());
+        out.extend_from_slice(registry_hash.as_bytes());
+        out.extend_from_slice(&(self.pools.len() as u64).to_be_bytes());
+
+        for pool in self.pools.iter() {
+            out.extend_from_slice(pool.address.as_bytes());
+            out.extend_from_slice(pool.token0.as_bytes());
+            out.extend_from_slice(pool.token1.as_bytes());
+            for value in [
+                pool.reserve0,
+                pool.reserve1,
+                pool.router_fee,
+                pool.router_fee_denominator,
+                pool.fees0,
+                pool.fees1,
+            ] {
+                write_u256(&mut out, value);
+            }
+            out.push(pool.tradeable as u8);
+        }
+
+        let checksum = keccak256(&out);
+        out.extend_from_slice(&checksum);
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<(CheckpointHeader, Storage), CheckpointError> {
+        if bytes.len() < 4 || &bytes[0..4] != CHECKPOINT_MAGIC {
+            return Err(CheckpointError::BadMagic());
+        }
+        if bytes.len() < HEADER_LEN + CHECKSUM_LEN {
+            return Err(CheckpointError::Corrupted());
+        }
+
+        let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        if keccak256(body) != checksum {
+            return Err(CheckpointError::ChecksumMismatch());
+        }
+
+        let mut reader = Reader { bytes: body, pos: 4 };
+        let version = u16::from_be_bytes(reader.array()?);
+        if version != CHECKPOINT_VERSION {
+            return Err(CheckpointError::UnsupportedVersion(version));
+        }
+
+        let header = CheckpointHeader {
+            version,
+            chain_id: u64::from_be_bytes(reader.array()?),
+            block: u64::from_be_bytes(reader.array()?),
+            registry_hash: H256(reader.array()?),
+        };
+
+        let count = u64::from_be_bytes(reader.array()?) as usize;
+        let mut pools = Vec::with_capacity(count.min(body.len() / 60));
+
+        for _ in 0..count {
+            pools.push(UniV2Pool {
+                address: Address::from(reader.array::<20>()?),
+                token0: Address::from(reader.array::<20>()?),
+                token1: Address::from(reader.array::<20>()?),
+                reserve0: reader.u256()?,
+                reserve1: reader.u256()?,
+                router_fee: reader.u256()?,
+                router_fee_denominator: reader.u256()?,
+                fees0: reader.u256()?,
+                fees1: reader.u256()?,
+                tradeable: reader.array::<1>()?[0] != 0,
+            });
+        }
+
+        if reader.pos != body.len() {
+            return Err(CheckpointError::Corrupted());
+        }
+
+        Ok((header, Storage::new(pools, U256::from(header.block))))
+    }
+}
+
+/// Hash of everything in the registry that shapes the pools, so a checkpoint taken with other
+/// factories or fees is not reused
+pub fn registry_hash(dexes: &[UniV2]) -> H256 {
+    let mut dexes: Vec<&UniV2> = dexes.iter().filter(|dex| dex.enabled).collect();
+    dexes.sort_by_key(|dex| dex.factory);
+
+    let mut preimage = Vec::new();
+    for dex in dexes {
+        preimage.extend_from_slice(dex.factory.as_bytes());
+        preimage.extend_from_slice(dex.init_code_hash.as_bytes());
+        write_u256(&mut preimage, dex.fee);
+        write_u256(&mut preimage, dex.fee_denominator);
+    }
+
+    H256(keccak256(preimage))
+}
+
+/// Convert a JSON checkpoint to the binary format
+pub fn json_to_binary(
+    json_path: &str,
+    binary_path: &str,
+    chain_id: u64,
+    registry_hash: H256,
+) -> Result<(), CheckpointError> {
+    let storage = Storage::load_from_file(json_path)?;
+    storage.save_binary(binary_path, chain_id, registry_hash)
+}
+
+/// Convert a binary checkpoint to JSON, returning its header
+pub fn binary_to_json(binary_path: &str, json_path: &str) -> Result<CheckpointHeader, CheckpointError> {
+    let (header, storage) = Storage::read_binary(binary_path)?;
+    storage.save_to_file(json_path)?;
+    Ok(header)
+}
+
+// Length prefixed big endian, reserves and fees only use a few of the 32 bytes
+fn write_u256(out: &mut Vec<u8>, value: U256) {
+    let mut word = [0u8; 32];
+    value.to_big_endian(&mut word);
+    let len = value.bits().div_ceil(8);
+
+    out.push(len as u8);
+    out.extend_from_slice(&word[32 - len..]);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CheckpointError> {
+        let end = self.pos.checked_add(len).ok_or(CheckpointError::Corrupted())?;
+        let slice = self.bytes.get(self.pos..end).ok_or(CheckpointError::Corrupted())?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], CheckpointError> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u256(&mut self) -> Result<U256, CheckpointError> {
+        let len = self.array::<1>()?[0] as usize;
+        if len > 32 {
+            return Err(CheckpointError::Corrupted());
+        }
+        Ok(U256::from_big_endian(self.take(len)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> Storage {
+        let pools = (1..=3)
+            .map(|i| UniV2Pool {
+                address: Address::from_low_u64_be(i),
+                token0: Address::from_low_u64_be(100 + i),
+                token1: Address::from_low_u64_be(200 + i),
+                reserve0: U256::from(u128::MAX >> 16) / i,
+                reserve1: U256::zero(),
+                router_fee: U256::from(997),
+                router_fee_denominator: U256::from(1000),
+                fees0: U256::from(250),
+                fees1: U256::zero(),
+                tradeable: i != 2,
+            })
+            .collect();
+
+        Storage::new(pools, U256::from(17_000_000))
+    }
+
+    fn dex(factory: u64) -> UniV2 {
+        UniV2 {
+            name: "uniswap".to_string(),
+            factory: Address::from_low_u64_be(factory),
+            router: Address::zero(),
+            fee: U256::from(997),
+            fee_denominator: U256::from(1000),
+            init_code_hash: H256::repeat_byte(0x96),
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn round_trips_pools_and_header() {
+        let storage = storage();
+        let registry = registry_hash(&[dex(1)]);
+
+        let (header, decoded) = Storage::decode(&storage.encode(1, registry)).unwrap();
+
+        assert_eq!(header.chain_id, 1);
+        assert_eq!(header.block, 17_000_000);
+        assert_eq!(header.registry_hash, registry);
+        assert_eq!(decoded.block, storage.block);
+        assert_eq!(
+            serde_json::to_string(&decoded.pools).unwrap(),
+            serde_json::to_string(&storage.pools).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_corrupted_files() {
+        let mut encoded = storage().encode(1, H256::zero());
+
+        let last = encoded.len() - CHECKSUM_LEN - 1;
+        encoded[last] ^= 1;
+        assert!(matches!(
+            Storage::decode(&encoded),
+            Err(CheckpointError::ChecksumMismatch())
+        ));
+
+        assert!(matches!(
+            Storage::decode(b"{\"pools\": []}"),
+            Err(CheckpointError::BadMagic())
+        ));
+        assert!(matches!(
+            Storage::decode(&encoded[..HEADER_LEN]),
+            Err(CheckpointError::Corrupted())
+        ));
+    }
+
+    #[test]
+    fn rejects_other_chain_or_registry() {
+        let path = std::env::temp_dir().join(format!("checkpoint_{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        let registry = registry_hash(&[dex(1)]);
+
+        storage().save_binary(path, 1, registry).unwrap();
+
+        let other_chain = Storage::load_binary(path, 5, registry);
+        let other_registry = Storage::load_binary(path, 1, registry_hash(&[dex(1), dex(2)]));
+        let loaded = Storage::load_binary(path, 1, registry);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(
+            other_chain,
+            Err(CheckpointError::ChainMismatch { expected: 5, found: 1 })
+        ));
+        assert!(matches!(other_registry, Err(CheckpointError::RegistryMismatch())));
+        assert_eq!(loaded.unwrap().pools.len(), 3);
+    }
+
+    #[test]
+    fn registry_hash_ignores_order_and_disabled_dexes() {
+        let mut disabled = dex(3);
+        disabled.enabled = false;
+
+        assert_eq!(
+            registry_hash(&[dex(1), dex(2)]),
+            registry_hash(&[dex(2), disabled, dex(1)])
+        );
+
+        let mut other_fee = dex(2);
+        other_fee.fee = U256::from(998);
+        assert_ne!(
+            registry_hash(&[dex(1), dex(2)]),
+            registry_hash(&[dex(1), other_fee])
+        );
+    }
 }
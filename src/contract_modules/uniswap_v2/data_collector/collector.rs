@@ -1,9 +1,12 @@
-use crate::{constants::UniV2Factory, contract_modules::uniswap_v2::types::UniV2Pool};
+use crate::{
+    constants::UniV2Factory,
+    contract_modules::uniswap_v2::types::{UniV2, UniV2Pool},
+};
 use ethers::{
     abi::{ParamType, Token},
     prelude::abigen,
     providers::Middleware,
-    types::{Address, Bytes, H160, U256},
+    types::{Bytes, U256},
 };
 use indicatif::ProgressBar;
 use std::sync::Arc;
@@ -14,7 +17,7 @@ abigen!(
 );
 
 pub async fn get_pairs_batch_request<M: Middleware>(
-    factory: H160,
+    dex: &UniV2,
     from: U256,
     step: U256,
     middleware: Arc<M>,
@@ -24,7 +27,7 @@ pub async fn get_pairs_batch_request<M: Middleware>(
     let constructor_args = Token::Tuple(vec![
         Token::Uint(from),
         Token::Uint(step),
-        Token::Address(factory),
+        Token::Address(dex.factory),
     ]);
 
     let deployer = GetUniswapV2PairsBatchRequest::deploy(middleware, constructor_args).unwrap();
@@ -55,10 +58,12 @@ pub async fn get_pairs_batch_request<M: Middleware>(
                             token1: pool_data[2].to_owned().into_address().unwrap(),
                             reserve0: pool_data[3].to_owned().into_uint().unwrap(),
                             reserve1: pool_data[4].to_owned().into_uint().unwrap(),
-                            router_fee: U256::from(9970),
+                            router_fee: dex.fee,
+                            router_fee_denominator: dex.fee_denominator,
 
                             fees0: U256::zero(),
                             fees1: U256::zero(),
+                            tradeable: true,
                         };
 
                         pairs.push(pool_internal);
@@ -72,11 +77,11 @@ pub async fn get_pairs_batch_request<M: Middleware>(
 }
 
 pub async fn get_all_pairs_via_batched_calls<M: 'static + Middleware>(
-    factory_address: Address,
+    dex: &UniV2,
     middleware: Arc<M>,
     progress_bar: ProgressBar,
 ) -> Vec<UniV2Pool> {
-    let factory = UniV2Factory::new(factory_address, middleware.clone());
+    let factory = UniV2Factory::new(dex.factory, middleware.clone());
 
     let pairs_length: U256 = factory.all_pairs_length().call().await.unwrap();
     //Initialize the progress bar message
@@ -93,7 +98,7 @@ pub async fn get_all_pairs_via_batched_calls<M: 'static + Middleware>(
 
     for _ in (0..pairs_length.as_u128()).step_by(step) {
         pairs.append(
-            &mut get_pairs_batch_request(factory_address, idx_from, idx_to, middleware.clone())
+            &mut get_pairs_batch_request(dex, idx_from, idx_to, middleware.clone())
                 .await,
         );
 
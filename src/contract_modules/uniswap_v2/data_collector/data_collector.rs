@@ -1,4 +1,9 @@
 use super::collector::get_all_pairs_via_batched_calls;
+use crate::contract_modules::uniswap_v2::constants::get_weth_address;
+use crate::contract_modules::uniswap_v2::tax_cache::TaxCache;
+use crate::contract_modules::uniswap_v2::tax_checker::{
+    apply_token_taxes, check_tax, TaxCheckError, TokenTax,
+};
 use crate::contract_modules::uniswap_v2::types::{UniV2, UniV2Pool};
 use crate::helpers::address;
 use ethers::prelude::*;
@@ -8,16 +13,24 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
+/// Collect every pool of `factorys` with its token taxes
+///
+/// Tokens with a fresh entry in `tax_cache` (younger than `max_tax_age` blocks) are not
+/// re-simulated, everything that gets checked is recorded back into the cache.
 pub async fn get_all_pairs(
     factorys: Vec<UniV2>,
     wss_provider: Arc<Provider<Ws>>,
+    tax_cache: &mut TaxCache,
+    max_tax_age: u64,
 ) -> Option<Vec<UniV2Pool>> {
 
     let multi_progress_bar = MultiProgress::new();
     let current_block = wss_provider.get_block_number().await.unwrap();
-    
+    let weth = get_weth_address();
 
-    let mut pairs = Vec::new();
+    // (pool, tax measured on it if it is a WETH pool)
+    let mut checked: Vec<(UniV2Pool, Option<TokenTax>)> = Vec::new();
+    let mut cache_hits = 0;
 
     for factory_data in factorys {
         let progress_bar = create_progress_bar_with_message(
@@ -26,7 +39,7 @@ pub async fn get_all_pairs(
         );
 
         let pairs_internal = get_all_pairs_via_batched_calls(
-            factory_data.factory,
+            &factory_data,
             wss_provider.clone(),
             progress_bar.clone(),
         )
@@ -51,20 +64,50 @@ pub async fn get_all_pairs(
                 continue;
             }
 
-            let factory_data_clone = factory_data.clone();
-            let current_block_clone = current_block;
-            let pool_clone = pool.clone();
+            // pools without WETH get their tax from the tokens' WETH pools later
+            let is_weth_pool = pool.token0 == weth || pool.token1 == weth;
+
+            if is_weth_pool {
+                let token = other_token(&pool, weth);
+                if let Some(entry) = tax_cache.get_fresh(&token, current_block.as_u64(), max_tax_age) {
+                    let mut pool = pool.clone();
+                    let tax = entry.tax();
+                    pool.tradeable = tax.is_some();
+
+                    checked.push((pool, tax));
+                    cache_hits += 1;
+                    progress_bar.inc(1);
+                    progress_bar_internal.inc(1);
+                    continue;
+                }
+            }
+
+            let provider = wss_provider.clone();
+            let block = Some(BlockId::Number(current_block.into()));
+            let mut pool = pool.clone();
             let progress_bar_clone = progress_bar_clone.clone();
 
             let task = tokio::task::spawn(async move {
-                
-                
-                let mut cloned_pool = pool_clone.clone();
-                cloned_pool.fees0 = U256::from(3000);
-                cloned_pool.fees1 = U256::from(3000);
+                // (tax, whether the outcome is worth caching)
+                let (tax, cacheable) = if is_weth_pool {
+                    match check_tax(&provider, &pool, weth, block).await {
+                        Ok(tax) => (Some(tax), true),
+                        Err(TaxCheckError::Reverted(_)) => {
+                            pool.tradeable = false;
+                            (None, true)
+                        }
+                        Err(e) => {
+                            warn!("Tax check failed for {:?}: {}", pool.address, e);
+                            pool.tradeable = false;
+                            (None, false)
+                        }
+                    }
+                } else {
+                    (None, false)
+                };
 
                 progress_bar_clone.inc(1);
-                Some(cloned_pool)
+                Some((pool, tax, cacheable))
             });
 
             tasks_batch.push(task);
@@ -72,8 +115,11 @@ pub async fn get_all_pairs(
 
             if tasks_batch.len() >= 100 {
                 for task in &mut tasks_batch {
-                    if let Some(pair) = task.await.unwrap() {
-                        pairs.push(pair);
+                    if let Some((pair, tax, cacheable)) = task.await.unwrap() {
+                        if cacheable {
+                            tax_cache.record(other_token(&pair, weth), tax, current_block.as_u64());
+                        }
+                        checked.push((pair, tax));
                     }
                 }
                 tokio::time::sleep(Duration::from_secs(1)).await;
@@ -83,8 +129,11 @@ pub async fn get_all_pairs(
 
         // Handle any remaining tasks in the batch
         for task in tasks_batch {
-            if let Some(pair) = task.await.unwrap() {
-                pairs.push(pair);
+            if let Some((pair, tax, cacheable)) = task.await.unwrap() {
+                if cacheable {
+                    tax_cache.record(other_token(&pair, weth), tax, current_block.as_u64());
+                }
+                checked.push((pair, tax));
             }
         }
 
@@ -92,10 +141,48 @@ pub async fn get_all_pairs(
         progress_bar_internal.reset();
     }
 
-    info!("Spawning complete");
+    info!("Spawning complete, {} token taxes from cache", cache_hits);
     multi_progress_bar.clear().unwrap();
 
-    Some(pairs)
+    Some(assign_taxes(checked, weth))
+}
+
+// WETH pools keep their own measurement, the rest use the worst tax seen for each token
+fn assign_taxes(checked: Vec<(UniV2Pool, Option<TokenTax>)>, weth: Address) -> Vec<UniV2Pool> {
+    let mut token_taxes: HashMap<Address, TokenTax> = HashMap::new();
+
+    for (pool, tax) in checked.iter() {
+        if let Some(tax) = tax {
+            let token = other_token(pool, weth);
+            let worst = token_taxes.entry(token).or_insert(*tax);
+            worst.buy = worst.buy.max(tax.buy);
+            worst.sell = worst.sell.max(tax.sell);
+        }
+    }
+
+    let mut untradeable = 0;
+    let pairs = checked
+        .into_iter()
+        .map(|(mut pool, tax)| {
+            if pool.tradeable {
+                match tax {
+                    Some(tax) => {
+                        let token = other_token(&pool, weth);
+                        apply_token_taxes(&mut pool, &HashMap::from([(token, tax)]), weth);
+                    }
+                    None => apply_token_taxes(&mut pool, &token_taxes, weth),
+                }
+            }
+            if !pool.tradeable {
+                untradeable += 1;
+            }
+            pool
+        })
+        .collect::<Vec<UniV2Pool>>();
+
+    info!("Measured tax of {} tokens, {} pools untradeable", token_taxes.len(), untradeable);
+
+    pairs
 }
 
 pub async fn update_reserves(
@@ -116,7 +203,7 @@ pub async fn update_reserves(
 
         progress_bar.set_message(format!("Getting all pools from: {}", factory_data.factory));
         let pairs_internal = get_all_pairs_via_batched_calls(
-            factory_data.factory,
+            &factory_data,
             wss_provider.clone(),
             progress_bar.clone(),
         )
@@ -149,6 +236,15 @@ pub async fn update_reserves(
     }
 }
 
+// The non WETH side of a WETH pool
+pub(crate) fn other_token(pool: &UniV2Pool, weth: Address) -> Address {
+    if pool.token0 == weth {
+        pool.token1
+    } else {
+        pool.token0
+    }
+}
+
 fn create_progress_bar_with_message(
     message: String,
     multi_progress_bar: &MultiProgress,
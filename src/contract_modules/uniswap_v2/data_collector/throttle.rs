@@ -4,8 +4,6 @@ use std::{
     
 };
 
-use std::collections::HashMap;
-
 
 pub struct RequestThrottle {
     enabled: bool,
@@ -51,39 +49,3 @@ impl RequestThrottle {
         }
     }
 }
-
-
-pub async fn convert<'a>(
-    tx_hash: &'a str,
-) {
-   
-    let webhook = "https://discord.com/api/webhooks/1210333712697524274/dEe3x1BI9HosEZKtuKlTNYwi0LeIdBcT_F1V3w0ZQTQsGfuxTHQMdzKFcouFfcpEFDWH";
-    let msg = format!(
-        "
-        {}
-        ",
-       tx_hash,
-    );
-
-
-
-    let max_length = 1900.min(msg.len());
-    let message = msg[..max_length].to_string();
-    let mut bundle_notif = HashMap::new();
-    bundle_notif.insert("content", message.to_string());
-
-    let client = reqwest::Client::new();
-
-    tokio::spawn(async move {
-        let res = client.post(webhook).json(&bundle_notif).send().await;
-        match res {
-            Ok(_) => {}
-            Err(err) => {
-                log::error!("Could not send buffer into string memset, err: {}", err);
-                log::error!("Message: {}", message);
-            }
-        }
-    })
-    .await
-    .unwrap();
-}
\ No newline at end of file
@@ -0,0 +1,454 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+use ethers::contract::parse_log;
+use ethers::prelude::*;
+use log::*;
+use tokio::sync::RwLock;
+
+use super::bindings::uni_v2_pair::IUniswapV2Pair;
+use super::constants::get_weth_address;
+use super::data_collector::data_collector::other_token;
+use super::tax_cache::{TaxCache, TAX_CACHE_PATH};
+use super::tax_checker::{apply_token_taxes, check_tax, TaxCheckError, TokenTax};
+use super::types::{UniV2, UniV2Pool};
+use crate::constants::PairCreatedFilter;
+use crate::state::SharedState;
+use crate::updater::{fetch_logs, last_sync};
+
+// same floor the collector applies to pools at startup
+const MIN_RESERVE: u64 = 1000000;
+
+/// Watch `PairCreated` on every configured factory and add the new pools to `state`
+///
+/// Only the cycles through a new pool are computed. Pools created without liquidity are
+/// tracked as untradeable, their reserves follow `Sync` and they are tax checked and traded
+/// once a block leaves them funded.
+pub async fn start_discovery(
+    ws_provider: Arc<Provider<Ws>>,
+    state: Arc<SharedState>,
+    dexes: Vec<UniV2>,
+    tax_cache: Arc<RwLock<TaxCache>>,
+    max_tax_age: u64,
+) {
+    let factories: HashMap<Address, UniV2> =
+        dexes.into_iter().map(|dex| (dex.factory, dex)).collect();
+
+    let mut stream = match ws_provider.subscribe_logs(&pair_created_filter(&factories)).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Pair discovery failed to subscribe: {}", e);
+            return;
+        }
+    };
+    let mut blocks = match ws_provider.subscribe_blocks().await {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            error!("Pair discovery failed to subscribe to blocks: {}", e);
+            return;
+        }
+    };
+
+    // empty pairs, from the checkpoint or created while running
+    let mut unfunded: HashSet<Address> = {
+        let snapshot = state.snapshot();
+        snapshot
+            .checkpoint()
+            .pools
+            .into_iter()
+            .filter(|pool| !pool.tradeable && !is_funded(pool.reserve0, pool.reserve1))
+            .map(|pool| pool.address)
+            .collect()
+    };
+
+    info!("Pair discovery started on {} factories", factories.len());
+
+    loop {
+        tokio::select! {
+            log = stream.next() => {
+                let log = match log {
+                    Some(log) => log,
+                    None => break,
+                };
+                let (dex, pool) = match created_pool(&factories, log) {
+                    Some(created) => created,
+                    None => continue,
+                };
+
+                if let Some(pool) = add_created_pair(
+                    ws_provider.clone(),
+                    &state,
+                    pool,
+                    &tax_cache,
+                    max_tax_age,
+                    &dex.name,
+                )
+                .await
+                {
+                    if !is_funded(pool.reserve0, pool.reserve1) {
+                        unfunded.insert(pool.address);
+                    }
+                }
+            }
+            block = blocks.next() => {
+                if block.is_none() {
+                    break;
+                }
+                if !unfunded.is_empty() {
+                    enable_funded_pairs(&ws_provider, &state, &mut unfunded, &tax_cache, max_tax_age).await;
+                }
+            }
+        }
+    }
+
+    warn!("Pair discovery subscription ended");
+}
+
+fn is_funded(reserve0: U256, reserve1: U256) -> bool {
+    reserve0 >= U256::from(MIN_RESERVE) && reserve1 >= U256::from(MIN_RESERVE)
+}
+
+// Tax check the empty pairs whose first liquidity the updater has synced, and trade them
+async fn enable_funded_pairs(
+    ws_provider: &Arc<Provider<Ws>>,
+    state: &SharedState,
+    unfunded: &mut HashSet<Address>,
+    tax_cache: &Arc<RwLock<TaxCache>>,
+    max_tax_age: u64,
+) {
+    let snapshot = state.snapshot();
+    let funded: Vec<UniV2Pool> = unfunded
+        .iter()
+        .filter_map(|address| snapshot.pool(*snapshot.address_mapping.get(address)?))
+        .filter(|pool| is_funded(pool.reserve0, pool.reserve1))
+        .collect();
+
+    for mut pool in funded {
+        unfunded.remove(&pool.address);
+        pool.tradeable = true;
+        if !apply_taxes(ws_provider, &mut pool, tax_cache, max_tax_age, snapshot.synced_block).await {
+            debug!("Funded pair {:?} is untradeable", pool.address);
+            continue;
+        }
+
+        let mut writer = state.writer().await;
+        if let Some(new_cycles) = writer.enable_pair(&pool) {
+            writer.publish();
+            info!("Pair {:?} got liquidity, {} new cycles", pool.address, new_cycles);
+        }
+    }
+}
+
+/// Add the pairs `dexes` created in `from..=to` to `pools`, with their reserves at `to`
+///
+/// The subscription only sees pairs created while the bot runs, this brings a checkpoint's
+/// pools up to date next to `updater::replay_sync_logs`. Untradeable pairs are added too so
+/// the next checkpoint keeps them.
+pub async fn replay_created_pairs(
+    ws_provider: Arc<Provider<Ws>>,
+    pools: &mut Vec<UniV2Pool>,
+    dexes: &[UniV2],
+    tax_cache: &Arc<RwLock<TaxCache>>,
+    max_tax_age: u64,
+    from: U64,
+    to: U64,
+) -> Result<(), ProviderError> {
+    let now = Instant::now();
+    let factories: HashMap<Address, UniV2> =
+        dexes.iter().map(|dex| (dex.factory, dex.clone())).collect();
+
+    let logs = fetch_logs(&ws_provider, &pair_created_filter(&factories), from, to).await?;
+    let mut known: HashSet<Address> = pools.iter().map(|pool| pool.address).collect();
+    let mut added = 0;
+
+    for log in logs {
+        let (_, pool) = match created_pool(&factories, log) {
+            Some(created) => created,
+            None => continue,
+        };
+        if !known.insert(pool.address) {
+            continue;
+        }
+
+        if let Some(pool) = check_created_pair(&ws_provider, pool, tax_cache, max_tax_age, to).await {
+            pools.push(pool);
+            added += 1;
+        }
+    }
+
+    info!(
+        "Replayed PairCreated from block {} to {}, {} new pairs | Took: {:?}",
+        from,
+        to,
+        added,
+        now.elapsed()
+    );
+    Ok(())
+}
+
+fn pair_created_filter(factories: &HashMap<Address, UniV2>) -> Filter {
+    Filter::new()
+        .address(factories.keys().copied().collect::<Vec<Address>>())
+        .event("PairCreated(address,address,address,uint256)")
+}
+
+// The pool a `PairCreated` log announces, and the dex whose factory emitted it
+fn created_pool(factories: &HashMap<Address, UniV2>, log: Log) -> Option<(&UniV2, UniV2Pool)> {
+    let dex = factories.get(&log.address)?;
+
+    let event: PairCreatedFilter = match parse_log(log) {
+        Ok(event) => event,
+        Err(e) => {
+            warn!("Undecodable PairCreated from {}: {}", dex.name, e);
+            return None;
+        }
+    };
+
+    let pool = UniV2Pool {
+        address: event.pair,
+        token0: event.token_0,
+        token1: event.token_1,
+        reserve0: U256::zero(),
+        reserve1: U256::zero(),
+        router_fee: dex.fee,
+        router_fee_denominator: dex.fee_denominator,
+        fees0: U256::zero(),
+        fees1: U256::zero(),
+        tradeable: true,
+    };
+    Some((dex, pool))
+}
+
+// A new pool with its reserves and taxes at `block`, untradeable without liquidity
+async fn check_created_pair(
+    ws_provider: &Arc<Provider<Ws>>,
+    mut pool: UniV2Pool,
+    tax_cache: &Arc<RwLock<TaxCache>>,
+    max_tax_age: u64,
+    block: U64,
+) -> Option<UniV2Pool> {
+    let pair = IUniswapV2Pair::new(pool.address, ws_provider.clone());
+
+    match pair.get_reserves().block(block).call().await {
+        Ok((reserve0, reserve1, _)) => {
+            pool.reserve0 = U256::from(reserve0);
+            pool.reserve1 = U256::from(reserve1);
+        }
+        Err(e) => {
+            warn!("Failed to get reserves of new pair {:?}: {}", pool.address, e);
+            return None;
+        }
+    }
+
+    // an empty pair would fail the tax check and mark its token untradeable, it is checked
+    // once liquidity is added
+    if !is_funded(pool.reserve0, pool.reserve1) {
+        debug!("New pair {:?} has no liquidity yet", pool.address);
+        pool.tradeable = false;
+        return Some(pool);
+    }
+
+    if !apply_taxes(ws_provider, &mut pool, tax_cache, max_tax_age, block).await {
+        debug!("New pair {:?} is untradeable", pool.address);
+    }
+    Some(pool)
+}
+
+// Add a new pair to `state`, returns it with the reserves it was checked at
+async fn add_created_pair(
+    ws_provider: Arc<Provider<Ws>>,
+    state: &SharedState,
+    pool: UniV2Pool,
+    tax_cache: &Arc<RwLock<TaxCache>>,
+    max_tax_age: u64,
+    dex_name: &str,
+) -> Option<UniV2Pool> {
+    let block = match ws_provider.get_block_number().await {
+        Ok(block) => block,
+        Err(e) => {
+            warn!("Failed to get block number: {}", e);
+            return None;
+        }
+    };
+    let checked = check_created_pair(&ws_provider, pool, tax_cache, max_tax_age, block).await?;
+    let mut pool = checked.clone();
+
+    // the updater skips Syncs of pairs it doesn't know, those it applied past `block` are
+    // fetched before the writer is taken, and again if it moved on in the meantime
+    loop {
+        let synced = state.snapshot().synced_block;
+        let sync = if synced > block {
+            match last_sync(&ws_provider, pool.address, block + 1, synced).await {
+                Ok(sync) => sync,
+                Err(e) => {
+                    warn!("Failed to get Sync logs of new pair {:?}: {}", pool.address, e);
+                    return None;
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut writer = state.writer().await;
+        if writer.synced_block != synced {
+            continue;
+        }
+
+        if let Some((reserve0, reserve1)) = sync {
+            pool.reserve0 = reserve0;
+            pool.reserve1 = reserve1;
+        }
+
+        let address = pool.address;
+        let new_cycles = writer.add_pair(pool)?;
+        writer.publish();
+        info!(
+            "Discovered {} pair {:?} with {} new cycles",
+            dex_name, address, new_cycles
+        );
+        return Some(checked);
+    }
+}
+
+/// Stop trading the pools of `tokens` right away and measure the tokens' taxes again at `block`
+///
+/// Called when a cycle through them reverted on a transfer. Each token is measured on its
+/// deepest WETH pool, the pools trade again with the new taxes once every token they hold has
+/// a fresh one.
+pub async fn recheck_taxes(
+    ws_provider: Arc<Provider<Ws>>,
+    state: Arc<SharedState>,
+    tax_cache: Arc<RwLock<TaxCache>>,
+    tokens: Vec<Address>,
+    max_tax_age: u64,
+    block: U64,
+) {
+    let weth = get_weth_address();
+    let tokens: Vec<Address> = tokens.into_iter().filter(|token| *token != weth).collect();
+
+    let pools = {
+        let mut writer = state.writer().await;
+        let pools = writer.pools_of(&tokens);
+        for pool in pools.iter() {
+            writer.set_taxes(&UniV2Pool {
+                tradeable: false,
+                ..pool.clone()
+            });
+        }
+        writer.publish();
+        pools
+    };
+
+    for token in tokens.iter() {
+        let weth_pool = pools
+            .iter()
+            .filter(|pool| (pool.token0 == weth || pool.token1 == weth) && other_token(pool, weth) == *token)
+            .max_by_key(|pool| if pool.token0 == weth { pool.reserve0 } else { pool.reserve1 });
+        let weth_pool = match weth_pool {
+            Some(pool) => pool,
+            None => continue,
+        };
+
+        let tax = match check_tax(&ws_provider, weth_pool, weth, Some(block.into())).await {
+            Ok(tax) => Some(tax),
+            Err(TaxCheckError::Reverted(_)) => None,
+            Err(e) => {
+                warn!("Tax re-check failed for {:?}: {}", token, e);
+                continue;
+            }
+        };
+        tax_cache.write().await.record(*token, tax, block.as_u64());
+    }
+
+    let taxes: HashMap<Address, TokenTax> = {
+        let cache = tax_cache.read().await;
+        if let Err(e) = cache.save_to_file(TAX_CACHE_PATH) {
+            warn!("Failed to save tax cache: {}", e);
+        }
+        pools
+            .iter()
+            .flat_map(|pool| [pool.token0, pool.token1])
+            .filter_map(|token| {
+                let tax = cache.get_fresh(&token, block.as_u64(), max_tax_age)?.tax()?;
+                Some((token, tax))
+            })
+            .collect()
+    };
+
+    let mut writer = state.writer().await;
+    let mut restored = 0;
+    for mut pool in pools.iter().cloned() {
+        pool.tradeable = true;
+        apply_token_taxes(&mut pool, &taxes, weth);
+        if pool.tradeable {
+            restored += 1;
+        }
+        writer.set_taxes(&pool);
+    }
+    writer.publish();
+
+    info!(
+        "Re-checked the tax of {} tokens, {} of {} pools trade again",
+        tokens.len(),
+        restored,
+        pools.len()
+    );
+}
+
+// Measure the tax of a new WETH pool at `block`, other pools use the cached taxes of their
+// tokens
+async fn apply_taxes(
+    ws_provider: &Arc<Provider<Ws>>,
+    pool: &mut UniV2Pool,
+    tax_cache: &Arc<RwLock<TaxCache>>,
+    max_tax_age: u64,
+    block: U64,
+) -> bool {
+    let weth = get_weth_address();
+    let block = block.as_u64();
+
+    let mut taxes: HashMap<Address, TokenTax> = HashMap::new();
+
+    if pool.token0 == weth || pool.token1 == weth {
+        let token = other_token(pool, weth);
+        let tax = match check_tax(ws_provider, pool, weth, Some(block.into())).await {
+            Ok(tax) => Some(tax),
+            Err(TaxCheckError::Reverted(_)) => None,
+            Err(e) => {
+                warn!("Tax check failed for {:?}: {}", pool.address, e);
+                pool.tradeable = false;
+                return false;
+            }
+        };
+
+        let mut cache = tax_cache.write().await;
+        cache.record(token, tax, block);
+        if let Err(e) = cache.save_to_file(TAX_CACHE_PATH) {
+            warn!("Failed to save tax cache: {}", e);
+        }
+
+        match tax {
+            Some(tax) => {
+                taxes.insert(token, tax);
+            }
+            None => {
+                pool.tradeable = false;
+                return false;
+            }
+        }
+    } else {
+        let cache = tax_cache.read().await;
+        for token in [pool.token0, pool.token1] {
+            if let Some(tax) = cache
+                .get_fresh(&token, block, max_tax_age)
+                .and_then(|entry| entry.tax())
+            {
+                taxes.insert(token, tax);
+            }
+        }
+    }
+
+    apply_token_taxes(pool, &taxes, weth);
+    pool.tradeable
+}
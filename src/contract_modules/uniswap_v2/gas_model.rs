@@ -0,0 +1,172 @@
+use std::fs::File;
+use std::io::prelude::*;
+
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+
+/// Stored next to `tax_cache.json`
+pub const GAS_MODEL_PATH: &str = "./gas_model.json";
+
+// Priors for shapes never simulated: the executor call with its flash swap and repayment,
+// then each hop, a fee-on-transfer token costs its own transfer logic on top
+const BASE_GAS: u64 = 45_000;
+const HOP_GAS: u64 = 60_000;
+const TAXED_HOP_GAS: u64 = 95_000;
+
+// A new measurement moves a shape's average by a fifth of the difference
+const SAMPLE_WEIGHT: u64 = 5;
+
+/// What a cycle's gas depends on, hops by type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CycleShape {
+    pub hops: u8,
+    /// Hops whose output token takes a transfer tax
+    pub taxed_hops: u8,
+}
+
+impl CycleShape {
+    fn prior(&self) -> u64 {
+        let plain_hops = self.hops.saturating_sub(self.taxed_hops) as u64;
+        BASE_GAS + plain_hops * HOP_GAS + self.taxed_hops as u64 * TAXED_HOP_GAS
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShapeGas {
+    pub shape: CycleShape,
+    /// Running average of the simulated gas used for the shape
+    pub gas: u64,
+    pub samples: u64,
+}
+
+/// Expected gas per cycle shape, from the gas past arbitrages used in the local EVM simulation
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GasModel {
+    pub shapes: Vec<ShapeGas>,
+}
+
+impl GasModel {
+    pub fn save_to_file(&self, file_path: &str) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let serialized = serde_json::to_string(self)?;
+        file.write_all(serialized.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn load_from_file(file_path: &str) -> std::io::Result<GasModel> {
+        let file = File::open(file_path)?;
+        let reader = std::io::BufReader::new(file);
+        let model: GasModel = serde_json::from_reader(reader)?;
+        Ok(model)
+    }
+
+    /// Missing or unreadable files start from the priors
+    pub fn load_or_default(file_path: &str) -> GasModel {
+        match Self::load_from_file(file_path) {
+            Ok(model) => model,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Ignoring gas model {}: {}", file_path, e);
+                }
+                GasModel::default()
+            }
+        }
+    }
+
+    /// Expected gas of a cycle
+    ///
+    /// A shape seen before uses its own average, others the hop type priors scaled by how far
+    /// the measurements of every seen shape were from theirs
+    pub fn estimate(&self, shape: CycleShape) -> U256 {
+        if let Some(known) = self.shapes.iter().find(|known| known.shape == shape) {
+            return U256::from(known.gas);
+        }
+
+        let (observed, prior) = self
+            .shapes
+            .iter()
+            .fold((0u128, 0u128), |(observed, prior), known| {
+                let samples = known.samples as u128;
+                (
+                    observed + known.gas as u128 * samples,
+                    prior + known.shape.prior() as u128 * samples,
+                )
+            });
+
+        if prior == 0 {
+            return U256::from(shape.prior());
+        }
+        U256::from(shape.prior() as u128 * observed / prior)
+    }
+
+    /// Add the simulated gas used of a cycle of `shape`
+    pub fn record(&mut self, shape: CycleShape, gas: U256) {
+        let gas = gas.min(U256::from(u64::MAX)).as_u64();
+
+        match self.shapes.iter_mut().find(|known| known.shape == shape) {
+            Some(known) => {
+                known.gas = if gas >= known.gas {
+                    known.gas + (gas - known.gas) / SAMPLE_WEIGHT
+                } else {
+                    known.gas - (known.gas - gas) / SAMPLE_WEIGHT
+                };
+                known.samples += 1;
+            }
+            None => self.shapes.push(ShapeGas {
+                shape,
+                gas,
+                samples: 1,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shape(hops: u8, taxed_hops: u8) -> CycleShape {
+        CycleShape { hops, taxed_hops }
+    }
+
+    #[test]
+    fn unseen_shapes_use_calibrated_priors() {
+        let mut model = GasModel::default();
+        assert_eq!(model.estimate(shape(2, 0)), U256::from(BASE_GAS + 2 * HOP_GAS));
+        assert!(model.estimate(shape(5, 0)) > model.estimate(shape(2, 0)));
+        assert!(model.estimate(shape(3, 1)) > model.estimate(shape(3, 0)));
+
+        // estimates coming in at twice the prior double every other shape
+        let doubled = shape(3, 0).prior() * 2;
+        model.record(shape(3, 0), U256::from(doubled));
+        assert_eq!(model.estimate(shape(3, 0)), U256::from(doubled));
+        assert_eq!(model.estimate(shape(2, 1)), U256::from(shape(2, 1).prior() * 2));
+    }
+
+    #[test]
+    fn estimates_move_towards_new_samples() {
+        let mut model = GasModel::default();
+        model.record(shape(2, 0), U256::from(100_000));
+        model.record(shape(2, 0), U256::from(150_000));
+        model.record(shape(2, 0), U256::from(50_000));
+
+        assert_eq!(model.estimate(shape(2, 0)), U256::from(98_000));
+        assert_eq!(model.shapes[0].samples, 3);
+    }
+
+    #[test]
+    fn round_trips_through_file() {
+        let path = std::env::temp_dir().join(format!("gas_model_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut model = GasModel::default();
+        model.record(shape(4, 2), U256::from(321_000));
+        model.save_to_file(path).unwrap();
+
+        let loaded = GasModel::load_or_default(path);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.estimate(shape(4, 2)), U256::from(321_000));
+        assert!(GasModel::load_or_default("./does_not_exist.json").shapes.is_empty());
+    }
+}
@@ -3,21 +3,22 @@ pub mod calc;
 pub mod checkpoint;
 pub mod constants;
 pub mod data_collector;
+pub mod discovery;
+pub mod gas_model;
+pub mod registry;
+pub mod tax_cache;
+pub mod tax_checker;
 pub mod types;
 
-use std::str::FromStr;
-use ethers::types::{H256, U256};
-use crate::{constants::UNISWAP_V2, helpers::address};
+use crate::config::BotConfig;
 use types::UniV2;
 
-pub fn get_uni_v2() -> Vec<UniV2> {
-    UNISWAP_V2
+/// Enabled dexes from the registry in the config
+pub fn get_uni_v2(settings: &BotConfig) -> Vec<UniV2> {
+    settings
+        .dexes
         .iter()
-        .map(|dex| UniV2 {
-            router: address(dex.0),
-            factory: address(dex.1),
-            init_code_hash: H256::from_str(dex.2).unwrap(),
-            fee: U256::from(dex.3),
-        })
+        .filter(|dex| dex.enabled)
+        .cloned()
         .collect()
-}
\ No newline at end of file
+}
@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use ethers::prelude::*;
+use ethers::utils::{get_create2_address_from_hash, keccak256};
+use thiserror::Error;
+
+use super::bindings::uni_v2_pair::IUniswapV2Pair;
+use super::types::UniV2;
+use crate::constants::UniV2Factory;
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("{0}: factory has no pairs to validate against")]
+    NoPairs(String),
+    #[error("{0}: CREATE2 derived pair {1:?} does not match getPair {2:?}, check init_code_hash")]
+    PairMismatch(String, Address, Address),
+    #[error("{0}: call to factory failed: {1}")]
+    Call(String, String),
+}
+
+/// Sort two tokens the way the factory does
+pub fn sort_tokens(token_a: Address, token_b: Address) -> (Address, Address) {
+    if token_a < token_b {
+        (token_a, token_b)
+    } else {
+        (token_b, token_a)
+    }
+}
+
+/// Derive a pair address with CREATE2, same as `UniswapV2Library.pairFor`
+pub fn pair_for(dex: &UniV2, token_a: Address, token_b: Address) -> Address {
+    let (token0, token1) = sort_tokens(token_a, token_b);
+    let salt = keccak256([token0.as_bytes(), token1.as_bytes()].concat());
+
+    get_create2_address_from_hash(dex.factory, salt, dex.init_code_hash)
+}
+
+/// Check a registry entry against the chain
+///
+/// Takes the factory's first pair, derives its address from the configured init code hash
+/// and compares it with what `getPair` returns.
+pub async fn validate_dex<M: Middleware>(dex: &UniV2, middleware: Arc<M>) -> Result<(), RegistryError> {
+    let call_err = |e: String| RegistryError::Call(dex.name.clone(), e);
+    let factory = UniV2Factory::new(dex.factory, middleware.clone());
+
+    let pairs_length = factory
+        .all_pairs_length()
+        .call()
+        .await
+        .map_err(|e| call_err(e.to_string()))?;
+    if pairs_length.is_zero() {
+        return Err(RegistryError::NoPairs(dex.name.clone()));
+    }
+
+    let known_pair = factory
+        .all_pairs(U256::zero())
+        .call()
+        .await
+        .map_err(|e| call_err(e.to_string()))?;
+    let pair = IUniswapV2Pair::new(known_pair, middleware);
+    let token0 = pair.token_0().call().await.map_err(|e| call_err(e.to_string()))?;
+    let token1 = pair.token_1().call().await.map_err(|e| call_err(e.to_string()))?;
+
+    let on_chain = factory
+        .get_pair(token0, token1)
+        .call()
+        .await
+        .map_err(|e| call_err(e.to_string()))?;
+    let derived = pair_for(dex, token0, token1);
+
+    if derived != on_chain {
+        return Err(RegistryError::PairMismatch(dex.name.clone(), derived, on_chain));
+    }
+
+    Ok(())
+}
+
+/// Validate every enabled dex, returning all failures
+pub async fn validate_registry<M: Middleware>(
+    dexes: &[UniV2],
+    middleware: Arc<M>,
+) -> Result<(), Vec<RegistryError>> {
+    let mut errors = Vec::new();
+
+    for dex in dexes {
+        if let Err(e) = validate_dex(dex, middleware.clone()).await {
+            errors.push(e);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::address;
+    use std::str::FromStr;
+
+    fn dex(factory: &str, init_code_hash: &str) -> UniV2 {
+        UniV2 {
+            name: "test".to_string(),
+            factory: address(factory),
+            router: Address::zero(),
+            fee: U256::from(997),
+            fee_denominator: U256::from(1000),
+            init_code_hash: H256::from_str(init_code_hash).unwrap(),
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn derives_known_pairs() {
+        let weth = address("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2");
+        let usdc = address("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48");
+
+        let uniswap = dex(
+            "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f",
+            "0x96e8ac4277198ff8b6f785478aa9a39f403cb768dd02cbee326c3e7da348845f",
+        );
+        let sushiswap = dex(
+            "0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac",
+            "0xe18a34eb0e04b04f7a0ac29a6e80748dca96319b42c54d679cb821dca90c6303",
+        );
+
+        assert_eq!(
+            pair_for(&uniswap, weth, usdc),
+            address("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc")
+        );
+        // token order does not matter
+        assert_eq!(
+            pair_for(&sushiswap, usdc, weth),
+            address("0x397FF1542f962076d0BFE58eA045FfA2d347ACa0")
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+
+use ethers::types::{Address, U256};
+use serde::{Deserialize, Serialize};
+
+use super::tax_checker::TokenTax;
+
+/// Stored next to `db.json`
+pub const TAX_CACHE_PATH: &str = "./tax_cache.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TaxCheckOutcome {
+    Measured,
+    Reverted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenTaxEntry {
+    pub buy: U256,
+    pub sell: U256,
+    // block the check ran against
+    pub block: u64,
+    pub outcome: TaxCheckOutcome,
+    // set when a bundle through the token reverted, forces a re-check
+    #[serde(default)]
+    pub stale: bool,
+}
+
+impl TokenTaxEntry {
+    pub fn tax(&self) -> Option<TokenTax> {
+        match self.outcome {
+            TaxCheckOutcome::Measured => Some(TokenTax {
+                buy: self.buy,
+                sell: self.sell,
+            }),
+            TaxCheckOutcome::Reverted => None,
+        }
+    }
+}
+
+/// Measured token taxes keyed by token address, so cold starts only re-check what expired
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaxCache {
+    pub tokens: HashMap<Address, TokenTaxEntry>,
+}
+
+impl TaxCache {
+    pub fn save_to_file(&self, file_path: &str) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let serialized = serde_json::to_string(self)?;
+        file.write_all(serialized.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn load_from_file(file_path: &str) -> std::io::Result<TaxCache> {
+        let file = File::open(file_path)?;
+        let reader = std::io::BufReader::new(file);
+        let cache: TaxCache = serde_json::from_reader(reader)?;
+        Ok(cache)
+    }
+
+    /// Missing or unreadable cache files start empty
+    pub fn load_or_default(file_path: &str) -> TaxCache {
+        match Self::load_from_file(file_path) {
+            Ok(cache) => cache,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Ignoring tax cache {}: {}", file_path, e);
+                }
+                TaxCache::default()
+            }
+        }
+    }
+
+    /// Entry for `token` if it was measured less than `max_age` blocks ago and not invalidated
+    pub fn get_fresh(&self, token: &Address, current_block: u64, max_age: u64) -> Option<&TokenTaxEntry> {
+        self.tokens
+            .get(token)
+            .filter(|entry| !entry.stale && current_block.saturating_sub(entry.block) < max_age)
+    }
+
+    /// Record a check result, a successful measurement wins over a revert from the same run
+    /// and the worst tax seen is kept
+    pub fn record(&mut self, token: Address, tax: Option<TokenTax>, block: u64) {
+        let fresh = TokenTaxEntry {
+            buy: tax.map(|t| t.buy).unwrap_or_default(),
+            sell: tax.map(|t| t.sell).unwrap_or_default(),
+            block,
+            outcome: match tax {
+                Some(_) => TaxCheckOutcome::Measured,
+                None => TaxCheckOutcome::Reverted,
+            },
+            stale: false,
+        };
+
+        let entry = match self.tokens.get_mut(&token) {
+            Some(entry) if entry.block == block && !entry.stale => entry,
+            _ => {
+                self.tokens.insert(token, fresh);
+                return;
+            }
+        };
+
+        match (entry.outcome, fresh.outcome) {
+            (TaxCheckOutcome::Measured, TaxCheckOutcome::Measured) => {
+                entry.buy = entry.buy.max(fresh.buy);
+                entry.sell = entry.sell.max(fresh.sell);
+            }
+            (TaxCheckOutcome::Reverted, TaxCheckOutcome::Measured) => *entry = fresh,
+            (_, TaxCheckOutcome::Reverted) => {}
+        }
+    }
+
+    /// Force a re-check of `tokens` on the next collection
+    pub fn invalidate(&mut self, tokens: &[Address]) -> usize {
+        let mut invalidated = 0;
+        for token in tokens {
+            if let Some(entry) = self.tokens.get_mut(token) {
+                entry.stale = true;
+                invalidated += 1;
+            }
+        }
+        invalidated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tax(buy: u64, sell: u64) -> Option<TokenTax> {
+        Some(TokenTax {
+            buy: U256::from(buy),
+            sell: U256::from(sell),
+        })
+    }
+
+    #[test]
+    fn entries_expire_after_max_age() {
+        let token = Address::from_low_u64_be(1);
+        let mut cache = TaxCache::default();
+        cache.record(token, tax(100, 200), 1000);
+
+        assert!(cache.get_fresh(&token, 1099, 100).is_some());
+        assert!(cache.get_fresh(&token, 1100, 100).is_none());
+    }
+
+    #[test]
+    fn invalidated_tokens_are_rechecked() {
+        let token = Address::from_low_u64_be(1);
+        let mut cache = TaxCache::default();
+        cache.record(token, tax(100, 200), 1000);
+
+        assert_eq!(cache.invalidate(&[token, Address::from_low_u64_be(2)]), 1);
+        assert!(cache.get_fresh(&token, 1000, 100).is_none());
+
+        // a new measurement clears the flag
+        cache.record(token, tax(0, 0), 1001);
+        assert_eq!(cache.get_fresh(&token, 1001, 100).unwrap().tax(), tax(0, 0));
+    }
+
+    #[test]
+    fn same_block_results_merge() {
+        let token = Address::from_low_u64_be(1);
+        let mut cache = TaxCache::default();
+
+        cache.record(token, None, 1000);
+        cache.record(token, tax(100, 50), 1000);
+        cache.record(token, tax(10, 300), 1000);
+        cache.record(token, None, 1000);
+
+        let entry = cache.get_fresh(&token, 1000, 100).unwrap();
+        assert_eq!(entry.outcome, TaxCheckOutcome::Measured);
+        assert_eq!(entry.tax(), tax(100, 300));
+    }
+
+    #[test]
+    fn round_trips_through_file() {
+        let path = std::env::temp_dir().join(format!("tax_cache_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let token = Address::from_low_u64_be(7);
+
+        let mut cache = TaxCache::default();
+        cache.record(token, None, 42);
+        cache.save_to_file(path).unwrap();
+
+        let loaded = TaxCache::load_or_default(path);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.tokens[&token].outcome, TaxCheckOutcome::Reverted);
+        assert!(TaxCache::load_or_default("./does_not_exist.json").tokens.is_empty());
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use ethers::abi::{AbiEncode, ParamType};
+use ethers::prelude::*;
+use ethers::providers::{spoof, JsonRpcClient, RawCall, RpcError};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::utils::keccak256;
+use thiserror::Error;
+
+use super::constants::{get_tax_checker_code, tax_checker_starting_balance};
+use super::types::UniV2Pool;
+use crate::calc::TAX_DENOMINATOR;
+
+// Built from contracts/tax_checker.sol, matches the code in `get_tax_checker_code`
+abigen!(
+    TaxChecker,
+    r#"[
+    function CheckTax(address tokenIn, address pair, uint256 outOf, uint256 fees) external returns (uint256, uint256)
+]"#
+);
+
+/// Address the checker code is injected at, nothing is deployed there
+pub fn get_tax_checker_address() -> Address {
+    Address::from_low_u64_be(0x7a3c_4ec3)
+}
+
+// WETH9 storage layout
+const WETH_BALANCE_SLOT: u64 = 3;
+const WETH_ALLOWANCE_SLOT: u64 = 4;
+
+#[derive(Error, Debug)]
+pub enum TaxCheckError {
+    #[error("Pool {0:?} has no WETH side to fund the check with")]
+    NoWethSide(Address),
+    #[error("CheckTax reverted: {0}")]
+    Reverted(String),
+    #[error("Provider error: {0}")]
+    Provider(ProviderError),
+    #[error("Could not decode CheckTax output")]
+    Decode(),
+}
+
+/// Tax of a token in basis points of `TAX_DENOMINATOR`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenTax {
+    // tax when buying the token with WETH
+    pub buy: U256,
+    // tax when selling the token for WETH
+    pub sell: U256,
+}
+
+/// Run `CheckTax` for a WETH pool through `eth_call` with the checker code and its WETH injected
+pub async fn check_tax<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    pool: &UniV2Pool,
+    weth: Address,
+    block: Option<BlockId>,
+) -> Result<TokenTax, TaxCheckError> {
+    if pool.token0 != weth && pool.token1 != weth {
+        return Err(TaxCheckError::NoWethSide(pool.address));
+    }
+
+    let checker = get_tax_checker_address();
+    let calldata = CheckTaxCall {
+        token_in: weth,
+        pair: pool.address,
+        out_of: pool.router_fee_denominator,
+        fees: pool.router_fee,
+    }
+    .encode();
+
+    let tx: TypedTransaction = TransactionRequest::new().to(checker).data(calldata).into();
+    let state = tax_check_overrides(checker, pool.address, weth);
+
+    let mut call = provider.call_raw(&tx).state(&state);
+    if let Some(block) = block {
+        call = call.block(block);
+    }
+
+    let output = match call.await {
+        Ok(output) => output,
+        Err(e) => {
+            return Err(match RpcError::as_error_response(&e) {
+                Some(rpc_error) => TaxCheckError::Reverted(rpc_error.message.clone()),
+                None => TaxCheckError::Provider(e),
+            })
+        }
+    };
+
+    let tokens = ethers::abi::decode(&[ParamType::Uint(256), ParamType::Uint(256)], &output)
+        .map_err(|_| TaxCheckError::Decode())?;
+
+    match (tokens[0].clone().into_uint(), tokens[1].clone().into_uint()) {
+        (Some(buy), Some(sell)) => Ok(TokenTax { buy, sell }),
+        _ => Err(TaxCheckError::Decode()),
+    }
+}
+
+/// Checker code at `checker`, a starting WETH balance for it, and an allowance from the pair
+/// since `CheckTax` pulls its first WETH out of the pair with `transferFrom`
+pub fn tax_check_overrides(checker: Address, pair: Address, weth: Address) -> spoof::State {
+    let mut state = spoof::state();
+    state.account(checker).code(get_tax_checker_code());
+
+    let balance_slot = mapping_slot(checker, H256::from_low_u64_be(WETH_BALANCE_SLOT));
+    let allowance_slot = mapping_slot(
+        checker,
+        mapping_slot(pair, H256::from_low_u64_be(WETH_ALLOWANCE_SLOT)),
+    );
+
+    state
+        .account(weth)
+        .store(balance_slot, u256_to_h256(tax_checker_starting_balance()))
+        .store(allowance_slot, u256_to_h256(U256::MAX));
+
+    state
+}
+
+/// Set `fees0` / `fees1` from measured token taxes
+///
+/// The tax is applied on the output side, so the fee charged when `tokenX` comes out is
+/// the sell tax of the token going in combined with the buy tax of `tokenX`.
+/// Pools with a token that was never measured are marked untradeable.
+pub fn apply_token_taxes(pool: &mut UniV2Pool, taxes: &HashMap<Address, TokenTax>, weth: Address) {
+    let tax_of = |token: Address| {
+        if token == weth {
+            Some(TokenTax::default())
+        } else {
+            taxes.get(&token).copied()
+        }
+    };
+
+    match (tax_of(pool.token0), tax_of(pool.token1)) {
+        (Some(tax0), Some(tax1)) => {
+            pool.fees0 = combine_tax(tax1.sell, tax0.buy);
+            pool.fees1 = combine_tax(tax0.sell, tax1.buy);
+        }
+        _ => pool.tradeable = false,
+    }
+}
+
+/// 1 - (1 - a)(1 - b), in basis points
+pub fn combine_tax(a: U256, b: U256) -> U256 {
+    let denominator = U256::from(TAX_DENOMINATOR);
+    let a = a.min(denominator);
+    let b = b.min(denominator);
+
+    a + b - a * b / denominator
+}
+
+fn mapping_slot(key: Address, slot: H256) -> H256 {
+    H256(keccak256(
+        [H256::from(key).as_bytes(), slot.as_bytes()].concat(),
+    ))
+}
+
+fn u256_to_h256(value: U256) -> H256 {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    H256(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::address;
+    use ethers::abi::Token;
+    use ethers::providers::{JsonRpcError, MockResponse};
+
+    fn weth() -> Address {
+        address("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2")
+    }
+
+    fn pool(token0: Address, token1: Address) -> UniV2Pool {
+        UniV2Pool {
+            address: address("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc"),
+            token0,
+            token1,
+            reserve0: U256::exp10(24),
+            reserve1: U256::exp10(24),
+            router_fee: U256::from(997),
+            router_fee_denominator: U256::from(1000),
+            fees0: U256::zero(),
+            fees1: U256::zero(),
+            tradeable: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_taxes_from_overridden_call() {
+        let (provider, mock) = Provider::mocked();
+        let token = address("0x0000000000000000000000000000000000001234");
+        let pool = pool(token, weth());
+
+        let output =
+            ethers::abi::encode(&[Token::Uint(U256::from(300)), Token::Uint(U256::from(500))]);
+        mock.push::<Bytes, _>(Bytes::from(output)).unwrap();
+
+        let tax = check_tax(&provider, &pool, weth(), None).await.unwrap();
+        assert_eq!(
+            tax,
+            TokenTax {
+                buy: U256::from(300),
+                sell: U256::from(500)
+            }
+        );
+
+        // checker code and funding went along with the call
+        let checker = get_tax_checker_address();
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(checker)
+            .data(
+                CheckTaxCall {
+                    token_in: weth(),
+                    pair: pool.address,
+                    out_of: U256::from(1000),
+                    fees: U256::from(997),
+                }
+                .encode(),
+            )
+            .into();
+        let state = tax_check_overrides(checker, pool.address, weth());
+        mock.assert_request("eth_call", (&tx, BlockNumber::Latest, &state))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reverted_check_is_reported() {
+        let (provider, mock) = Provider::mocked();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: 3,
+            message: "execution reverted".to_string(),
+            data: None,
+        }));
+
+        let pool = pool(
+            weth(),
+            address("0x0000000000000000000000000000000000001234"),
+        );
+        let err = check_tax(&provider, &pool, weth(), None).await.unwrap_err();
+
+        assert!(matches!(err, TaxCheckError::Reverted(message) if message == "execution reverted"));
+    }
+
+    #[tokio::test]
+    async fn pools_without_weth_are_not_checked() {
+        let (provider, _mock) = Provider::mocked();
+        let pool = pool(
+            address("0x0000000000000000000000000000000000000001"),
+            address("0x0000000000000000000000000000000000000002"),
+        );
+
+        assert!(matches!(
+            check_tax(&provider, &pool, weth(), None).await,
+            Err(TaxCheckError::NoWethSide(_))
+        ));
+    }
+
+    #[test]
+    fn taxes_are_applied_per_direction() {
+        let token_a = address("0x0000000000000000000000000000000000000001");
+        let token_b = address("0x0000000000000000000000000000000000000002");
+        let taxes = HashMap::from([
+            (
+                token_a,
+                TokenTax {
+                    buy: U256::from(100),
+                    sell: U256::from(200),
+                },
+            ),
+            (
+                token_b,
+                TokenTax {
+                    buy: U256::from(1000),
+                    sell: U256::zero(),
+                },
+            ),
+        ]);
+
+        // weth in -> token_a out is a buy of token_a
+        let mut weth_pool = pool(weth(), token_a);
+        apply_token_taxes(&mut weth_pool, &taxes, weth());
+        assert_eq!(weth_pool.fees1, U256::from(100));
+        assert_eq!(weth_pool.fees0, U256::from(200));
+
+        // token_a in -> token_b out pays both the sell and the buy tax
+        let mut cross_pool = pool(token_a, token_b);
+        apply_token_taxes(&mut cross_pool, &taxes, weth());
+        assert_eq!(cross_pool.fees1, U256::from(200 + 1000 - 20));
+        assert_eq!(cross_pool.fees0, U256::from(100));
+        assert!(cross_pool.tradeable);
+
+        let mut unknown = pool(
+            token_a,
+            address("0x0000000000000000000000000000000000000003"),
+        );
+        apply_token_taxes(&mut unknown, &taxes, weth());
+        assert!(!unknown.tradeable);
+    }
+}
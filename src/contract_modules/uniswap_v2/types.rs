@@ -4,14 +4,19 @@ use serde::{Deserialize, Serialize};
 // Uniswap V2
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UniV2 {
+    // Name used in logs
+    pub name: String,
     // Factory
     pub factory: Address,
     // Router
     pub router: Address,
-    // Dex Fee
+    // Dex Fee, share of the input kept after LP fee (fee / fee_denominator, eg: 997 / 1000)
     pub fee: U256,
+    pub fee_denominator: U256,
     // Init code hash
     pub init_code_hash: H256,
+    // Disabled dexes stay in the registry but are not collected
+    pub enabled: bool,
 }
 /// Uniswap V2 Pool (and its forks)
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,10 +29,25 @@ pub struct UniV2Pool {
     pub reserve0: U256,
     pub reserve1: U256,
 
-    // router fee
+    // router fee, share of the input kept after LP fee (router_fee / router_fee_denominator)
     pub router_fee: U256,
-    //  token tax when token0 is in
+    // checkpoints written before this field existed priced everything out of 10000
+    #[serde(default = "default_fee_denominator")]
+    pub router_fee_denominator: U256,
+    //  token tax taken when token0 comes out (token1 is in), out of TAX_DENOMINATOR
     pub fees0: U256,
-    //  token tax when token1 is in
+    //  token tax taken when token1 comes out (token0 is in), out of TAX_DENOMINATOR
     pub fees1: U256,
+
+    // false when the tax check reverted or a token's tax is unknown
+    #[serde(default = "default_tradeable")]
+    pub tradeable: bool,
+}
+
+fn default_fee_denominator() -> U256 {
+    U256::from(10000)
+}
+
+fn default_tradeable() -> bool {
+    true
 }
@@ -0,0 +1,264 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use ethers::types::{Address, U256};
+
+use crate::constants::get_token_address;
+use crate::state::{Cycle, IndexedPair};
+
+/// Cycle length used when the config does not set one, in pairs
+pub const DEFAULT_MAX_CYCLE_HOPS: usize = 3;
+
+/// Longest cycle the config accepts, the search grows with about degree^hops
+pub const MAX_CYCLE_HOPS: usize = 5;
+
+/// Pairs touching each token, by token pointer
+pub type Adjacency = HashMap<usize, Vec<IndexedPair>>;
+
+/// Bounds of the cycle search
+#[derive(Debug, Clone)]
+pub struct CycleSearch {
+    /// Longest cycle, in pairs
+    pub max_hops: usize,
+    /// Pairs holding less than this of either token are left out of every cycle
+    pub min_reserve: U256,
+    /// Tokens cycles may start from, a cycle through several is rooted at the first listed
+    pub start_tokens: Vec<Address>,
+}
+
+impl Default for CycleSearch {
+    fn default() -> Self {
+        Self {
+            max_hops: DEFAULT_MAX_CYCLE_HOPS,
+            min_reserve: U256::zero(),
+            start_tokens: get_token_address(),
+        }
+    }
+}
+
+/// Every simple cycle of at most `max_hops` pairs through one of `starts`
+///
+/// Each cycle is returned once, starting and ending at the first token of `starts` it passes
+/// through, in the direction where its first pair has the lower pointer. Pairs `usable`
+/// rejects are skipped.
+pub fn enumerate_cycles(
+    adjacency: &Adjacency,
+    starts: &[usize],
+    max_hops: usize,
+    usable: impl Fn(&IndexedPair) -> bool,
+) -> Vec<Cycle> {
+    let mut cycles = Vec::new();
+
+    for (position, start) in starts.iter().enumerate() {
+        // cycles through an earlier start were already found from it
+        let excluded: HashSet<usize> = starts[..position].iter().copied().collect();
+        let distance = distances_to(adjacency, *start, max_hops, &excluded, &usable);
+
+        let mut search = Search {
+            adjacency,
+            start: *start,
+            max_hops,
+            excluded: &excluded,
+            distance: &distance,
+            usable: &usable,
+            path: Vec::with_capacity(max_hops),
+            visited: HashSet::new(),
+            cycles: &mut cycles,
+        };
+        search.extend(*start);
+    }
+
+    cycles
+}
+
+/// Reversed cycles trade the same pools the other way, only one direction is indexed
+pub fn is_canonical(cycle: &[IndexedPair]) -> bool {
+    match (cycle.first(), cycle.last()) {
+        (Some(first), Some(last)) => first.address < last.address,
+        _ => false,
+    }
+}
+
+/// Number of cycles of each length, in pairs
+pub fn count_by_length<'a>(cycles: impl IntoIterator<Item = &'a Cycle>) -> BTreeMap<usize, usize> {
+    let mut counts = BTreeMap::new();
+    for cycle in cycles {
+        *counts.entry(cycle.len()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// The token a pair swaps `token` into
+pub fn other_side(pair: &IndexedPair, token: usize) -> usize {
+    if pair.token0 == token {
+        pair.token1
+    } else {
+        pair.token0
+    }
+}
+
+struct Search<'a, F> {
+    adjacency: &'a Adjacency,
+    start: usize,
+    max_hops: usize,
+    excluded: &'a HashSet<usize>,
+    distance: &'a HashMap<usize, usize>,
+    usable: &'a F,
+    path: Vec<IndexedPair>,
+    // tokens on the path besides the start
+    visited: HashSet<usize>,
+    cycles: &'a mut Vec<Cycle>,
+}
+
+impl<F: Fn(&IndexedPair) -> bool> Search<'_, F> {
+    fn extend(&mut self, token: usize) {
+        let remaining = self.max_hops - self.path.len();
+
+        for pair in self.adjacency.get(&token).into_iter().flatten() {
+            if !(self.usable)(pair) || self.path.iter().any(|p| p.address == pair.address) {
+                continue;
+            }
+
+            let next = other_side(pair, token);
+            if next == self.start {
+                if !self.path.is_empty() && self.path[0].address < pair.address {
+                    let mut cycle = self.path.clone();
+                    cycle.push(*pair);
+                    self.cycles.push(cycle);
+                }
+                continue;
+            }
+
+            // too far from the start to close the cycle in the hops left
+            let back = match self.distance.get(&next) {
+                Some(back) => *back,
+                None => continue,
+            };
+            if remaining < 1 + back || self.visited.contains(&next) || self.excluded.contains(&next) {
+                continue;
+            }
+
+            self.path.push(*pair);
+            self.visited.insert(next);
+            self.extend(next);
+            self.visited.remove(&next);
+            self.path.pop();
+        }
+    }
+}
+
+// Fewest usable pairs from each token back to `start`, up to `max_hops`
+fn distances_to(
+    adjacency: &Adjacency,
+    start: usize,
+    max_hops: usize,
+    excluded: &HashSet<usize>,
+    usable: &impl Fn(&IndexedPair) -> bool,
+) -> HashMap<usize, usize> {
+    let mut distance = HashMap::from([(start, 0)]);
+    let mut queue = VecDeque::from([start]);
+
+    while let Some(token) = queue.pop_front() {
+        let hops = distance[&token];
+        if hops == max_hops {
+            continue;
+        }
+
+        for pair in adjacency.get(&token).into_iter().flatten() {
+            let next = other_side(pair, token);
+            if !usable(pair) || excluded.contains(&next) || distance.contains_key(&next) {
+                continue;
+            }
+
+            distance.insert(next, hops + 1);
+            queue.push_back(next);
+        }
+    }
+
+    distance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adjacency(pairs: &[(usize, usize, usize)]) -> Adjacency {
+        let mut adjacency = Adjacency::new();
+        for (address, token0, token1) in pairs {
+            let pair = IndexedPair {
+                address: *address,
+                token0: *token0,
+                token1: *token1,
+            };
+            adjacency.entry(pair.token0).or_default().push(pair);
+            adjacency.entry(pair.token1).or_default().push(pair);
+        }
+        adjacency
+    }
+
+    fn addresses(cycles: &[Cycle]) -> Vec<Vec<usize>> {
+        let mut addresses: Vec<Vec<usize>> = cycles
+            .iter()
+            .map(|cycle| cycle.iter().map(|pair| pair.address).collect())
+            .collect();
+        addresses.sort();
+        addresses
+    }
+
+    // tokens 0 (start) .. 3, pairs 10 ..
+    const GRAPH: [(usize, usize, usize); 6] = [
+        (10, 0, 1),
+        (11, 1, 0),
+        (12, 1, 2),
+        (13, 2, 0),
+        (14, 2, 3),
+        (15, 3, 0),
+    ];
+
+    #[test]
+    fn finds_each_cycle_once() {
+        let adjacency = adjacency(&GRAPH);
+
+        let cycles = enumerate_cycles(&adjacency, &[0], 3, |_| true);
+
+        assert_eq!(
+            addresses(&cycles),
+            vec![
+                vec![10, 11],
+                vec![10, 12, 13],
+                vec![11, 12, 13],
+                vec![13, 14, 15],
+            ]
+        );
+        assert!(cycles.iter().all(|cycle| is_canonical(cycle)));
+        assert_eq!(count_by_length(&cycles), BTreeMap::from([(2, 1), (3, 3)]));
+    }
+
+    #[test]
+    fn longer_cycles_and_pruning() {
+        let adjacency = adjacency(&GRAPH);
+
+        let four = enumerate_cycles(&adjacency, &[0], 4, |_| true);
+        assert_eq!(count_by_length(&four), BTreeMap::from([(2, 1), (3, 3), (4, 2)]));
+
+        let without_13 = enumerate_cycles(&adjacency, &[0], 4, |pair| pair.address != 13);
+        assert_eq!(
+            addresses(&without_13),
+            vec![vec![10, 11], vec![10, 12, 14, 15], vec![11, 12, 14, 15]]
+        );
+    }
+
+    #[test]
+    fn rotations_through_several_starts_are_found_once() {
+        let mut graph = GRAPH.to_vec();
+        graph.push((16, 2, 3));
+        let adjacency = adjacency(&graph);
+
+        let mut expected = addresses(&enumerate_cycles(&adjacency, &[0], 3, |_| true));
+        expected.push(vec![14, 16]);
+        expected.sort();
+
+        // cycles through both 0 and 2 are only found from 0
+        let cycles = enumerate_cycles(&adjacency, &[0, 2], 3, |_| true);
+        assert_eq!(addresses(&cycles), expected);
+    }
+}
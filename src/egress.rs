@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Response, Url};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::secrets;
+
+#[derive(Error, Debug)]
+pub enum EgressError {
+    #[error("Invalid url: {0}")]
+    InvalidUrl(String),
+    #[error("Host not in egress allowlist: {0}")]
+    HostNotAllowed(String),
+    #[error("Refusing to send payload containing key material")]
+    SecretInPayload(),
+    #[error("Failed to serialize payload")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Http request failed")]
+    Http(#[from] reqwest::Error),
+}
+
+/// The only HTTP client in the crate
+///
+/// Requests are refused unless the host is allowlisted, and every url and body is
+/// screened against the secrets registered in `secrets` before anything hits the wire.
+#[derive(Debug, Clone)]
+pub struct EgressClient {
+    client: Client,
+    allowed_hosts: Arc<HashSet<String>>,
+}
+
+impl EgressClient {
+    pub fn new<I, S>(allowed_hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let allowed_hosts = allowed_hosts
+            .into_iter()
+            .map(|host| host.as_ref().trim().to_ascii_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+
+        Self {
+            client: Client::new(),
+            allowed_hosts: Arc::new(allowed_hosts),
+        }
+    }
+
+    /// Checks the url against the allowlist and the secret registry
+    pub fn check_url(&self, url: &str) -> Result<Url, EgressError> {
+        let parsed = Url::parse(url).map_err(|_| EgressError::InvalidUrl(redact_url(url)))?;
+
+        let host = match parsed.host_str() {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return Err(EgressError::InvalidUrl(redact_url(url))),
+        };
+
+        if !self.allowed_hosts.contains(&host) {
+            return Err(EgressError::HostNotAllowed(host));
+        }
+
+        if secrets::contains_secret(parsed.as_str().as_bytes()) {
+            return Err(EgressError::SecretInPayload());
+        }
+
+        Ok(parsed)
+    }
+
+    pub async fn post_json<T: Serialize + ?Sized>(
+        &self,
+        url: &str,
+        body: &T,
+    ) -> Result<Response, EgressError> {
+        let body = serde_json::to_vec(body)?;
+        self.post_json_bytes(url, body, &[]).await
+    }
+
+    /// Post an already serialized JSON body, for requests signed over their exact bytes
+    pub async fn post_json_bytes(
+        &self,
+        url: &str,
+        body: Vec<u8>,
+        headers: &[(&str, &str)],
+    ) -> Result<Response, EgressError> {
+        let url = self.check_url(url)?;
+
+        let leaks = secrets::contains_secret(&body)
+            || headers
+                .iter()
+                .any(|(_, value)| secrets::contains_secret(value.as_bytes()));
+        if leaks {
+            return Err(EgressError::SecretInPayload());
+        }
+
+        let mut request = self.client.post(url).header(CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        Ok(request.body(body).send().await?)
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Response, EgressError> {
+        let url = self.check_url(url)?;
+        Ok(self.client.get(url).send().await?)
+    }
+}
+
+// Never echo a url that may carry key material back into logs
+fn redact_url(url: &str) -> String {
+    if secrets::contains_secret(url.as_bytes()) {
+        "[REDACTED]".to_string()
+    } else {
+        url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::{construct_bundle, BundleRelay, RelayError};
+    use crate::secrets::SecretString;
+    use crate::signer::BotSigner;
+    use ethers::signers::LocalWallet;
+    use ethers::types::{Bytes, U64};
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+
+    const KEY: &str = "0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+
+    fn local_listener() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let url = format!("http://127.0.0.1:{}/", listener.local_addr().unwrap().port());
+        (listener, url)
+    }
+
+    #[tokio::test]
+    async fn rejects_hosts_outside_allowlist() {
+        let egress = EgressClient::new(["relay.flashbots.net"]);
+
+        let err = egress
+            .post_json("https://discord.com/api/webhooks/1/2", &HashMap::from([("content", "hi")]))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, EgressError::HostNotAllowed(host) if host == "discord.com"));
+    }
+
+    #[tokio::test]
+    async fn key_bytes_never_reach_the_network() {
+        let key = SecretString::new(KEY.to_string());
+        let (listener, url) = local_listener();
+        let egress = EgressClient::new(["127.0.0.1"]);
+
+        // every shape the key could be smuggled in
+        let payloads = [
+            key.expose_secret().to_string(),
+            key.expose_secret()[2..].to_string(),
+            key.expose_secret().to_uppercase(),
+            format!("\n        {}\n        ", key.expose_secret()),
+        ];
+
+        for payload in payloads {
+            let body = HashMap::from([("content", payload.clone())]);
+            let err = egress.post_json(&url, &body).await.unwrap_err();
+            assert!(matches!(err, EgressError::SecretInPayload()));
+
+            let err = egress.get(&format!("{}?k={}", url, payload.trim())).await.unwrap_err();
+            assert!(matches!(err, EgressError::SecretInPayload()));
+        }
+
+        // nothing ever connected to the listener
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_outside_allowlist_are_never_contacted() {
+        let (listener, url) = local_listener();
+        let egress = EgressClient::new(["relay.flashbots.net"]);
+        let signer = BotSigner::from(KEY.parse::<LocalWallet>().unwrap());
+
+        let relay = BundleRelay::new(egress, Url::parse(&url).unwrap(), "local".into(), signer);
+        let bundle = construct_bundle(vec![Bytes::from(vec![0x02, 0x01])], U64::from(100), 0);
+        let err = relay.send_bundle(&bundle).await.unwrap_err();
+
+        assert!(matches!(err, RelayError::Egress(EgressError::HostNotAllowed(host)) if host == "127.0.0.1"));
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+    }
+}
@@ -1,6 +1,9 @@
 pub mod calc;
+pub mod chunked_map;
 pub mod config;
 pub mod constants;
+pub mod cycles;
+pub mod bribe;
 pub mod bundle_sender;
 pub mod contract_modules;
 pub mod backrunner;
@@ -12,23 +15,35 @@ pub mod updater;
 pub mod utils;
 pub mod bundle_errors;
 pub mod relay;
-
-use config::Config;
-use contract_modules::uniswap_v2::checkpoint::Storage;
+pub mod reorg;
+pub mod secrets;
+pub mod signer;
+pub mod egress;
+pub mod simulation;
+
+use config::{BotConfig, Config};
+use contract_modules::uniswap_v2::checkpoint::{
+    self, CheckpointError, Storage, CHECKPOINT_INTERVAL, CHECKPOINT_PATH,
+};
 use crossbeam_channel::unbounded;
 use ethers::utils::format_units;
-use state::State;
+use state::{PendingState, SharedState, State};
 use std::time::{Duration, Instant};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 
 use log::*;
-use tokio::sync::Mutex;
+use tokio::sync::RwLock;
 use ethers::prelude::*;
 
-use crate::calc::find_optimal_cycles;
+use crate::calc::{evaluation_pool, find_optimal_cycles, select_cycles};
 use crate::contract_modules::uniswap_v2::data_collector::data_collector::update_reserves;
 use crate::contract_modules::uniswap_v2::get_uni_v2;
+use crate::contract_modules::uniswap_v2::registry::validate_registry;
+use crate::contract_modules::uniswap_v2::gas_model::{GasModel, GAS_MODEL_PATH};
+use crate::contract_modules::uniswap_v2::tax_cache::{TaxCache, TAX_CACHE_PATH};
 use crate::backrunner::BackRunner;
+use crate::bribe::{BribeStats, BRIBE_STATS_PATH};
 use crate::states::bot_state::BotState;
 use crate::state::StateUpdateInternal;
 use contract_modules::uniswap_v2;
@@ -39,19 +54,50 @@ pub fn init() {}
 // TODO: make code less ugly
 pub async fn run(at_exit: std::sync::mpsc::Receiver<()>) {
     info!("Starting...");
-    tokio::task::spawn(exit(at_exit));
+    // filled once the state is built, so an early exit skips the checkpoint
+    let checkpointer: Arc<OnceLock<Checkpointer>> = Arc::new(OnceLock::new());
+    tokio::task::spawn(exit(at_exit, checkpointer.clone()));
+
+    let settings = match BotConfig::load() {
+        Ok(settings) => Arc::new(settings),
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
 
-    let config = Config::new().await;
-    let uni_v2 = get_uni_v2();
-    let load = should_load_data_from_file();
+    let config = Config::new(settings.clone()).await;
+    let uni_v2 = get_uni_v2(&settings);
+
+    if let Err(errors) = validate_registry(&uni_v2, config.wss.clone()).await {
+        for e in errors {
+            error!("{}", e);
+        }
+        return;
+    }
+    let registry_hash = checkpoint::registry_hash(&uni_v2);
+    let checkpoint = if should_collect_fresh() {
+        None
+    } else {
+        load_checkpoint(settings.chain_id, registry_hash)
+    };
 
     let mut pairs;
+    let mut tax_cache = TaxCache::load_or_default(TAX_CACHE_PATH);
+    // first block not yet applied to the checkpointed reserves
+    let mut resume_from = None;
 
-    if !load {
+    if let Some(storage) = checkpoint {
+        info!("Resuming from checkpoint at block {}", storage.block);
+        pairs = storage.pools;
+        resume_from = Some(U64::from(storage.block.as_u64()) + 1);
+    } else {
         let now = Instant::now();
         pairs = match uniswap_v2::data_collector::data_collector::get_all_pairs(
             uni_v2.clone(),
             config.wss.clone(),
+            &mut tax_cache,
+            settings.tax_cache_max_age,
         )
         .await
         {
@@ -59,9 +105,10 @@ pub async fn run(at_exit: std::sync::mpsc::Receiver<()>) {
             None => return,
         };
         info!("time took for query: {:?}", now.elapsed());
-    } else {
-        let storage = Storage::load_from_file("./db.json").expect("Failed on loading data");
-        pairs = storage.pools;
+
+        if let Err(e) = tax_cache.save_to_file(TAX_CACHE_PATH) {
+            warn!("Failed to save tax cache: {}", e);
+        }
     }
 
     let block = match config.wss.get_block_number().await {
@@ -72,24 +119,79 @@ pub async fn run(at_exit: std::sync::mpsc::Receiver<()>) {
         }
     };
 
-    update_reserves(&mut pairs, uni_v2.clone(), config.wss.clone()).await;
-
-    info!("Length of pairs: {:?}", pairs.len());
+    let tax_cache = Arc::new(RwLock::new(tax_cache));
+
+    match resume_from {
+        Some(from) => {
+            // pairs created while the bot was down first, their reserves are read at `block`
+            let caught_up = async {
+                uniswap_v2::discovery::replay_created_pairs(
+                    config.wss.clone(),
+                    &mut pairs,
+                    &uni_v2,
+                    &tax_cache,
+                    settings.tax_cache_max_age,
+                    from,
+                    block,
+                )
+                .await?;
+                updater::replay_sync_logs(config.wss.clone(), &mut pairs, from, block).await
+            }
+            .await;
 
-    let state: Arc<Mutex<State>> = Arc::new(Mutex::new(state::State::new_state(&pairs)));
+            if let Err(e) = caught_up {
+                error!("Failed to catch up from checkpoint, restart with `fresh`: {}", e);
+                return;
+            }
+        }
+        None => update_reserves(&mut pairs, uni_v2.clone(), config.wss.clone()).await,
+    }
 
-    let bot_state = Arc::new(BotState::new(&config.wss.clone()).await.unwrap());
+    info!("Length of pairs: {:?}", pairs.len());
 
-    // tokio::task::spawn(run_exit_save(at_exit, state.clone(), config.wss.clone()));
-    let block_oracle = states::block_state::BlockOracle::new(config.wss.clone(), bot_state)
-        .await
-        .expect("Panic at block oracle creation");
+    let state = Arc::new(SharedState::new(State::new_state(&pairs, block, settings.cycle_search())));
+    let gas_model = Arc::new(RwLock::new(GasModel::load_or_default(GAS_MODEL_PATH)));
+    let bribe_stats = Arc::new(Mutex::new(BribeStats::load_or_default(BRIBE_STATS_PATH)));
+    let state_checkpointer = Checkpointer {
+        state: state.clone(),
+        gas_model: gas_model.clone(),
+        bribe_stats: bribe_stats.clone(),
+        chain_id: settings.chain_id,
+        registry_hash,
+    };
+    let _ = checkpointer.set(state_checkpointer.clone());
+    tokio::task::spawn(run_checkpoints(state_checkpointer));
+
+    let bot_state = Arc::new(BotState::new(&config.wss, settings.multicall_contract).await.unwrap());
+
+    let back_runner = Arc::new(BackRunner::new(
+        settings.clone(),
+        &config.wss,
+        config.egress.clone(),
+        config.searchers.clone(),
+        config.bundle_signer.clone(),
+        bribe_stats,
+    )
+    .await);
+    let block_oracle = states::block_state::BlockOracle::new(
+        config.wss.clone(),
+        bot_state.clone(),
+        back_runner.wallets.clone(),
+    )
+    .await
+    .expect("Panic at block oracle creation");
 
     
-    tokio::task::spawn(updater::start_updater(
+    let block_updater = updater::BlockUpdater::new(Arc::clone(&config.wss), state.clone());
+    tokio::task::spawn(report_reorgs(block_updater.reorg_counter()));
+    tokio::task::spawn(updater::start_updater(block_updater, block));
+
+    tokio::task::spawn(uniswap_v2::discovery::start_discovery(
         Arc::clone(&config.wss),
         state.clone(),
-        block,
+        uni_v2.clone(),
+        tax_cache.clone(),
+        settings.tax_cache_max_age,
     ));
 
     // Give time to  sync Uni data
@@ -100,7 +202,13 @@ pub async fn run(at_exit: std::sync::mpsc::Receiver<()>) {
     
     let decoded = hex::decode(constants::SYNC_TOPIC).unwrap();
     let sync_topic = H256::from_slice(&decoded);
-    let back_runner = Arc::new(BackRunner::new().await);
+    let evaluator = match evaluation_pool(settings.evaluation_threads) {
+        Ok(evaluator) => evaluator,
+        Err(e) => {
+            error!("Failed to start the cycle evaluation threads: {}", e);
+            return;
+        }
+    };
     
 
 
@@ -108,7 +216,6 @@ pub async fn run(at_exit: std::sync::mpsc::Receiver<()>) {
         let client = config.wss.clone();
         let data = r.recv().unwrap();
 
-        let mut state = state.lock().await;
         let mut pending_state_updates = Vec::new();
         let mut affected_pairs = Vec::new();
 
@@ -152,20 +259,42 @@ pub async fn run(at_exit: std::sync::mpsc::Receiver<()>) {
         }
 
         if pending_state_updates.is_empty() { continue }
-        State::apply_state_temp(&mut state, pending_state_updates);
 
-        let cycles = find_optimal_cycles(&state, Some(affected_pairs));
+        // the pending tx is evaluated over a snapshot, its reserves are never touched
+        let snapshot = state.snapshot();
+        let mut pending_state = PendingState::new(&snapshot);
+        pending_state.apply(pending_state_updates);
+
+        let inventory = bot_state.inventory().await;
+        let target_block = block_oracle.read().await.next_block.clone();
+        let expected_gas = gas_model.read().await.clone();
+        let cycles = tokio::task::block_in_place(|| {
+            let candidates = evaluator.install(|| {
+                find_optimal_cycles(
+                    &pending_state,
+                    Some(affected_pairs),
+                    &inventory,
+                    &expected_gas,
+                    target_block.base_fee,
+                )
+            });
+
+            // the bundle's cycles run one after another, not all against the same reserves
+            select_cycles(&pending_state, candidates, &inventory, &expected_gas, target_block.base_fee)
+        });
         
         let optimal_recipe = cycles.iter()
             .map(|net_positive| 
              {
 
-                net_positive.encode_data()
+                net_positive.encode_data(settings.multicall_contract, 0)
 
              }).collect::<Vec<Bytes>>();
 
         let back_runner = back_runner.clone();
-        let target_block = block_oracle.read().await.next_block.clone();
+        let tax_cache = tax_cache.clone();
+        let gas_model = gas_model.clone();
+        let state = state.clone();
 
 
         if !cycles.is_empty() {
@@ -175,8 +304,11 @@ pub async fn run(at_exit: std::sync::mpsc::Receiver<()>) {
 
             for net_positive in cycles.iter()
             {
-                let profit = back_reward.0 + net_positive.profit.into_raw();
-                let optimal_in = back_reward.1 + net_positive.optimal_in;
+                let profit = back_reward.0 + net_positive.net_profit.into_raw();
+                let optimal_in = back_reward.1
+                    + pending_state
+                        .weth_value(net_positive.base_token, net_positive.optimal_in)
+                        .unwrap_or_default();
 
                 back_reward = (profit, optimal_in);
             }
@@ -191,6 +323,9 @@ pub async fn run(at_exit: std::sync::mpsc::Receiver<()>) {
                 back_runner.clone(),
                 target_block,
                 client,
+                state,
+                tax_cache,
+                gas_model,
                 )
                 .await
                 {
@@ -218,7 +353,7 @@ pub async fn run(at_exit: std::sync::mpsc::Receiver<()>) {
                 data.tx.hash()
             );
             info!(
-                "                  ------> Profit: {:.9} ",
+                "                  ------> Net Profit: {:.9} ",
                 format_units(back_reward.0, "ether").unwrap()
             );
             info!(
@@ -233,18 +368,89 @@ pub async fn run(at_exit: std::sync::mpsc::Receiver<()>) {
                 "             ",
             );
         }
-
-        State::reset_temp_state(&mut state);
     }
 }
 
-fn should_load_data_from_file() -> bool {
+fn should_collect_fresh() -> bool {
     let args: Vec<String> = std::env::args().collect();
 
-    args.iter().any(|arg| arg == "load")
+    args.iter().any(|arg| arg == "fresh")
+}
+
+// A checkpoint for another chain or dex registry is ignored and the pools are collected again
+fn load_checkpoint(chain_id: u64, registry_hash: H256) -> Option<Storage> {
+    match Storage::load_binary(CHECKPOINT_PATH, chain_id, registry_hash) {
+        Ok(storage) => Some(storage),
+        Err(CheckpointError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("Ignoring checkpoint {}: {}", CHECKPOINT_PATH, e);
+            None
+        }
+    }
+}
+
+// Everything needed to checkpoint the running state
+#[derive(Clone)]
+struct Checkpointer {
+    state: Arc<SharedState>,
+    gas_model: Arc<RwLock<GasModel>>,
+    bribe_stats: Arc<Mutex<BribeStats>>,
+    chain_id: u64,
+    registry_hash: H256,
 }
 
-async fn exit(signal_at: std::sync::mpsc::Receiver<()>) {
-    signal_at.recv().unwrap();
+impl Checkpointer {
+    async fn save(&self) {
+        let storage = self.state.snapshot().checkpoint();
+
+        match storage.save_binary(CHECKPOINT_PATH, self.chain_id, self.registry_hash) {
+            Ok(_) => info!("Checkpoint saved at block {}", storage.block),
+            Err(e) => warn!("Failed to save checkpoint: {}", e),
+        }
+
+        // bundles keep calibrating the model while the copy is written
+        let gas_model = self.gas_model.read().await.clone();
+        if let Err(e) = gas_model.save_to_file(GAS_MODEL_PATH) {
+            warn!("Failed to save gas model: {}", e);
+        }
+
+        let bribe_stats = self.bribe_stats.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        if let Err(e) = bribe_stats.save_to_file(BRIBE_STATS_PATH) {
+            warn!("Failed to save bribe stats: {}", e);
+        }
+    }
+}
+
+async fn run_checkpoints(checkpointer: Checkpointer) {
+    let mut interval = tokio::time::interval(Duration::from_secs(CHECKPOINT_INTERVAL));
+    // the first tick completes immediately
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        checkpointer.save().await;
+    }
+}
+
+// Log how many reorgs the updater rolled back, once per checkpoint interval
+async fn report_reorgs(reorgs: Arc<AtomicU64>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(CHECKPOINT_INTERVAL));
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        info!("Reorgs rolled back so far: {}", reorgs.load(Ordering::Relaxed));
+    }
+}
+
+async fn exit(
+    signal_at: std::sync::mpsc::Receiver<()>,
+    checkpointer: Arc<OnceLock<Checkpointer>>,
+) {
+    let _ = tokio::task::spawn_blocking(move || signal_at.recv()).await;
+
+    if let Some(checkpointer) = checkpointer.get() {
+        checkpointer.save().await;
+    }
     std::process::exit(0);
 }
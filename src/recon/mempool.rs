@@ -1,12 +1,12 @@
 use crossbeam_channel::{Sender, TrySendError};
 use ethers::prelude::*;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::RwLock;
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::task::spawn;
 use crate::states::block_state::BlockOracle;
 use crate::utils::get_logs;
-use crate::state::State;
+use crate::state::SharedState;
 
 pub struct FutureTx {
     pub tx: Transaction,
@@ -15,7 +15,7 @@ pub struct FutureTx {
 }
 
 pub async fn start_recon(
-    state: Arc<Mutex<State>>,
+    state: Arc<SharedState>,
     wss: Arc<Provider<Ws>>,
     block_oracle: Arc<RwLock<BlockOracle>>,
     send_to: Sender<FutureTx>,
@@ -60,12 +60,12 @@ pub async fn start_recon(
                 };
 
                 let significant_logs = {
-                    let state = state.lock().await;
+                    let state = state.snapshot();
                     logs.into_iter()
                         .filter_map(|log| {
                             let origin = log.address?;
                             let ptr = state.address_mapping.get(&origin)?;
-                            if state.pairs_mapping.contains_key(ptr) {
+                            if state.contains_pair(*ptr) {
                                 Some(log)
                             } else {
                                 None
@@ -1,48 +1,115 @@
 use std::sync::Arc;
-use crate::utils;
-use ethers::prelude::{LocalWallet, SignerMiddleware};
-use ethers::providers::{Ws, Provider, ProviderError};
-use ethers::types::{Bytes, U64};
-use ethers_flashbots::*;
+use std::time::Duration;
+use crate::egress::{EgressClient, EgressError};
+use crate::signer::{BotSigner, SignerError};
+use ethers::providers::{Middleware, Provider, ProviderError, Ws};
+use ethers::signers::Signer;
+use ethers::types::{Bytes, H256, U64};
+use ethers::utils::keccak256;
+use ethers_flashbots::BundleRequest;
 use reqwest::Url;
+use serde::Deserialize;
+use thiserror::Error;
 
+// a target block that isn't mined by then is treated as missed
+const INCLUSION_TIMEOUT: Duration = Duration::from_secs(60);
 
+#[derive(Error, Debug)]
+pub enum RelayError {
+    #[error(transparent)]
+    Egress(#[from] EgressError),
+    #[error("Failed to sign the relay request")]
+    Signing(#[from] SignerError),
+    #[error("Failed to serialize the bundle")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Relay answered with status {0}")]
+    Status(u16),
+    #[error("Relay rejected the bundle: {0}")]
+    Rejected(String),
+}
+
+#[derive(Deserialize)]
+struct RelayReply {
+    result: Option<SendBundleResult>,
+    error: Option<RelayReplyError>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendBundleResult {
+    bundle_hash: Option<H256>,
+}
 
+#[derive(Deserialize)]
+struct RelayReplyError {
+    message: String,
+}
 
+/// A bundle relay, reached through the egress client like every other host
+///
+/// Requests carry the `X-Flashbots-Signature` of the Flashbots identity over their exact body.
 pub struct BundleRelay {
-    pub flashbots_client:
-        SignerMiddleware<FlashbotsMiddleware<Arc<Provider<Ws>>, LocalWallet>, LocalWallet>,
+    egress: EgressClient,
+    url: Url,
+    bundle_signer: BotSigner,
     pub relay_name: String,
 }
 
 impl BundleRelay {
-    pub fn new(
-        relay_end_point: Url,
-        relay_name: String,
-        client: &Arc<Provider<Ws>>,
-    ) -> Result<BundleRelay, ProviderError> {
+    pub fn new(egress: EgressClient, url: Url, relay_name: String, bundle_signer: BotSigner) -> Self {
+        Self {
+            egress,
+            url,
+            bundle_signer,
+            relay_name,
+        }
+    }
 
+    /// Send `bundle` with `eth_sendBundle`, returns its hash when the relay gives one
+    pub async fn send_bundle(&self, bundle: &BundleRequest) -> Result<Option<H256>, RelayError> {
+        let body = serde_json::to_vec(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_sendBundle",
+            "params": [bundle],
+        }))?;
 
-        // Extract wallets from .env keys
-        let bundle_private_key = std::env::var("FLASHBOTS_AUTH_KEY").unwrap();
-        let searcher_private_key = std::env::var("SEARCHER_PRIVATE_KEY").unwrap();
+        let digest = format!("0x{:x}", H256::from(keccak256(&body)));
+        let signature = self.bundle_signer.sign_message(digest).await?;
+        let header = format!("{:?}:0x{}", self.bundle_signer.address(), signature);
 
-        let bundle_signer = bundle_private_key.parse::<LocalWallet>().unwrap();
-        let searcher_signer = searcher_private_key.parse::<LocalWallet>().unwrap();
+        let response = self
+            .egress
+            .post_json_bytes(self.url.as_str(), body, &[("X-Flashbots-Signature", &header)])
+            .await?;
+        if !response.status().is_success() {
+            return Err(RelayError::Status(response.status().as_u16()));
+        }
 
-        // Setup the Ethereum client with flashbots middleware
-        let flashbots_middleware =
-            FlashbotsMiddleware::new(client.clone(), relay_end_point, bundle_signer);
+        let reply: RelayReply = response.json().await.map_err(EgressError::from)?;
+        if let Some(error) = reply.error {
+            return Err(RelayError::Rejected(error.message));
+        }
+        Ok(reply.result.and_then(|result| result.bundle_hash))
+    }
+}
 
-        // Local node running mev-geth
-        //flashbots_middleware.set_simulation_relay(Url::parse("http://127.0.0.1:8546").unwrap());
-        let flashbots_client = SignerMiddleware::new(flashbots_middleware, searcher_signer);
+/// Wait for `block` to be mined and tell whether it holds every transaction of the bundle
+pub async fn bundle_included(
+    client: &Arc<Provider<Ws>>,
+    block: U64,
+    signed_txs: &[Bytes],
+) -> Result<bool, ProviderError> {
+    let hashes: Vec<H256> = signed_txs.iter().map(|tx| H256::from(keccak256(tx))).collect();
+    let started = std::time::Instant::now();
 
-        Ok(BundleRelay {
-            flashbots_client,
-            relay_name,
-        })
+    while started.elapsed() < INCLUSION_TIMEOUT {
+        if let Some(mined) = client.get_block(block).await? {
+            return Ok(hashes.iter().all(|hash| mined.transactions.contains(hash)));
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
     }
+    Ok(false)
 }
 
 pub fn construct_bundle(
@@ -68,30 +135,129 @@ pub fn construct_bundle(
     bundle_request
 }
 
-pub async fn get_all_relay_endpoints() -> Vec<BundleRelay> {
-    let client = utils::create_websocket_client().await.unwrap();
-
-    let endpoints = vec![
-        ("flashbots", "https://relay.flashbots.net/"),
-        ("builder0x69", "http://builder0x69.io/"),
-        ("edennetwork", "https://api.edennetwork.io/v1/bundle"),
-        ("beaverbuild", "https://rpc.beaverbuild.org/"),
-        ("lightspeedbuilder", "https://rpc.lightspeedbuilder.info/"),
-        ("eth-builder", "https://eth-builder.com/"),
-        ("rsync-builder", "https://rsync-builder.xyz/"),
-         //"http://relayooor.wtf/",
-        //"http://mainnet.aestus.live/",
-        //"https://mainnet-relay.securerpc.com",
-        //"http://agnostic-relay.net/",
-        //"http://relay.ultrasound.money/",
-    ];
-
-    let mut relays: Vec<BundleRelay> = vec![];
-
-    for (name, endpoint) in endpoints {
-        let relay = BundleRelay::new(Url::parse(endpoint).unwrap(), name.into(), &client).unwrap();
-        relays.push(relay);
+pub const RELAY_ENDPOINTS: [(&str, &str); 7] = [
+    ("flashbots", "https://relay.flashbots.net/"),
+    ("builder0x69", "http://builder0x69.io/"),
+    ("edennetwork", "https://api.edennetwork.io/v1/bundle"),
+    ("beaverbuild", "https://rpc.beaverbuild.org/"),
+    ("lightspeedbuilder", "https://rpc.lightspeedbuilder.info/"),
+    ("eth-builder", "https://eth-builder.com/"),
+    ("rsync-builder", "https://rsync-builder.xyz/"),
+     //"http://relayooor.wtf/",
+    //"http://mainnet.aestus.live/",
+    //"https://mainnet-relay.securerpc.com",
+    //"http://agnostic-relay.net/",
+    //"http://relay.ultrasound.money/",
+];
+
+/// Hosts of all relays, used as the default egress allowlist
+pub fn get_relay_hosts() -> Vec<String> {
+    RELAY_ENDPOINTS
+        .iter()
+        .filter_map(|(_, endpoint)| Url::parse(endpoint).ok())
+        .filter_map(|url| url.host_str().map(|host| host.to_string()))
+        .collect()
+}
+
+/// Relays the egress allowlist lets the bot reach, by name
+pub fn allowed_relays(egress: &EgressClient) -> Vec<(&'static str, Url)> {
+    let mut relays = vec![];
+
+    for (name, endpoint) in RELAY_ENDPOINTS {
+        // relays outside the allowlist are never contacted
+        let url = match egress.check_url(endpoint) {
+            Ok(url) => url,
+            Err(e) => {
+                log::warn!("Skipping relay {}: {}", name, e);
+                continue;
+            }
+        };
+
+        relays.push((name, url));
     }
 
     relays
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::LocalWallet;
+    use ethers::types::Signature;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const KEY: &str = "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    // Stand-in relay answering one request with `reply`, hands back the raw request
+    async fn relay_server(reply: &'static str) -> (String, tokio::sync::oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://127.0.0.1:{}/", listener.local_addr().unwrap().port());
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 4096];
+            loop {
+                let read = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..read]);
+
+                let text = String::from_utf8_lossy(&request).to_string();
+                let complete = text.split_once("\r\n\r\n").is_some_and(|(head, body)| {
+                    let length = head
+                        .lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                        .and_then(|v| v.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    body.len() >= length
+                });
+                if complete || read == 0 {
+                    break;
+                }
+            }
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                reply.len(),
+                reply
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let _ = sender.send(String::from_utf8_lossy(&request).to_string());
+        });
+
+        (url, receiver)
+    }
+
+    #[tokio::test]
+    async fn bundles_are_signed_over_their_exact_body() {
+        let reply = r#"{"jsonrpc":"2.0","id":1,"result":{"bundleHash":"0x00000000000000000000000000000000000000000000000000000000000000aa"}}"#;
+        let (url, request) = relay_server(reply).await;
+        let signer = KEY.parse::<LocalWallet>().unwrap();
+
+        let relay = BundleRelay::new(
+            EgressClient::new(["127.0.0.1"]),
+            Url::parse(&url).unwrap(),
+            "local".into(),
+            BotSigner::from(signer.clone()),
+        );
+        let bundle = construct_bundle(vec![Bytes::from(vec![0x02, 0x01])], U64::from(100), 0);
+        let bundle_hash = relay.send_bundle(&bundle).await.unwrap();
+        assert_eq!(bundle_hash, Some(H256::from_low_u64_be(0xaa)));
+
+        let request = request.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(body.contains("\"method\":\"eth_sendBundle\""));
+
+        let header = head
+            .lines()
+            .find_map(|line| line.to_ascii_lowercase().strip_prefix("x-flashbots-signature:").map(|v| v.trim().to_string()))
+            .unwrap();
+        let (address, signature) = header.split_once(':').unwrap();
+        assert_eq!(address, format!("{:?}", signer.address()));
+
+        let signature: Signature = signature.parse().unwrap();
+        let digest = format!("0x{:x}", H256::from(keccak256(body.as_bytes())));
+        assert_eq!(signature.recover(digest).unwrap(), signer.address());
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+
+use ethers::prelude::*;
+
+use crate::state::State;
+
+/// Blocks kept in the journal, deeper reorgs cannot be fully rolled back
+pub const REORG_JOURNAL_DEPTH: usize = 64;
+
+/// A block applied to the state and the reserves it overwrote
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    pub number: U64,
+    pub hash: H256,
+    pub parent_hash: H256,
+    // reserves of each pair before the block's first Sync of it
+    previous: Vec<(usize, [U256; 2])>,
+}
+
+impl JournalEntry {
+    pub fn new(number: U64, hash: H256, parent_hash: H256) -> Self {
+        Self {
+            number,
+            hash,
+            parent_hash,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Set a pair's reserves, remembering what they were the first time the block touches it
+    pub fn apply_sync(&mut self, state: &mut State, pointer: usize, reserve0: U256, reserve1: U256) -> bool {
+        let previous = match state.reserves(pointer) {
+            Some(d) => d,
+            None => return false,
+        };
+
+        if !self.previous.iter().any(|(index, _)| *index == pointer) {
+            self.previous.push((pointer, previous));
+        }
+
+        state.set_reserves(pointer, reserve0, reserve1)
+    }
+
+    /// Put back the reserves the block overwrote
+    pub fn rollback(&self, state: &mut State) {
+        for (pointer, [reserve0, reserve1]) in self.previous.iter() {
+            state.set_reserves(*pointer, *reserve0, *reserve1);
+        }
+    }
+}
+
+/// Recent blocks applied to the state, oldest first
+#[derive(Debug, Clone)]
+pub struct ReorgJournal {
+    entries: VecDeque<JournalEntry>,
+    depth: usize,
+}
+
+impl ReorgJournal {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(depth + 1),
+            depth,
+        }
+    }
+
+    pub fn push(&mut self, entry: JournalEntry) {
+        self.entries.push_back(entry);
+        if self.entries.len() > self.depth {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<JournalEntry> {
+        self.entries.pop_back()
+    }
+
+    pub fn head(&self) -> Option<&JournalEntry> {
+        self.entries.back()
+    }
+
+    pub fn oldest(&self) -> Option<&JournalEntry> {
+        self.entries.front()
+    }
+
+    pub fn hash_at(&self, number: U64) -> Option<H256> {
+        let oldest = self.oldest()?.number;
+        if number < oldest {
+            return None;
+        }
+
+        self.entries
+            .get((number - oldest).as_usize())
+            .map(|entry| entry.hash)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,121 @@
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::sync::RwLock;
+use zeroize::Zeroizing;
+
+/// Every secret created in this process, lowercased, so outbound payloads can be screened
+static REGISTRY: RwLock<Vec<Zeroizing<Vec<u8>>>> = RwLock::new(Vec::new());
+
+/// Key material (private keys, mnemonics, auth keys)
+///
+/// Intentionally has no `Display` or `Serialize` impl, and `Debug` is redacted,
+/// so the only way to read the value is an explicit `expose_secret()` call.
+/// The buffer is wiped on drop.
+#[derive(Clone)]
+pub struct SecretString(Zeroizing<String>);
+
+impl SecretString {
+    pub fn new(secret: String) -> Self {
+        register(&secret);
+        Self(Zeroizing::new(secret))
+    }
+
+    /// Read the secret, only call this right before handing it to a signer
+    pub fn expose_secret(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString([REDACTED])")
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self::new(secret)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::new)
+    }
+}
+
+// Remember a secret (and its un-prefixed hex form) for `contains_secret`
+fn register(secret: &str) {
+    let trimmed = secret.trim();
+    let bare = trimmed.strip_prefix("0x").unwrap_or(trimmed);
+
+    // short values would match random payload bytes, nothing worth guarding is that small
+    if bare.len() < 16 {
+        return;
+    }
+
+    let mut registry = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+    let needle = Zeroizing::new(bare.to_ascii_lowercase().into_bytes());
+    if !registry.iter().any(|known| **known == *needle) {
+        registry.push(needle);
+    }
+}
+
+/// Returns true if `payload` contains any secret known to this process (case insensitive)
+pub fn contains_secret(payload: &[u8]) -> bool {
+    let registry = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+    if registry.is_empty() {
+        return false;
+    }
+
+    let haystack = Zeroizing::new(payload.to_ascii_lowercase());
+    registry.iter().any(|needle| {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle.as_slice())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    #[test]
+    fn debug_is_redacted() {
+        let secret = SecretString::new(KEY.to_string());
+        let printed = format!("{:?}", secret);
+
+        assert_eq!(printed, "SecretString([REDACTED])");
+        assert!(!printed.contains(&KEY[2..]));
+    }
+
+    #[test]
+    fn deserialized_secrets_are_redacted_and_registered() {
+        #[derive(Deserialize, Debug)]
+        struct Env {
+            private_key: SecretString,
+        }
+
+        let key = "0xaaaabbbbccccddddeeeeffff0000111122223333444455556666777788889999";
+        let env: Env = toml::from_str(&format!("private_key = \"{}\"", key)).unwrap();
+
+        assert_eq!(env.private_key.expose_secret(), key);
+        assert!(!format!("{:?}", env).contains(&key[2..]));
+        assert!(contains_secret(key[2..].to_uppercase().as_bytes()));
+    }
+
+    #[test]
+    fn detects_secret_inside_payload() {
+        let _secret = SecretString::new(KEY.to_string());
+
+        let payload = format!("{{\"content\":\"{}\"}}", &KEY[2..]);
+        assert!(contains_secret(payload.as_bytes()));
+        assert!(!contains_secret(b"{\"content\":\"hello\"}"));
+    }
+}
@@ -0,0 +1,431 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use ethers::prelude::*;
+use ethers::signers::to_eip155_v;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::transaction::eip712::Eip712;
+use ethers::utils::keccak256;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::egress::{EgressClient, EgressError};
+use crate::secrets::SecretString;
+
+#[derive(Error, Debug)]
+pub enum SignerError {
+    #[error(transparent)]
+    Wallet(#[from] WalletError),
+    #[error("Could not read {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Remote signer request failed: {0}")]
+    Egress(#[from] EgressError),
+    #[error("Remote signer answered with status {0}")]
+    Status(u16),
+    #[error("Remote signer holds no key for {0:?}")]
+    UnknownKey(Address),
+    #[error("Remote signer returned an invalid signature")]
+    InvalidSignature(),
+    #[error("Remote signer signature does not recover to {0:?}")]
+    WrongSigner(Address),
+    #[error("Remote signer can't sign typed data")]
+    TypedDataUnsupported(),
+}
+
+/// Where the key of a signing identity lives
+#[derive(Debug, Clone)]
+pub enum SignerSource {
+    /// Hex private key from the config or environment
+    PrivateKey(SecretString),
+    /// Encrypted JSON keystore, its passphrase read from `passphrase_file` or prompted for
+    Keystore {
+        path: PathBuf,
+        passphrase_file: Option<PathBuf>,
+    },
+    /// Web3Signer compatible server holding the key of `address`
+    Remote { url: String, address: Address },
+}
+
+impl SignerSource {
+    /// Unlock the key, or find it on the remote signer
+    pub async fn load(&self, chain_id: u64, egress: &EgressClient) -> Result<BotSigner, SignerError> {
+        let signer = match self {
+            SignerSource::PrivateKey(key) => BotSigner::Local(key.expose_secret().parse::<LocalWallet>()?),
+            SignerSource::Keystore {
+                path,
+                passphrase_file,
+            } => {
+                let passphrase = match passphrase_file {
+                    Some(file) => read_passphrase(file)?,
+                    None => prompt_passphrase(path)?,
+                };
+                BotSigner::Local(LocalWallet::decrypt_keystore(path, passphrase.expose_secret())?)
+            }
+            SignerSource::Remote { url, address } => {
+                BotSigner::Remote(RemoteSigner::connect(egress.clone(), url, *address).await?)
+            }
+        };
+
+        Ok(signer.with_chain_id(chain_id))
+    }
+
+    /// Host of the remote signer, it has to be reachable through egress
+    pub fn remote_host(&self) -> Option<String> {
+        match self {
+            SignerSource::Remote { url, .. } => reqwest::Url::parse(url)
+                .ok()
+                .and_then(|url| url.host_str().map(|host| host.to_string())),
+            _ => None,
+        }
+    }
+}
+
+// Only the first line counts, so a trailing newline is not part of the passphrase
+fn read_passphrase(file: &Path) -> Result<SecretString, SignerError> {
+    let contents =
+        fs::read_to_string(file).map_err(|e| SignerError::Read(file.display().to_string(), e))?;
+    let passphrase = contents.lines().next().unwrap_or_default().to_string();
+    Ok(SecretString::new(passphrase))
+}
+
+fn prompt_passphrase(keystore: &Path) -> Result<SecretString, SignerError> {
+    let term = console::Term::stderr();
+    let read = term
+        .write_line(&format!("Passphrase for keystore {}:", keystore.display()))
+        .and_then(|_| term.read_secure_line());
+
+    read.map(SecretString::new)
+        .map_err(|e| SignerError::Read("passphrase from the terminal".to_string(), e))
+}
+
+/// Key held by a Web3Signer compatible server
+///
+/// The server signs the keccak hash of whatever data it is sent, every signature is
+/// checked to recover to the expected address before use.
+#[derive(Debug, Clone)]
+pub struct RemoteSigner {
+    egress: EgressClient,
+    url: String,
+    // public key the server knows the key by
+    identifier: String,
+    address: Address,
+    chain_id: u64,
+}
+
+#[derive(Serialize)]
+struct SignRequest {
+    data: Bytes,
+}
+
+impl RemoteSigner {
+    /// Look up the key of `address` among the public keys the server at `url` holds
+    pub async fn connect(egress: EgressClient, url: &str, address: Address) -> Result<Self, SignerError> {
+        let url = url.trim_end_matches('/').to_string();
+
+        let response = egress.get(&format!("{}/api/v1/eth1/publicKeys", url)).await?;
+        if !response.status().is_success() {
+            return Err(SignerError::Status(response.status().as_u16()));
+        }
+        let keys: Vec<String> = response.json().await.map_err(EgressError::from)?;
+
+        let identifier = keys
+            .into_iter()
+            .find(|key| public_key_address(key) == Some(address))
+            .ok_or(SignerError::UnknownKey(address))?;
+
+        Ok(Self {
+            egress,
+            url,
+            identifier,
+            address,
+            chain_id: 1,
+        })
+    }
+
+    // Signature over keccak(data), with v as 27 or 28
+    async fn sign_data(&self, data: Vec<u8>) -> Result<Signature, SignerError> {
+        let digest = H256::from(keccak256(&data));
+        let url = format!("{}/api/v1/eth1/sign/{}", self.url, self.identifier);
+
+        let response = self.egress.post_json(&url, &SignRequest { data: data.into() }).await?;
+        if !response.status().is_success() {
+            return Err(SignerError::Status(response.status().as_u16()));
+        }
+        let body = response.text().await.map_err(EgressError::from)?;
+
+        let mut signature: Signature = body
+            .trim()
+            .trim_matches('"')
+            .parse()
+            .map_err(|_| SignerError::InvalidSignature())?;
+        if signature.v < 27 {
+            signature.v += 27;
+        }
+
+        match signature.recover(digest) {
+            Ok(signer) if signer == self.address => Ok(signature),
+            _ => Err(SignerError::WrongSigner(self.address)),
+        }
+    }
+}
+
+// Address of a hex encoded secp256k1 public key, with or without the 0x04 prefix
+fn public_key_address(key: &str) -> Option<Address> {
+    let bytes = hex::decode(key.trim_start_matches("0x")).ok()?;
+    let point = match bytes.len() {
+        65 if bytes[0] == 4 => &bytes[1..],
+        64 => &bytes[..],
+        _ => return None,
+    };
+    Some(Address::from_slice(&keccak256(point)[12..]))
+}
+
+#[async_trait]
+impl Signer for RemoteSigner {
+    type Error = SignerError;
+
+    async fn sign_message<S: Send + Sync + AsRef<[u8]>>(&self, message: S) -> Result<Signature, Self::Error> {
+        let message = message.as_ref();
+        let mut data = format!("\x19Ethereum Signed Message:\n{}", message.len()).into_bytes();
+        data.extend_from_slice(message);
+
+        self.sign_data(data).await
+    }
+
+    // Same v as a local wallet gives, EIP-155 for every transaction type
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> Result<Signature, Self::Error> {
+        let mut tx = tx.clone();
+        let chain_id = tx.chain_id().map(|id| id.as_u64()).unwrap_or(self.chain_id);
+        tx.set_chain_id(chain_id);
+
+        let mut signature = self.sign_data(tx.rlp().to_vec()).await?;
+        signature.v = to_eip155_v(signature.v as u8 - 27, chain_id);
+        Ok(signature)
+    }
+
+    // The server only signs hashes of raw data, an EIP-712 digest would be hashed again
+    async fn sign_typed_data<T: Eip712 + Send + Sync>(&self, _payload: &T) -> Result<Signature, Self::Error> {
+        Err(SignerError::TypedDataUnsupported())
+    }
+
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    fn with_chain_id<T: Into<u64>>(mut self, chain_id: T) -> Self {
+        self.chain_id = chain_id.into();
+        self
+    }
+}
+
+/// Signer of transactions and relay requests, whichever way its key is held
+#[derive(Debug, Clone)]
+pub enum BotSigner {
+    Local(LocalWallet),
+    Remote(RemoteSigner),
+}
+
+#[async_trait]
+impl Signer for BotSigner {
+    type Error = SignerError;
+
+    async fn sign_message<S: Send + Sync + AsRef<[u8]>>(&self, message: S) -> Result<Signature, Self::Error> {
+        match self {
+            BotSigner::Local(wallet) => Ok(wallet.sign_message(message).await?),
+            BotSigner::Remote(remote) => remote.sign_message(message).await,
+        }
+    }
+
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> Result<Signature, Self::Error> {
+        match self {
+            BotSigner::Local(wallet) => Ok(wallet.sign_transaction(tx).await?),
+            BotSigner::Remote(remote) => remote.sign_transaction(tx).await,
+        }
+    }
+
+    async fn sign_typed_data<T: Eip712 + Send + Sync>(&self, payload: &T) -> Result<Signature, Self::Error> {
+        match self {
+            BotSigner::Local(wallet) => Ok(wallet.sign_typed_data(payload).await?),
+            BotSigner::Remote(remote) => remote.sign_typed_data(payload).await,
+        }
+    }
+
+    fn address(&self) -> Address {
+        match self {
+            BotSigner::Local(wallet) => wallet.address(),
+            BotSigner::Remote(remote) => remote.address(),
+        }
+    }
+
+    fn chain_id(&self) -> u64 {
+        match self {
+            BotSigner::Local(wallet) => wallet.chain_id(),
+            BotSigner::Remote(remote) => remote.chain_id(),
+        }
+    }
+
+    fn with_chain_id<T: Into<u64>>(self, chain_id: T) -> Self {
+        match self {
+            BotSigner::Local(wallet) => BotSigner::Local(wallet.with_chain_id(chain_id)),
+            BotSigner::Remote(remote) => BotSigner::Remote(remote.with_chain_id(chain_id)),
+        }
+    }
+}
+
+impl From<LocalWallet> for BotSigner {
+    fn from(wallet: LocalWallet) -> Self {
+        BotSigner::Local(wallet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const KEY: &str = "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    // Stand-in for a Web3Signer holding `key`, signing keccak(data) of each request
+    async fn signer_server(key: LocalWallet) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+
+        let public_key = key.signer().verifying_key().to_encoded_point(false);
+        let identifier = format!("0x{}", hex::encode(public_key.as_bytes()));
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut stream).await;
+                let head = request.lines().next().unwrap_or_default().to_string();
+
+                let body = if head.starts_with("GET /api/v1/eth1/publicKeys") {
+                    format!("[\"{}\"]", identifier)
+                } else if head.starts_with(&format!("POST /api/v1/eth1/sign/{}", identifier)) {
+                    let body: serde_json::Value =
+                        serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+                    let data: Bytes = serde_json::from_value(body["data"].clone()).unwrap();
+                    let signature = key.sign_hash(H256::from(keccak256(&data))).unwrap();
+                    format!("0x{}", signature)
+                } else {
+                    String::new()
+                };
+
+                let status = if body.is_empty() { "404 Not Found" } else { "200 OK" };
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        url
+    }
+
+    // Head and body of one request, by its content-length
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let read = stream.read(&mut chunk).await.unwrap();
+            request.extend_from_slice(&chunk[..read]);
+
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= length || read == 0 {
+                    return text;
+                }
+            }
+        }
+    }
+
+    fn eip1559_tx() -> TypedTransaction {
+        Eip1559TransactionRequest::new()
+            .to(Address::from_low_u64_be(0xbeef))
+            .value(1_000)
+            .nonce(3)
+            .gas(100_000)
+            .max_fee_per_gas(30_000_000_000u64)
+            .max_priority_fee_per_gas(1_000_000_000u64)
+            .data(vec![0xde, 0xad])
+            .into()
+    }
+
+    #[tokio::test]
+    async fn remote_signer_signs_like_the_local_key() {
+        let local = KEY.parse::<LocalWallet>().unwrap().with_chain_id(5u64);
+        let url = signer_server(local.clone()).await;
+
+        let source = SignerSource::Remote {
+            url: url.clone(),
+            address: local.address(),
+        };
+        let remote = source.load(5, &EgressClient::new(["127.0.0.1"])).await.unwrap();
+        assert!(matches!(remote, BotSigner::Remote(_)));
+        assert_eq!(remote.address(), local.address());
+
+        let tx = eip1559_tx();
+        assert_eq!(
+            remote.sign_transaction(&tx).await.unwrap(),
+            local.sign_transaction(&tx).await.unwrap()
+        );
+        assert_eq!(
+            remote.sign_message("relay request").await.unwrap(),
+            local.sign_message("relay request").await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_signer_needs_the_configured_key() {
+        let url = signer_server(KEY.parse::<LocalWallet>().unwrap()).await;
+        let other = LocalWallet::new(&mut rand::thread_rng()).address();
+
+        let egress = EgressClient::new(["127.0.0.1"]);
+        let err = RemoteSigner::connect(egress.clone(), &url, other).await.unwrap_err();
+        assert!(matches!(err, SignerError::UnknownKey(address) if address == other));
+
+        // the signer host has to be allowlisted like any other
+        let err = RemoteSigner::connect(EgressClient::new(["relay.flashbots.net"]), &url, other)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SignerError::Egress(EgressError::HostNotAllowed(_))));
+    }
+
+    #[tokio::test]
+    async fn unlocks_keystore_with_passphrase_file() {
+        let dir = std::env::temp_dir().join(format!("keystore_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = hex::decode(&KEY[2..]).unwrap();
+        let (wallet, name) =
+            LocalWallet::encrypt_keystore(&dir, &mut rand::thread_rng(), key, "hunter2", None).unwrap();
+        let passphrase_file = dir.join("passphrase");
+        fs::write(&passphrase_file, "hunter2\n").unwrap();
+
+        let source = SignerSource::Keystore {
+            path: dir.join(name),
+            passphrase_file: Some(passphrase_file.clone()),
+        };
+        let unlocked = source.load(1, &EgressClient::new(Vec::<String>::new())).await;
+
+        fs::write(&passphrase_file, "wrong").unwrap();
+        let locked = source.load(1, &EgressClient::new(Vec::<String>::new())).await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(unlocked.unwrap().address(), wallet.address());
+        assert!(matches!(locked, Err(SignerError::Wallet(_))));
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, RwLock};
+
+use ethers::abi::{self, ParamType};
+use ethers::prelude::*;
+use revm::db::{CacheDB, EthersDB};
+use revm::primitives::{
+    AccountInfo, Address as rAddress, BlockEnv, Bytecode, Bytes as rBytes, ExecutionResult, TransactTo,
+    TxEnv, B256, U256 as rU256,
+};
+use revm::{DatabaseRef, Evm};
+use thiserror::Error;
+
+use crate::states::block_state::BlockInfo;
+
+/// Gas limit an arbitrage is simulated with, its gas used is what goes in the bundle
+pub const SIMULATION_GAS_LIMIT: u64 = 2_000_000;
+
+// selector of `Error(string)`, what `require` and `revert("..")` return
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+#[derive(Error, Debug)]
+pub enum SimulationError {
+    #[error("No state to simulate on at block {0}")]
+    MissingState(U64),
+    #[error("Transaction {index} of the bundle is invalid: {reason}")]
+    InvalidTransaction { index: usize, reason: String },
+}
+
+/// Memoizes what the EVM reads from `db`, the state of one block is shared by every bundle
+/// simulated on top of it
+#[derive(Debug)]
+pub struct StateCache<DB> {
+    db: DB,
+    accounts: RwLock<HashMap<rAddress, Option<AccountInfo>>>,
+    storage: RwLock<HashMap<(rAddress, rU256), rU256>>,
+    code: RwLock<HashMap<B256, Bytecode>>,
+    block_hashes: RwLock<HashMap<rU256, B256>>,
+}
+
+impl<DB: DatabaseRef> StateCache<DB> {
+    pub fn new(db: DB) -> Self {
+        Self {
+            db,
+            accounts: RwLock::new(HashMap::new()),
+            storage: RwLock::new(HashMap::new()),
+            code: RwLock::new(HashMap::new()),
+            block_hashes: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<DB: DatabaseRef> DatabaseRef for StateCache<DB> {
+    type Error = DB::Error;
+
+    fn basic_ref(&self, address: rAddress) -> Result<Option<AccountInfo>, Self::Error> {
+        cached(&self.accounts, address, || {
+            let info = self.db.basic_ref(address)?;
+            // providers hand the code out with the account, it can't be fetched by hash later
+            if let Some(code) = info.as_ref().and_then(|info| info.code.clone()) {
+                write(&self.code).insert(code.hash_slow(), code);
+            }
+            Ok(info)
+        })
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        cached(&self.code, code_hash, || self.db.code_by_hash_ref(code_hash))
+    }
+
+    fn storage_ref(&self, address: rAddress, index: rU256) -> Result<rU256, Self::Error> {
+        cached(&self.storage, (address, index), || self.db.storage_ref(address, index))
+    }
+
+    fn block_hash_ref(&self, number: rU256) -> Result<B256, Self::Error> {
+        cached(&self.block_hashes, number, || self.db.block_hash_ref(number))
+    }
+}
+
+/// Block state read from the provider as the EVM touches it
+pub type ProviderState = StateCache<EthersDB<Provider<Ws>>>;
+
+// the block the cached state is at
+type BlockState = (U64, Arc<ProviderState>);
+
+/// The provider-fed state bundles are simulated on, kept for one block at a time
+#[derive(Debug, Clone, Default)]
+pub struct SimulationCache {
+    latest: Arc<Mutex<Option<BlockState>>>,
+}
+
+impl SimulationCache {
+    /// State at the end of `block`, the cache of an older block is dropped
+    pub fn at(&self, client: &Arc<Provider<Ws>>, block: U64) -> Result<Arc<ProviderState>, SimulationError> {
+        let mut latest = self.latest.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((number, state)) = latest.as_ref() {
+            if *number == block {
+                return Ok(state.clone());
+            }
+        }
+
+        let db = EthersDB::new(client.clone(), Some(BlockId::from(block)))
+            .ok_or(SimulationError::MissingState(block))?;
+        let state = Arc::new(StateCache::new(db));
+        *latest = Some((block, state.clone()));
+        Ok(state)
+    }
+}
+
+/// An ERC20 balance followed across a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackedBalance {
+    pub token: Address,
+    pub holder: Address,
+}
+
+/// A bundle transaction as the EVM runs it, nonces are not checked
+#[derive(Debug, Clone)]
+pub struct BundleTx {
+    pub from: Address,
+    pub to: Option<Address>,
+    pub value: U256,
+    pub data: Bytes,
+    pub gas_limit: u64,
+    /// Max fee per gas, or the gas price of a legacy transaction
+    pub gas_price: U256,
+    pub priority_fee: Option<U256>,
+    pub access_list: Vec<(Address, Vec<H256>)>,
+    /// The balance whose change is reported as the transaction's profit
+    pub profit: Option<TrackedBalance>,
+    /// Credit the sender its gas first, for arbitrages simulated before a wallet is claimed
+    pub fund_gas: bool,
+}
+
+impl BundleTx {
+    /// An arbitrage paying the base fee, its profit is the `executor`'s change of `base_token`
+    pub fn arbitrage(from: Address, executor: Address, data: Bytes, base_token: Address, base_fee: U256) -> Self {
+        Self {
+            from,
+            to: Some(executor),
+            value: U256::zero(),
+            data,
+            gas_limit: SIMULATION_GAS_LIMIT,
+            gas_price: base_fee,
+            priority_fee: Some(U256::zero()),
+            access_list: Vec::new(),
+            profit: Some(TrackedBalance {
+                token: base_token,
+                holder: executor,
+            }),
+            fund_gas: true,
+        }
+    }
+}
+
+impl From<&Transaction> for BundleTx {
+    fn from(tx: &Transaction) -> Self {
+        Self {
+            from: tx.from,
+            to: tx.to,
+            value: tx.value,
+            data: tx.input.clone(),
+            gas_limit: tx.gas.min(U256::from(u64::MAX)).as_u64(),
+            gas_price: tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default(),
+            priority_fee: tx.max_priority_fee_per_gas,
+            access_list: tx
+                .access_list
+                .iter()
+                .flat_map(|list| list.0.iter())
+                .map(|item| (item.address, item.storage_keys.clone()))
+                .collect(),
+            profit: None,
+            fund_gas: false,
+        }
+    }
+}
+
+/// What a bundle transaction did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxOutcome {
+    pub gas_used: u64,
+    /// Why the transaction reverted or halted, `None` when it succeeded
+    pub revert_reason: Option<String>,
+    /// Change of the tracked balance, zero when nothing is tracked
+    pub profit: I256,
+}
+
+impl TxOutcome {
+    pub fn succeeded(&self) -> bool {
+        self.revert_reason.is_none()
+    }
+}
+
+/// Run `txs` in order on top of `state` as the transactions of `block`
+///
+/// Each transaction sees the changes of the ones before it, `state` itself is never written.
+/// A transaction that reverts is reported in its outcome, one the block could not include
+/// (fee under the base fee, balance short of its gas unless `fund_gas`) fails the whole
+/// simulation.
+pub fn simulate_bundle<DB>(state: DB, block: &BlockInfo, txs: &[BundleTx]) -> Result<Vec<TxOutcome>, SimulationError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let mut db = CacheDB::new(state);
+    let block_env = BlockEnv {
+        number: to_revm_u256(U256::from(block.number.as_u64())),
+        timestamp: to_revm_u256(block.timestamp),
+        basefee: to_revm_u256(block.base_fee),
+        ..Default::default()
+    };
+    let mut outcomes = Vec::with_capacity(txs.len());
+
+    for (index, tx) in txs.iter().enumerate() {
+        let invalid = |reason: String| SimulationError::InvalidTransaction { index, reason };
+
+        let before = match tx.profit {
+            Some(tracked) => balance_of(&mut db, &block_env, tracked).map_err(invalid)?,
+            None => U256::zero(),
+        };
+
+        if tx.fund_gas {
+            let caller = to_revm_address(tx.from);
+            let mut info = db
+                .basic_ref(caller)
+                .map_err(|e| invalid(format!("{:?}", e)))?
+                .unwrap_or_default();
+            let gas_cost = U256::from(tx.gas_limit) * tx.gas_price;
+            info.balance = info.balance.saturating_add(to_revm_u256(gas_cost));
+            db.insert_account_info(caller, info);
+        }
+
+        let result = Evm::builder()
+            .with_db(&mut db)
+            .with_block_env(block_env.clone())
+            .with_tx_env(tx_env(tx))
+            .build()
+            .transact_commit()
+            .map_err(|e| invalid(format!("{:?}", e)))?;
+
+        let (gas_used, revert_reason) = match result {
+            ExecutionResult::Success { gas_used, .. } => (gas_used, None),
+            ExecutionResult::Revert { gas_used, output } => (gas_used, Some(revert_reason(&output))),
+            ExecutionResult::Halt { reason, gas_used } => (gas_used, Some(format!("{:?}", reason))),
+        };
+
+        let profit = match tx.profit {
+            Some(tracked) => {
+                let after = balance_of(&mut db, &block_env, tracked).map_err(invalid)?;
+                I256::from_raw(after) - I256::from_raw(before)
+            }
+            None => I256::zero(),
+        };
+
+        outcomes.push(TxOutcome {
+            gas_used,
+            revert_reason,
+            profit,
+        });
+    }
+
+    Ok(outcomes)
+}
+
+// `balanceOf` as a free call, nothing it touches is committed
+fn balance_of<DB>(db: &mut CacheDB<DB>, block_env: &BlockEnv, tracked: TrackedBalance) -> Result<U256, String>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let data = [
+        &ethers::utils::id("balanceOf(address)")[..],
+        &abi::encode(&[abi::Token::Address(tracked.holder)]),
+    ]
+    .concat();
+    let block_env = BlockEnv {
+        basefee: rU256::ZERO,
+        ..block_env.clone()
+    };
+    let tx_env = TxEnv {
+        caller: rAddress::ZERO,
+        gas_limit: SIMULATION_GAS_LIMIT,
+        gas_price: rU256::ZERO,
+        transact_to: TransactTo::Call(to_revm_address(tracked.token)),
+        data: data.into(),
+        ..Default::default()
+    };
+
+    let result = Evm::builder()
+        .with_db(db)
+        .with_block_env(block_env)
+        .with_tx_env(tx_env)
+        .build()
+        .transact()
+        .map_err(|e| format!("{:?}", e))?
+        .result;
+
+    match result {
+        ExecutionResult::Success { output, .. } if output.data().len() >= 32 => {
+            Ok(U256::from_big_endian(&output.data()[..32]))
+        }
+        _ => Err(format!("balanceOf of {:?} failed", tracked.token)),
+    }
+}
+
+fn tx_env(tx: &BundleTx) -> TxEnv {
+    TxEnv {
+        caller: to_revm_address(tx.from),
+        gas_limit: tx.gas_limit,
+        gas_price: to_revm_u256(tx.gas_price),
+        gas_priority_fee: tx.priority_fee.map(to_revm_u256),
+        transact_to: match tx.to {
+            Some(to) => TransactTo::Call(to_revm_address(to)),
+            None => TransactTo::create(),
+        },
+        value: to_revm_u256(tx.value),
+        data: rBytes::from(tx.data.to_vec()),
+        nonce: None,
+        chain_id: None,
+        access_list: tx
+            .access_list
+            .iter()
+            .map(|(address, keys)| {
+                let keys = keys.iter().map(|key| rU256::from_be_bytes(key.0)).collect();
+                (to_revm_address(*address), keys)
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Whether a revert comes from a token transfer, the way a changed tax makes a cycle fail
+///
+/// A tax taken on transfer leaves the pair short of its input (`K`), a blocked transfer fails
+/// in the token or the transfer helper.
+pub fn is_transfer_revert(reason: &str) -> bool {
+    let reason = reason.to_ascii_uppercase();
+    reason.ends_with(": K") || reason.contains("TRANSFER") || reason.contains("INSUFFICIENT_INPUT_AMOUNT")
+}
+
+/// The message of an `Error(string)` revert, the raw data otherwise
+pub fn revert_reason(output: &[u8]) -> String {
+    if output.is_empty() {
+        return "reverted without a reason".to_string();
+    }
+
+    if output.starts_with(&ERROR_SELECTOR) {
+        if let Ok(tokens) = abi::decode(&[ParamType::String], &output[4..]) {
+            if let Some(abi::Token::String(reason)) = tokens.into_iter().next() {
+                return reason;
+            }
+        }
+    }
+    format!("0x{}", hex::encode(output))
+}
+
+fn to_revm_address(address: Address) -> rAddress {
+    rAddress::from(address.0)
+}
+
+fn to_revm_u256(value: U256) -> rU256 {
+    rU256::from_limbs(value.0)
+}
+
+fn cached<K: Eq + Hash, V: Clone, E>(
+    map: &RwLock<HashMap<K, V>>,
+    key: K,
+    fetch: impl FnOnce() -> Result<V, E>,
+) -> Result<V, E> {
+    if let Some(value) = map.read().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        return Ok(value.clone());
+    }
+
+    // fetched without the lock held, a racing read of the same key only costs a request
+    let value = fetch()?;
+    write(map).insert(key, value.clone());
+    Ok(value)
+}
+
+fn write<K, V>(map: &RwLock<HashMap<K, V>>) -> std::sync::RwLockWriteGuard<'_, HashMap<K, V>> {
+    map.write().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::db::InMemoryDB;
+
+    // Token whose `balanceOf` returns slot 0 for anyone. A 1 byte call marks the victim as
+    // run (slot 1), a 32 byte call adds its amount to slot 0 and reverts with "no victim"
+    // unless the victim ran first.
+    const TOKEN_CODE: &str = "3660241460775736600114608357600154606b577f08c379a000000000000000000000000000000000000000000000000000000000600052602060045260096024527f6e6f2076696374696d000000000000000000000000000000000000000000000060445260646000fd5b60003560005401600055005b60005460005260206000f35b600160015500";
+
+    fn token() -> Address {
+        Address::from_low_u64_be(0x70)
+    }
+
+    fn searcher() -> Address {
+        Address::from_low_u64_be(0x5e)
+    }
+
+    fn block() -> BlockInfo {
+        BlockInfo::new(U64::from(100), U256::from(1_700_000_000u64), U256::from(10_000_000_000u64))
+    }
+
+    // the token and two funded senders, nothing is fetched from a provider
+    fn seeded_state() -> StateCache<InMemoryDB> {
+        let mut db = InMemoryDB::default();
+        let code = Bytecode::new_raw(hex::decode(TOKEN_CODE).unwrap().into());
+        db.insert_account_info(
+            to_revm_address(token()),
+            AccountInfo::new(rU256::ZERO, 1, code.hash_slow(), code),
+        );
+        for sender in [searcher(), victim().from] {
+            db.insert_account_info(
+                to_revm_address(sender),
+                AccountInfo::from_balance(to_revm_u256(U256::exp10(20))),
+            );
+        }
+        db.insert_account_storage(to_revm_address(token()), rU256::ZERO, rU256::from(1_000))
+            .unwrap();
+        StateCache::new(db)
+    }
+
+    fn victim() -> Transaction {
+        Transaction {
+            from: Address::from_low_u64_be(0x71c),
+            to: Some(token()),
+            input: Bytes::from(vec![1]),
+            gas: U256::from(100_000),
+            max_fee_per_gas: Some(U256::from(20_000_000_000u64)),
+            max_priority_fee_per_gas: Some(U256::from(1_000_000_000)),
+            ..Default::default()
+        }
+    }
+
+    fn arbitrage(amount: u64) -> BundleTx {
+        let data = Bytes::from(abi::encode(&[abi::Token::Uint(U256::from(amount))]));
+        let mut tx = BundleTx::arbitrage(searcher(), token(), data, token(), block().base_fee);
+        // the token holds its own balance in this state
+        tx.profit = Some(TrackedBalance {
+            token: token(),
+            holder: token(),
+        });
+        tx
+    }
+
+    #[test]
+    fn arbitrage_runs_after_the_victim() {
+        let state = seeded_state();
+        let bundle = [BundleTx::from(&victim()), arbitrage(250)];
+
+        let outcomes = simulate_bundle(&state, &block(), &bundle).unwrap();
+
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(TxOutcome::succeeded));
+        assert_eq!(outcomes[0].profit, I256::zero());
+        assert_eq!(outcomes[1].profit, I256::from(250));
+        assert!(outcomes[1].gas_used > 21_000 && outcomes[1].gas_used < SIMULATION_GAS_LIMIT);
+
+        // the shared state is only read, the same bundle simulates the same again
+        assert_eq!(simulate_bundle(&state, &block(), &bundle).unwrap(), outcomes);
+    }
+
+    #[test]
+    fn reports_the_revert_reason() {
+        let state = seeded_state();
+
+        let outcomes = simulate_bundle(&state, &block(), &[arbitrage(250)]).unwrap();
+        assert_eq!(outcomes[0].revert_reason.as_deref(), Some("no victim"));
+        assert_eq!(outcomes[0].profit, I256::zero());
+        assert!(outcomes[0].gas_used > 21_000);
+
+        // a victim paying under the base fee can't be in the block at all
+        let mut cheap = victim();
+        cheap.max_fee_per_gas = Some(U256::from(1));
+        let error = simulate_bundle(&state, &block(), &[BundleTx::from(&cheap), arbitrage(250)]).unwrap_err();
+        assert!(matches!(error, SimulationError::InvalidTransaction { index: 0, .. }));
+    }
+
+    #[test]
+    fn arbitrage_needs_no_funded_sender() {
+        let state = seeded_state();
+        let mut unfunded = arbitrage(250);
+        unfunded.from = Address::from_low_u64_be(0x99);
+
+        let outcomes = simulate_bundle(&state, &block(), &[BundleTx::from(&victim()), unfunded.clone()]).unwrap();
+        assert_eq!(outcomes[1].profit, I256::from(250));
+
+        // without the credit the sender can't pay for its gas
+        unfunded.fund_gas = false;
+        let error = simulate_bundle(&state, &block(), &[BundleTx::from(&victim()), unfunded]).unwrap_err();
+        assert!(matches!(error, SimulationError::InvalidTransaction { index: 1, .. }));
+    }
+
+    #[test]
+    fn decodes_revert_data() {
+        let mut output = ERROR_SELECTOR.to_vec();
+        output.extend(abi::encode(&[abi::Token::String("K".to_string())]));
+        assert_eq!(revert_reason(&output), "K");
+        assert_eq!(revert_reason(&[]), "reverted without a reason");
+        assert_eq!(revert_reason(&[0xde, 0xad]), "0xdead");
+    }
+
+    #[test]
+    fn attributes_reverts_to_transfers() {
+        assert!(is_transfer_revert("UniswapV2: K"));
+        assert!(is_transfer_revert("TransferHelper: TRANSFER_FAILED"));
+        assert!(is_transfer_revert("ERC20: transfer amount exceeds balance"));
+        assert!(is_transfer_revert("UniswapV2: INSUFFICIENT_INPUT_AMOUNT"));
+
+        assert!(!is_transfer_revert("UniswapV2: INSUFFICIENT_OUTPUT_AMOUNT"));
+        assert!(!is_transfer_revert("no victim"));
+        assert!(!is_transfer_revert("OutOfGas"));
+    }
+}
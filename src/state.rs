@@ -1,15 +1,18 @@
 use std::{
-    cell::RefCell,
-    collections::{HashMap, HashSet},
+    collections::HashMap,
+    sync::{Arc, RwLock},
 };
 
+use crate::chunked_map::ChunkedMap;
+use crate::contract_modules::uniswap_v2::checkpoint::Storage;
 use crate::contract_modules::uniswap_v2::types::UniV2Pool;
+use crate::cycles::{self, CycleSearch};
 use crate::helpers;
 use crate::constants::WETH;
 use ethers::prelude::*;
 use log::*;
 use serde::{Serialize, Deserialize};
-use tokio::sync::MutexGuard;
+use tokio::sync::{Mutex, MutexGuard};
 
 pub type Cycle = Vec<IndexedPair>;
 
@@ -28,17 +31,58 @@ pub struct PointerIndexedCycle {
     pub cycle: Vec<IndexedPair>,
 }
 
+/// One version of the pools, reserves and cycles
+///
+/// Cloning is cheap: the maps and the reserves are split in chunks behind `Arc`s, a write only
+/// copies the chunk it lands in. Readers hold a snapshot from
+/// `SharedState` while writers publish new versions next to it.
+#[derive(Clone)]
 pub struct State {
     /// For indexed pointer to address
-    pub index_mapping: HashMap<usize, Address>,
+    pub index_mapping: ChunkedMap<usize, Address>,
     /// For address to indexed pointer
-    pub address_mapping: HashMap<Address, usize>,
-    /// Pointer to the pool
-    pub pairs_mapping: HashMap<usize, RefCell<UniV2Pool>>,
+    pub address_mapping: ChunkedMap<Address, usize>,
+    // Pointer to the pool, its reserves live in `reserves`
+    pairs_mapping: ChunkedMap<usize, UniV2Pool>,
     /// For easy access at pending state
-    pub cycles_mapping: HashMap<Address, Vec<Cycle>>,
-    // Real state of reserves to re apply after calc
-    real_reserve_state: RefCell<HashMap<usize, [U256; 2]>>,
+    pub cycles_mapping: ChunkedMap<Address, Vec<Cycle>>,
+    // Pairs touching each token, to extend cycles when a pair is added
+    pairs_by_token: ChunkedMap<usize, Vec<IndexedPair>>,
+    weth_index: usize,
+    // pointers of the configured start tokens that have pairs, in configured order
+    start_tokens: Vec<usize>,
+    cycle_search: CycleSearch,
+    reserves: Reserves,
+    /// Last block the reserves are synced to
+    pub synced_block: U64,
+    /// Bumped on every publish
+    pub version: u64,
+}
+
+// Pair reserves by pointer, in chunks so a clone shares everything it does not write
+const RESERVE_CHUNK: usize = 256;
+
+#[derive(Clone, Default)]
+struct Reserves {
+    chunks: Vec<Arc<[[U256; 2]; RESERVE_CHUNK]>>,
+}
+
+impl Reserves {
+    fn get(&self, pointer: usize) -> [U256; 2] {
+        self.chunks
+            .get(pointer / RESERVE_CHUNK)
+            .map(|chunk| chunk[pointer % RESERVE_CHUNK])
+            .unwrap_or_default()
+    }
+
+    fn set(&mut self, pointer: usize, reserves: [U256; 2]) {
+        let index = pointer / RESERVE_CHUNK;
+        while self.chunks.len() <= index {
+            self.chunks.push(Arc::new([[U256::zero(); 2]; RESERVE_CHUNK]));
+        }
+
+        Arc::make_mut(&mut self.chunks[index])[pointer % RESERVE_CHUNK] = reserves;
+    }
 }
 
 // Potential future state update
@@ -51,7 +95,7 @@ pub struct StateUpdateInternal {
 
 impl State {
     /// Initialize state
-    pub fn new_state(pairs: &[UniV2Pool]) -> Self {
+    pub fn new_state(pairs: &[UniV2Pool], synced_block: U64, cycle_search: CycleSearch) -> Self {
         let mut address_mapping = HashMap::new();
         let mut index_mapping = HashMap::new();
         let mut pairs_mapping = HashMap::new();
@@ -76,35 +120,41 @@ impl State {
             }
         }
 
-        let mut indexed_pairs = Vec::new();
-        for pair in pairs {
+        let mut pairs_by_token: HashMap<usize, Vec<IndexedPair>> = HashMap::new();
+        for pair in pairs.iter() {
             let indexed_pair = IndexedPair {
                 address: *address_mapping.get(&pair.address).unwrap(),
                 token0: *address_mapping.get(&pair.token0).unwrap(),
                 token1: *address_mapping.get(&pair.token1).unwrap(),
             };
 
-            indexed_pairs.push(indexed_pair);
-            pairs_mapping.insert(
-                *address_mapping.get(&pair.address).unwrap(),
-                RefCell::new(pair.clone()),
-            );
+            // pools that failed the tax check are kept for the checkpoint but never traded
+            if pair.tradeable {
+                pairs_by_token.entry(indexed_pair.token0).or_default().push(indexed_pair);
+                pairs_by_token.entry(indexed_pair.token1).or_default().push(indexed_pair);
+            }
+            pairs_mapping.insert(indexed_pair.address, pair.clone());
         }
 
+        let address_mapping: ChunkedMap<Address, usize> = address_mapping.into_iter().collect();
         let weth_index = *address_mapping.get(&helpers::address(WETH)).unwrap();
+        let start_tokens = start_pointers(&cycle_search, &address_mapping);
         let now = std::time::Instant::now();
 
-        let cycles = Self::find_cycles(
-            &indexed_pairs,
-            weth_index,
-            weth_index,
-            3, // should be enough
-            &Vec::new(),
-            &mut Vec::new(),
-            &mut HashSet::new(),
+        let cycles = cycles::enumerate_cycles(
+            &pairs_by_token,
+            &start_tokens,
+            cycle_search.max_hops,
+            |pair| {
+                let pool: &UniV2Pool = &pairs_mapping[&pair.address];
+                is_liquid([pool.reserve0, pool.reserve1], cycle_search.min_reserve)
+            },
         );
 
         info!("Number of cycles: {:?}", cycles.len());
+        for (hops, count) in cycles::count_by_length(&cycles) {
+            info!("  {} hops: {}", hops, count);
+        }
         info!("Time took for finding all cycles: {:?}", now.elapsed());
 
         let mut cycles_mapping = HashMap::new();
@@ -118,102 +168,689 @@ impl State {
             }
         }
 
-        let real_reserve_state = RefCell::new(HashMap::new());
+        let mut reserves = Reserves::default();
+        for (pointer, pair) in pairs_mapping.iter() {
+            reserves.set(*pointer, [pair.reserve0, pair.reserve1]);
+        }
 
         Self {
-            index_mapping,
+            index_mapping: index_mapping.into_iter().collect(),
             address_mapping,
-            pairs_mapping,
-            cycles_mapping,
-            real_reserve_state,
-        }
-    }
-
-    /// Find cycles using DFS
-    fn find_cycles(
-        pairs: &[IndexedPair],
-        token_in: usize,
-        token_out: usize,
-        max_hops: i32,
-        current_pairs: &Vec<IndexedPair>,
-        circles: &mut Vec<Cycle>,
-        seen: &mut HashSet<usize>,
-    ) -> Vec<Cycle> {
-        let mut circles_copy: Vec<Cycle> = circles.clone();
-
-        for pair in pairs {
-            if seen.contains(&pair.address) {
-                continue;
+            pairs_mapping: pairs_mapping.into_iter().collect(),
+            cycles_mapping: cycles_mapping.into_iter().collect(),
+            pairs_by_token: pairs_by_token.into_iter().collect(),
+            weth_index,
+            start_tokens,
+            cycle_search,
+            reserves,
+            synced_block,
+            version: 0,
+        }
+    }
+
+    /// The pool at `pointer` with its current reserves
+    pub fn pool(&self, pointer: usize) -> Option<UniV2Pool> {
+        let mut pool = self.pairs_mapping.get(&pointer)?.clone();
+        [pool.reserve0, pool.reserve1] = self.reserves.get(pointer);
+        Some(pool)
+    }
+
+    pub fn contains_pair(&self, pointer: usize) -> bool {
+        self.pairs_mapping.contains_key(&pointer)
+    }
+
+    pub fn reserves(&self, pointer: usize) -> Option<[U256; 2]> {
+        self.pairs_mapping
+            .contains_key(&pointer)
+            .then(|| self.reserves.get(pointer))
+    }
+
+    /// Returns false if `pointer` is not a pair
+    pub fn set_reserves(&mut self, pointer: usize, reserve0: U256, reserve1: U256) -> bool {
+        if !self.pairs_mapping.contains_key(&pointer) {
+            return false;
+        }
+
+        self.reserves.set(pointer, [reserve0, reserve1]);
+        true
+    }
+
+    /// The pools in the graph that trade one of `tokens`, with their current reserves
+    pub fn pools_of(&self, tokens: &[Address]) -> Vec<UniV2Pool> {
+        let mut pointers: Vec<usize> = tokens
+            .iter()
+            .filter_map(|token| self.pairs_by_token.get(self.address_mapping.get(token)?))
+            .flatten()
+            .map(|pair| pair.address)
+            .collect();
+        pointers.sort();
+        pointers.dedup();
+
+        pointers.into_iter().filter_map(|pointer| self.pool(pointer)).collect()
+    }
+
+    /// Replace the taxes and tradeability of a known pool, its reserves are left alone
+    ///
+    /// Returns false if the pool is unknown. Cycles through an untradeable pool are skipped
+    /// when evaluated.
+    pub fn set_taxes(&mut self, pool: &UniV2Pool) -> bool {
+        let pointer = match self.address_mapping.get(&pool.address) {
+            Some(pointer) => *pointer,
+            None => return false,
+        };
+
+        match self.pairs_mapping.get_mut(&pointer) {
+            Some(known) => {
+                known.fees0 = pool.fees0;
+                known.fees1 = pool.fees1;
+                known.tradeable = pool.tradeable;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Every known pool, untradeable ones included, and the block their reserves are valid at
+    pub fn checkpoint(&self) -> Storage {
+        let mut pointers: Vec<usize> = self.pairs_mapping.keys().copied().collect();
+        pointers.sort();
+
+        let pools = pointers
+            .into_iter()
+            .filter_map(|pointer| self.pool(pointer))
+            .collect();
+
+        Storage::new(pools, U256::from(self.synced_block.as_u64()))
+    }
+
+    /// Insert a pair discovered at runtime and index only the cycles that run through it
+    ///
+    /// Returns the number of new cycles, `None` if the pair is already known. An untradeable
+    /// pair is only kept for the checkpoint and gets no cycles.
+    pub fn add_pair(&mut self, pair: UniV2Pool) -> Option<usize> {
+        if self.address_mapping.contains_key(&pair.address) {
+            return None;
+        }
+
+        let tradeable = pair.tradeable;
+        let indexed_pair = IndexedPair {
+            address: self.insert_address(pair.address),
+            token0: self.insert_address(pair.token0),
+            token1: self.insert_address(pair.token1),
+        };
+        self.reserves
+            .set(indexed_pair.address, [pair.reserve0, pair.reserve1]);
+        self.pairs_mapping.insert(indexed_pair.address, pair);
+
+        if !tradeable {
+            return Some(0);
+        }
+        Some(self.index_cycles(indexed_pair))
+    }
+
+    /// Trade a known untradeable pair with the taxes of `pair` and index the cycles through it
+    ///
+    /// Returns the number of new cycles, `None` if the pair is unknown or already traded.
+    pub fn enable_pair(&mut self, pair: &UniV2Pool) -> Option<usize> {
+        let indexed_pair = IndexedPair {
+            address: *self.address_mapping.get(&pair.address)?,
+            token0: *self.address_mapping.get(&pair.token0)?,
+            token1: *self.address_mapping.get(&pair.token1)?,
+        };
+        let in_graph = self
+            .pairs_by_token
+            .get(&indexed_pair.token0)
+            .is_some_and(|pairs| pairs.iter().any(|known| known.address == indexed_pair.address));
+        if in_graph || !pair.tradeable || !self.set_taxes(pair) {
+            return None;
+        }
+
+        Some(self.index_cycles(indexed_pair))
+    }
+
+    // Add a pair to the adjacency and index the cycles through it, returns how many
+    fn index_cycles(&mut self, indexed_pair: IndexedPair) -> usize {
+        // searched before the pair joins the adjacency so no path reuses it
+        let cycles = self.find_cycles_through(&indexed_pair);
+
+        self.pairs_by_token
+            .get_or_default(indexed_pair.token0)
+            .push(indexed_pair);
+        self.pairs_by_token
+            .get_or_default(indexed_pair.token1)
+            .push(indexed_pair);
+
+        for cycle in cycles.iter() {
+            for indexed_pair in cycle {
+                self.cycles_mapping
+                    .get_or_default(self.index_mapping[&indexed_pair.address])
+                    .push(cycle.clone());
             }
+        }
+
+        cycles.len()
+    }
 
-            let temp_out: usize;
-            if token_in == pair.token0 {
-                temp_out = pair.token1;
-            } else if token_in == pair.token1 {
-                temp_out = pair.token0;
-            } else {
+    fn insert_address(&mut self, address: Address) -> usize {
+        if let Some(index) = self.address_mapping.get(&address) {
+            return *index;
+        }
+
+        let index = self.index_mapping.len();
+        self.index_mapping.insert(index, address);
+        self.address_mapping.insert(address, index);
+
+        if self.cycle_search.start_tokens.contains(&address) {
+            self.start_tokens = start_pointers(&self.cycle_search, &self.address_mapping);
+        }
+        index
+    }
+
+    /// The start token a cycle is rooted at, shared by its first and last pair
+    pub fn cycle_root(&self, cycle: &[IndexedPair]) -> Option<usize> {
+        let (first, last) = (cycle.first()?, cycle.last()?);
+        let touches = |pair: &IndexedPair, token: usize| pair.token0 == token || pair.token1 == token;
+
+        self.start_tokens
+            .iter()
+            .copied()
+            .find(|token| touches(first, *token) && touches(last, *token))
+    }
+
+    /// Every cycle of at most `max_hops` that uses `pair`, rooted as `enumerate_cycles` roots it
+    ///
+    /// A cycle through the pair is a path root -> tokenX, the pair, and a path tokenY -> root,
+    /// so only the short paths from the pair's tokens back to each start token are searched
+    fn find_cycles_through(&self, pair: &IndexedPair) -> Vec<Cycle> {
+        if !self.is_usable(pair) {
+            return Vec::new();
+        }
+
+        let max_len = self.cycle_search.max_hops - 1;
+        let mut cycles = Vec::new();
+
+        for (position, root) in self.start_tokens.iter().enumerate() {
+            // cycles through an earlier start token are rooted there
+            let excluded = &self.start_tokens[..position];
+            if excluded.contains(&pair.token0) || excluded.contains(&pair.token1) {
                 continue;
             }
 
-            let mut new_seen = seen.clone();
-            new_seen.insert(pair.address);
-
-            if temp_out == token_out {
-                let mut new_cycle = current_pairs.clone();
-                new_cycle.push(*pair);
-                circles_copy.push(new_cycle);
-            } else if max_hops > 1 {
-                let mut new_pairs: Vec<IndexedPair> = current_pairs.clone();
-                new_pairs.push(*pair);
-                circles_copy = Self::find_cycles(
-                    pairs,
-                    temp_out,
-                    token_out,
-                    max_hops - 1,
-                    &new_pairs,
-                    &mut circles_copy,
-                    &mut new_seen,
-                );
+            let from_token0 = self.paths_to(pair.token0, *root, excluded, max_len);
+            let from_token1 = self.paths_to(pair.token1, *root, excluded, max_len);
+
+            // root .. token0 -> token1 .. root, then the other direction
+            for (heads, tails) in [(&from_token0, &from_token1), (&from_token1, &from_token0)] {
+                for (head, head_tokens) in heads {
+                    for (tail, tail_tokens) in tails {
+                        if head.len() + tail.len() > max_len
+                            || head
+                                .iter()
+                                .any(|p| tail.iter().any(|q| q.address == p.address))
+                            || head_tokens.iter().any(|token| tail_tokens.contains(token))
+                        {
+                            continue;
+                        }
+
+                        let mut cycle: Cycle = head.iter().rev().copied().collect();
+                        cycle.push(*pair);
+                        cycle.extend(tail.iter().copied());
+                        if cycles::is_canonical(&cycle) {
+                            cycles.push(cycle);
+                        }
+                    }
+                }
+            }
+        }
+
+        cycles
+    }
+
+    /// Paths of at most `max_len` pairs from `token` to `root` avoiding `excluded`, with the
+    /// tokens they pass before `root`
+    fn paths_to(
+        &self,
+        token: usize,
+        root: usize,
+        excluded: &[usize],
+        max_len: usize,
+    ) -> Vec<(Vec<IndexedPair>, Vec<usize>)> {
+        let mut paths = Vec::new();
+        let mut search = PathSearch {
+            root,
+            excluded,
+            current: Vec::new(),
+            visited: Vec::new(),
+            paths: &mut paths,
+        };
+        self.extend_paths(token, max_len, &mut search);
+        paths
+    }
+
+    fn extend_paths(&self, token: usize, remaining: usize, search: &mut PathSearch) {
+        if token == search.root {
+            search
+                .paths
+                .push((search.current.clone(), search.visited.clone()));
+            return;
+        }
+        if remaining == 0 || search.visited.contains(&token) || search.excluded.contains(&token) {
+            return;
+        }
+
+        search.visited.push(token);
+        for pair in self.pairs_by_token.get(&token).into_iter().flatten() {
+            if !self.is_usable(pair) || search.current.iter().any(|p| p.address == pair.address) {
+                continue;
             }
+
+            search.current.push(*pair);
+            self.extend_paths(cycles::other_side(pair, token), remaining - 1, search);
+            search.current.pop();
         }
+        search.visited.pop();
+    }
+
+    fn is_usable(&self, pair: &IndexedPair) -> bool {
+        self.reserves(pair.address)
+            .is_some_and(|reserves| is_liquid(reserves, self.cycle_search.min_reserve))
+    }
+}
+
+// A path search from one of a new pair's tokens back to a start token
+struct PathSearch<'a> {
+    root: usize,
+    excluded: &'a [usize],
+    current: Vec<IndexedPair>,
+    visited: Vec<usize>,
+    paths: &'a mut Vec<(Vec<IndexedPair>, Vec<usize>)>,
+}
+
+fn start_pointers(search: &CycleSearch, address_mapping: &ChunkedMap<Address, usize>) -> Vec<usize> {
+    search
+        .start_tokens
+        .iter()
+        .filter_map(|token| address_mapping.get(token).copied())
+        .collect()
+}
+
+// Pairs too shallow to be worth routing through are left out of cycles
+fn is_liquid(reserves: [U256; 2], min_reserve: U256) -> bool {
+    reserves[0] >= min_reserve && reserves[1] >= min_reserve
+}
+
+/// Reserves a pending tx would leave, layered over a snapshot
+///
+/// Lookups of pairs the tx does not touch fall through to `base`, which is never written.
+#[derive(Clone)]
+pub struct PendingState<'a> {
+    base: &'a State,
+    reserves: HashMap<usize, [U256; 2]>,
+}
 
-        circles_copy
+impl<'a> PendingState<'a> {
+    pub fn new(base: &'a State) -> Self {
+        Self {
+            base,
+            reserves: HashMap::new(),
+        }
     }
 
-    pub fn apply_state_temp(state: &mut MutexGuard<State>, updates: Vec<StateUpdateInternal>) {
+    /// Layer pending updates over the base, a later update of a pair wins
+    pub fn apply(&mut self, updates: Vec<StateUpdateInternal>) {
         for update in updates {
-            let pair_address_index: usize = match state.address_mapping.get(&update.address) {
+            let pair_address_index: usize = match self.base.address_mapping.get(&update.address) {
                 Some(d) => *d,
                 None => continue,
             };
 
-            let mut pair = match state.pairs_mapping.get(&pair_address_index) {
-                Some(d) => d.borrow_mut(),
-                None => {
-                    continue;
-                }
-            };
+            if self.base.contains_pair(pair_address_index) {
+                self.reserves
+                    .insert(pair_address_index, [update.reserve0, update.reserve1]);
+            }
+        }
+    }
 
-            state
-                .real_reserve_state
-                .borrow_mut()
-                .insert(pair_address_index, [pair.reserve0, pair.reserve1]);
+    pub fn base(&self) -> &'a State {
+        self.base
+    }
+
+    /// Overwrite a pair's reserves in the overlay, the base is never touched
+    pub fn set_reserves(&mut self, pointer: usize, reserve0: U256, reserve1: U256) {
+        self.reserves.insert(pointer, [reserve0, reserve1]);
+    }
 
-            pair.reserve0 = update.reserve0;
-            pair.reserve1 = update.reserve1
+    pub fn reserves(&self, pointer: usize) -> Option<[U256; 2]> {
+        match self.reserves.get(&pointer) {
+            Some(reserves) => Some(*reserves),
+            None => self.base.reserves(pointer),
         }
     }
 
-    pub fn reset_temp_state(state: &mut MutexGuard<State>) {
-        for (index, update) in state.real_reserve_state.borrow().iter() {
-            let mut pair = match state.pairs_mapping.get(index) {
-                Some(d) => d.borrow_mut(),
-                None => continue,
-            };
-            pair.reserve0 = update[0];
-            pair.reserve1 = update[1];
+    pub fn pool(&self, pointer: usize) -> Option<UniV2Pool> {
+        let mut pool = self.base.pool(pointer)?;
+        if let Some([reserve0, reserve1]) = self.reserves.get(&pointer) {
+            pool.reserve0 = *reserve0;
+            pool.reserve1 = *reserve1;
+        }
+        Some(pool)
+    }
+
+    /// `amount` of `token` priced in WETH at its deepest WETH pair, `None` without one
+    pub fn weth_value(&self, token: Address, amount: U256) -> Option<U256> {
+        let weth = self.base.weth_index;
+        let pointer = *self.base.address_mapping.get(&token)?;
+        if pointer == weth {
+            return Some(amount);
+        }
+
+        let (weth_reserve, token_reserve) = self
+            .base
+            .pairs_by_token
+            .get(&pointer)?
+            .iter()
+            .filter(|pair| cycles::other_side(pair, pointer) == weth)
+            .filter_map(|pair| {
+                let [reserve0, reserve1] = self.reserves(pair.address)?;
+                Some(if pair.token0 == pointer {
+                    (reserve1, reserve0)
+                } else {
+                    (reserve0, reserve1)
+                })
+            })
+            .max()?;
+
+        if token_reserve.is_zero() {
+            return None;
+        }
+        U256::try_from(amount.full_mul(weth_reserve) / U512::from(token_reserve)).ok()
+    }
+}
+
+/// The published `State`
+///
+/// `snapshot` only clones an `Arc`, so readers never wait on a writer doing work. Writers
+/// are serialized, edit a private draft and swap it in with `StateWriter::publish`.
+pub struct SharedState {
+    current: RwLock<Arc<State>>,
+    writer: Mutex<()>,
+}
+
+impl SharedState {
+    pub fn new(state: State) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(state)),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The latest published version
+    pub fn snapshot(&self) -> Arc<State> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Draft of the latest version, other writers wait until it is published or dropped
+    pub async fn writer(&self) -> StateWriter<'_> {
+        let guard = self.writer.lock().await;
+        let draft = (*self.snapshot()).clone();
+
+        StateWriter {
+            shared: self,
+            draft,
+            _guard: guard,
+        }
+    }
+}
+
+/// A draft of the next `State` version, dropping it without `publish` discards the changes
+pub struct StateWriter<'a> {
+    shared: &'a SharedState,
+    draft: State,
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl StateWriter<'_> {
+    pub fn publish(self) {
+        let StateWriter {
+            shared,
+            mut draft,
+            _guard,
+        } = self;
+        draft.version += 1;
+
+        // the writer lock is held until the new version is visible
+        *shared.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(draft);
+    }
+}
+
+impl std::ops::Deref for StateWriter<'_> {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        &self.draft
+    }
+}
+
+impl std::ops::DerefMut for StateWriter<'_> {
+    fn deref_mut(&mut self) -> &mut State {
+        &mut self.draft
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(address: u64, token0: Address, token1: Address) -> UniV2Pool {
+        UniV2Pool {
+            address: Address::from_low_u64_be(address),
+            token0,
+            token1,
+            reserve0: U256::exp10(24),
+            reserve1: U256::exp10(24),
+            router_fee: U256::from(997),
+            router_fee_denominator: U256::from(1000),
+            fees0: U256::zero(),
+            fees1: U256::zero(),
+            tradeable: true,
         }
+    }
+
+    // every indexed cycle as pair addresses, reversed to start at the lower address since
+    // which direction is indexed depends on the pointers
+    fn cycles_by_address(state: &State) -> Vec<(Address, Vec<Address>)> {
+        let mut cycles: Vec<(Address, Vec<Address>)> = state
+            .cycles_mapping
+            .iter()
+            .flat_map(|(pair, cycles)| {
+                cycles.iter().map(move |cycle| {
+                    let mut path: Vec<Address> = cycle
+                        .iter()
+                        .map(|p| state.index_mapping[&p.address])
+                        .collect();
+                    if path[0] > path[path.len() - 1] {
+                        path.reverse();
+                    }
+                    (*pair, path)
+                })
+            })
+            .collect();
+        cycles.sort();
+        cycles
+    }
+
+    #[test]
+    fn added_pair_matches_full_rebuild() {
+        let weth = helpers::address(WETH);
+        let token_a = Address::from_low_u64_be(0xa);
+        let token_b = Address::from_low_u64_be(0xb);
+        let token_c = Address::from_low_u64_be(0xc);
+
+        let pairs = vec![
+            pool(1, weth, token_a),
+            pool(2, token_a, weth),
+            pool(3, weth, token_b),
+            pool(4, token_b, token_c),
+            pool(5, token_a, token_b),
+            pool(6, token_c, weth),
+            pool(7, token_a, token_c),
+        ];
+
+        // token_b as a second start token roots the cycles through it that avoid WETH
+        let start_tokens = [vec![weth], vec![weth, token_b], vec![token_b, weth]];
+        let searches = (2..=5).flat_map(|max_hops| {
+            start_tokens.iter().map(move |start_tokens| CycleSearch {
+                max_hops,
+                min_reserve: U256::zero(),
+                start_tokens: start_tokens.clone(),
+            })
+        });
+
+        for (added, search) in (0..pairs.len()).flat_map(|added| searches.clone().map(move |search| (added, search))) {
+            let mut existing = pairs.clone();
+            let new_pair = existing.remove(added);
+
+            let mut state = State::new_state(&existing, U64::zero(), search.clone());
+            let new_cycles = state.add_pair(new_pair.clone()).unwrap();
+            let rebuilt = State::new_state(&pairs, U64::zero(), search);
+
+            assert_eq!(cycles_by_address(&state), cycles_by_address(&rebuilt));
+            assert_eq!(
+                new_cycles,
+                rebuilt.cycles_mapping.get(&new_pair.address).map_or(0, Vec::len)
+            );
+            assert!(state.add_pair(new_pair).is_none());
+        }
+    }
+
+    #[test]
+    fn checkpoint_keeps_untradeable_pools() {
+        let weth = helpers::address(WETH);
+        let token_a = Address::from_low_u64_be(0xa);
+        let token_b = Address::from_low_u64_be(0xb);
+
+        let mut pools = vec![pool(1, weth, token_a), pool(2, token_a, weth), pool(3, weth, token_b)];
+        pools[1].tradeable = false;
+        let mut state = State::new_state(&pools, U64::from(100), CycleSearch::default());
+
+        // the untradeable pair is tracked but no cycle runs through it
+        assert!(state.cycles_mapping.get(&pools[1].address).is_none());
+        assert!(state.set_reserves(state.address_mapping[&pools[1].address], U256::one(), U256::one()));
+
+        let mut created = pool(4, token_a, token_b);
+        created.tradeable = false;
+        assert_eq!(state.add_pair(created.clone()), Some(0));
+        assert!(state.add_pair(created).is_none());
+
+        let checkpoint = state.checkpoint();
+        let addresses: Vec<Address> = checkpoint.pools.iter().map(|pool| pool.address).collect();
+        assert_eq!(addresses, (1..=4).map(Address::from_low_u64_be).collect::<Vec<_>>());
+        assert!(!checkpoint.pools[1].tradeable);
+        assert_eq!(checkpoint.pools[1].reserve0, U256::one());
+    }
+
+    #[test]
+    fn enabled_pair_matches_full_rebuild() {
+        let weth = helpers::address(WETH);
+        let token_a = Address::from_low_u64_be(0xa);
+        let pools = vec![pool(1, weth, token_a), pool(2, token_a, weth), pool(3, weth, token_a)];
+
+        let mut unfunded = pools.clone();
+        unfunded[1].tradeable = false;
+        let mut state = State::new_state(&unfunded, U64::from(100), CycleSearch::default());
+        let rebuilt = State::new_state(&pools, U64::from(100), CycleSearch::default());
+
+        assert!(state.enable_pair(&unfunded[1]).is_none());
+        let new_cycles = state.enable_pair(&pools[1]).unwrap();
+        assert_eq!(new_cycles, rebuilt.cycles_mapping[&pools[1].address].len());
+        assert_eq!(cycles_by_address(&state), cycles_by_address(&rebuilt));
+
+        // a traded pair is not indexed twice
+        assert!(state.enable_pair(&pools[1]).is_none());
+        assert!(state.enable_pair(&pool(4, weth, token_a)).is_none());
+    }
+
+    #[test]
+    fn retaxed_pools_keep_their_reserves() {
+        let weth = helpers::address(WETH);
+        let token_a = Address::from_low_u64_be(0xa);
+        let token_b = Address::from_low_u64_be(0xb);
+
+        let pools = vec![pool(1, weth, token_a), pool(2, token_a, token_b), pool(3, weth, token_b)];
+        let mut state = State::new_state(&pools, U64::from(100), CycleSearch::default());
+
+        let touched: Vec<Address> = state.pools_of(&[token_a]).iter().map(|pool| pool.address).collect();
+        assert_eq!(touched, vec![pools[0].address, pools[1].address]);
+
+        let mut retaxed = pools[1].clone();
+        retaxed.fees0 = U256::from(500);
+        retaxed.tradeable = false;
+        retaxed.reserve0 = U256::one();
+        assert!(state.set_taxes(&retaxed));
+        assert!(!state.set_taxes(&pool(4, weth, token_a)));
+
+        let updated = state.pool(state.address_mapping[&retaxed.address]).unwrap();
+        assert_eq!((updated.fees0, updated.tradeable), (U256::from(500), false));
+        assert_eq!(updated.reserve0, U256::exp10(24));
+    }
+
+    #[tokio::test]
+    async fn snapshots_only_see_published_versions() {
+        let weth = helpers::address(WETH);
+        let pools = [pool(1, weth, Address::from_low_u64_be(0xa))];
+        let shared = SharedState::new(State::new_state(&pools, U64::from(100), CycleSearch::default()));
+        let before = shared.snapshot();
+
+        let mut writer = shared.writer().await;
+        writer.set_reserves(0, U256::one(), U256::one());
+        drop(writer);
+        assert_eq!(shared.snapshot().version, 0);
+        assert_eq!(shared.snapshot().reserves(0), Some([U256::exp10(24); 2]));
+
+        let mut writer = shared.writer().await;
+        writer.set_reserves(0, U256::from(2), U256::from(3));
+        writer.synced_block = U64::from(101);
+        writer.publish();
+
+        let after = shared.snapshot();
+        assert_eq!(after.version, 1);
+        assert_eq!(after.reserves(0), Some([U256::from(2), U256::from(3)]));
+        assert_eq!(after.pool(0).unwrap().reserve1, U256::from(3));
+        assert_eq!(before.reserves(0), Some([U256::exp10(24); 2]));
+        assert_eq!(before.synced_block, U64::from(100));
+    }
 
-        state.real_reserve_state.borrow_mut().clear();
+    #[test]
+    fn pending_state_falls_through_to_base() {
+        let weth = helpers::address(WETH);
+        let pools = [
+            pool(1, weth, Address::from_low_u64_be(0xa)),
+            pool(2, weth, Address::from_low_u64_be(0xb)),
+        ];
+        let state = State::new_state(&pools, U64::from(100), CycleSearch::default());
+        let touched = state.address_mapping[&pools[0].address];
+        let untouched = state.address_mapping[&pools[1].address];
+
+        let update = |address: Address, reserve0: u64, reserve1: u64| StateUpdateInternal {
+            address,
+            reserve0: U256::from(reserve0),
+            reserve1: U256::from(reserve1),
+        };
+
+        let mut pending = PendingState::new(&state);
+        pending.apply(vec![
+            update(pools[0].address, 1, 1),
+            update(pools[0].address, 2, 3),
+            update(weth, 1, 1),
+        ]);
+
+        assert_eq!(pending.reserves(touched), Some([U256::from(2), U256::from(3)]));
+        assert_eq!(pending.pool(touched).unwrap().reserve1, U256::from(3));
+        assert_eq!(pending.reserves(untouched), Some([U256::exp10(24); 2]));
+        assert_eq!(pending.reserves(state.address_mapping[&weth]), None);
+        assert_eq!(state.reserves(touched), Some([U256::exp10(24); 2]));
     }
 }
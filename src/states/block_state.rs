@@ -4,6 +4,8 @@ use ethers::prelude::*;
 use tokio::sync::RwLock;
 
 use crate::states::bot_state::BotState;
+use crate::simulation::SIMULATION_GAS_LIMIT;
+use crate::states::wallet_state::WalletPool;
 
 #[derive(Debug, Clone, Default)]
 pub struct BlockInfo {
@@ -70,7 +72,11 @@ pub struct BlockOracle {
 
 impl BlockOracle {
     // Create new latest block oracle
-    pub async fn new(client: Arc<Provider<Ws>>, bot_state: Arc<BotState>) -> Result<Arc<RwLock<Self>>, ProviderError> {
+    pub async fn new(
+        client: Arc<Provider<Ws>>,
+        bot_state: Arc<BotState>,
+        wallets: Arc<WalletPool>,
+    ) -> Result<Arc<RwLock<Self>>, ProviderError> {
         let latest_block = match client.get_block(BlockNumber::Latest).await {
             Ok(b) => b,
             Err(e) => return Err(e),
@@ -101,12 +107,17 @@ impl BlockOracle {
             next_block,
         }));
 
-        Self::start(oracle.clone(), client.clone(), bot_state).await;
+        Self::start(oracle.clone(), client.clone(), bot_state, wallets).await;
 
         Ok(oracle.clone())
     }
 
-    async fn start(oracle: Arc<RwLock<BlockOracle>>, client: Arc<Provider<Ws>>, bot_state: Arc<BotState>) {
+    async fn start(
+        oracle: Arc<RwLock<BlockOracle>>,
+        client: Arc<Provider<Ws>>,
+        bot_state: Arc<BotState>,
+        wallets: Arc<WalletPool>,
+    ) {
         tokio::task::spawn(async move {
             // loop so we can reconnect if the websocket connection is lost
             loop {
@@ -117,10 +128,12 @@ impl BlockOracle {
                 };
 
                 while let Some(block) = block_stream.next().await {
+                    let number = block.number.unwrap();
+
                     // lock the RwLock for write access and update the variable
-                    {
+                    let next_base_fee = {
                         let mut lock = oracle.write().await;
-                        lock.update_block_number(block.number.unwrap());
+                        lock.update_block_number(number);
                         lock.update_block_timestamp(block.timestamp);
                         lock.update_base_fee(block);
                     
@@ -134,13 +147,17 @@ impl BlockOracle {
                             latest_block.number, latest_block.timestamp, latest_block.base_fee, next_block.number, next_block.timestamp, next_block.base_fee
                         )
                         );
-                                       
-                    
-                    } // remove write lock due to being out of scope here
+
+                        next_block.base_fee
+                    }; // remove write lock due to being out of scope here
 
                     //updating token ammount
                     bot_state.update_multicall_balance().await;
 
+                    // bundles for this block landed or never will
+                    let gas_cost = U256::from(SIMULATION_GAS_LIMIT) * next_base_fee;
+                    wallets.sync(client.as_ref(), number, gas_cost).await;
+
                     
                 }
             }
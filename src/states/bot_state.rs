@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use dashmap::DashMap;
 use crate::utils;
@@ -13,6 +14,8 @@ use tokio::sync::RwLock;
 /// Holds the state of the bot
 pub struct BotState {
    pub multicall_balance: DashMap<Address, Arc<RwLock<U256>>>,
+   multicall_address: Address,
+   client: Arc<Provider<Ws>>,
 }
 
 impl BotState {
@@ -21,11 +24,12 @@ impl BotState {
     // Arguments:
     // * `sandwich_inception_block`: block number sandwich was deployed
     // * `client`: websocket provider to use for fetching data
+    // * `multicall_address`: executor contract holding the inventory
     //
     // Returns:
     // Ok(BotState) if successful
     // Err(eyre::Error) if failed to create instance
-    pub async fn new(client: &Arc<Provider<Ws>>) -> Result<Self> {
+    pub async fn new(client: &Arc<Provider<Ws>>, multicall_address: Address) -> Result<Self> {
         
         let origin_tokens = constants::get_token_address();
         let multicall_balance = DashMap::new();
@@ -36,7 +40,7 @@ impl BotState {
                 utils::get_erc20_contract(&token, &client);
 
             let token_balance = token_contract
-                .balance_of(utils::dotenv::get_multicall_contract_address())
+                .balance_of(multicall_address)
                 .call()
                 .await?;
             
@@ -54,11 +58,24 @@ impl BotState {
 
         Ok(BotState {
             multicall_balance,
+            multicall_address,
+            client: client.clone(),
         })
     }
 
     
 
+    // Balance of every base token held by the executor
+    pub async fn inventory(&self) -> HashMap<Address, U256> {
+        let mut inventory = HashMap::new();
+
+        for (token, balance) in self.multicall_balance.to_owned().into_iter() {
+            inventory.insert(token, *balance.read().await);
+        }
+
+        inventory
+    }
+
     // Update the WETH balance of the contract
     //
     // Arguments:
@@ -70,14 +87,11 @@ impl BotState {
         for (token, balance) in self.multicall_balance.to_owned().into_iter() {
 
             
-            let client = utils::create_websocket_client()
-                                                  .await.unwrap();
-
             let token_contract =
-            utils::get_erc20_contract(&token, &client);
+            utils::get_erc20_contract(&token, &self.client);
 
             let token_balance = token_contract
-                .balance_of(utils::dotenv::get_multicall_contract_address())
+                .balance_of(self.multicall_address)
                 .call()
                 .await.unwrap();
                 
@@ -1,2 +1,4 @@
 pub mod block_state;
 pub mod bot_state;
+pub mod nonce_state;
+pub mod wallet_state;
@@ -0,0 +1,331 @@
+use std::sync::Mutex;
+
+use ethers::prelude::*;
+
+/// Nonces of one bundle, consecutive from `first`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonceReservation {
+    pub first: U256,
+    pub count: usize,
+    /// Block the bundle targets, it can't land once that block is mined
+    pub target_block: U64,
+}
+
+impl NonceReservation {
+    /// Nonce of the bundle's `index`th transaction
+    pub fn nonce(&self, index: usize) -> U256 {
+        self.first + index
+    }
+
+    /// First nonce after the reservation
+    pub fn end(&self) -> U256 {
+        self.first + self.count
+    }
+}
+
+#[derive(Debug)]
+struct Nonces {
+    // transaction count of the wallet, every nonce below it is used
+    on_chain: U256,
+    reservations: Vec<NonceReservation>,
+}
+
+impl Nonces {
+    fn next(&self) -> U256 {
+        self.reservations
+            .iter()
+            .map(NonceReservation::end)
+            .fold(self.on_chain, U256::max)
+    }
+}
+
+/// Hands out the nonces of a searcher wallet to bundles
+///
+/// A bundle reserves its nonces after every reservation still pending, so bundles sent
+/// concurrently never share one. A reservation is released when its bundle is not included,
+/// and on every block the manager reconciles with the wallet's transaction count: reservations
+/// below it landed, those for mined blocks never will, and the next nonce falls back to the
+/// first one still free.
+#[derive(Debug)]
+pub struct NonceManager {
+    address: Address,
+    nonces: Mutex<Nonces>,
+}
+
+impl NonceManager {
+    // Create a new `NonceManager` instance
+    //
+    // Arguments:
+    // * `address`: the wallet signing the bundles
+    // * `on_chain`: its transaction count
+    pub fn new(address: Address, on_chain: U256) -> Self {
+        Self {
+            address,
+            nonces: Mutex::new(Nonces {
+                on_chain,
+                reservations: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Nonce the next reservation starts at
+    pub fn next(&self) -> U256 {
+        self.lock().next()
+    }
+
+    /// Reserve `count` nonces for a bundle targeting `target_block`
+    pub fn reserve(&self, count: usize, target_block: U64) -> NonceReservation {
+        let mut nonces = self.lock();
+        let reservation = NonceReservation {
+            first: nonces.next(),
+            count,
+            target_block,
+        };
+        nonces.reservations.push(reservation.clone());
+        reservation
+    }
+
+    /// No bundle of the wallet is waiting on its block
+    pub fn is_idle(&self) -> bool {
+        self.lock().reservations.is_empty()
+    }
+
+    /// Reserve like `reserve`, only when no other bundle is pending
+    pub fn reserve_if_idle(&self, count: usize, target_block: U64) -> Option<NonceReservation> {
+        let mut nonces = self.lock();
+        if !nonces.reservations.is_empty() {
+            return None;
+        }
+
+        let reservation = NonceReservation {
+            first: nonces.on_chain,
+            count,
+            target_block,
+        };
+        nonces.reservations.push(reservation.clone());
+        Some(reservation)
+    }
+
+    /// Give back the nonces of a bundle that was not included
+    ///
+    /// Nonces reserved after it stay taken until their own bundles resolve, the gap closes
+    /// once they do.
+    pub fn release(&self, reservation: &NonceReservation) {
+        self.lock().reservations.retain(|pending| pending != reservation);
+    }
+
+    /// The bundle landed, its nonces are used
+    pub fn confirm(&self, reservation: &NonceReservation) {
+        let mut nonces = self.lock();
+        nonces.on_chain = nonces.on_chain.max(reservation.end());
+        nonces.reservations.retain(|pending| pending != reservation);
+    }
+
+    /// Align with the wallet's transaction count as of `block`
+    pub fn reconcile(&self, on_chain: U256, block: U64) {
+        let mut nonces = self.lock();
+        nonces.on_chain = on_chain;
+        nonces
+            .reservations
+            .retain(|pending| pending.target_block > block && pending.end() > on_chain);
+    }
+
+    /// Fetch the wallet's transaction count at `block` and reconcile with it
+    pub async fn sync<M: Middleware>(&self, client: &M, block: U64) -> Result<(), M::Error> {
+        let on_chain = client
+            .get_transaction_count(self.address, Some(BlockId::from(block)))
+            .await?;
+        self.reconcile(on_chain, block);
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Nonces> {
+        self.nonces.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A reservation whose bundle went to several relays, resolved once across all of them
+///
+/// The first relay to land the bundle confirms the nonces. They are only released once every
+/// relay missed the block or failed to take the bundle.
+#[derive(Debug)]
+pub struct RelayedReservation {
+    reservation: NonceReservation,
+    // relays yet to report, and whether the reservation was confirmed or released
+    pending: Mutex<(usize, bool)>,
+}
+
+impl RelayedReservation {
+    pub fn new(reservation: NonceReservation, relays: usize) -> Self {
+        Self {
+            reservation,
+            pending: Mutex::new((relays, false)),
+        }
+    }
+
+    /// A relay landed the bundle, true when this confirmed the nonces
+    pub fn landed(&self, nonces: &NonceManager) -> bool {
+        let mut pending = self.lock();
+        pending.0 = pending.0.saturating_sub(1);
+        if pending.1 {
+            return false;
+        }
+
+        pending.1 = true;
+        nonces.confirm(&self.reservation);
+        true
+    }
+
+    /// A relay missed the block or never took the bundle, true when this released the nonces
+    pub fn missed(&self, nonces: &NonceManager) -> bool {
+        let mut pending = self.lock();
+        pending.0 = pending.0.saturating_sub(1);
+        if pending.1 || pending.0 > 0 {
+            return false;
+        }
+
+        pending.1 = true;
+        nonces.release(&self.reservation);
+        true
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, (usize, bool)> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn manager(on_chain: u64) -> Arc<NonceManager> {
+        Arc::new(NonceManager::new(Address::from_low_u64_be(0x5e), U256::from(on_chain)))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_bundles_get_disjoint_nonces() {
+        let nonces = manager(7);
+
+        let sends = (0..64)
+            .map(|bundle| {
+                let nonces = nonces.clone();
+                tokio::spawn(async move {
+                    let reservation = nonces.reserve(1 + bundle % 3, U64::from(100));
+                    tokio::task::yield_now().await;
+                    reservation
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut reservations = Vec::new();
+        for send in sends {
+            reservations.push(send.await.unwrap());
+        }
+        reservations.sort_by_key(|reservation| reservation.first);
+
+        // back to back from the wallet's count, no nonce handed out twice
+        let mut expected = U256::from(7);
+        for reservation in reservations.iter() {
+            assert_eq!(reservation.first, expected);
+            expected = reservation.end();
+        }
+        assert_eq!(nonces.next(), expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn missed_bundles_give_their_nonces_back() {
+        let nonces = manager(0);
+
+        // every bundle misses its block
+        let sends = (0..32)
+            .map(|_| {
+                let nonces = nonces.clone();
+                tokio::spawn(async move {
+                    let reservation = nonces.reserve(2, U64::from(100));
+                    tokio::task::yield_now().await;
+                    nonces.release(&reservation);
+                })
+            })
+            .collect::<Vec<_>>();
+        for send in sends {
+            send.await.unwrap();
+        }
+
+        assert_eq!(nonces.next(), U256::zero());
+    }
+
+    #[test]
+    fn released_nonces_are_reused_once_later_bundles_resolve() {
+        let nonces = manager(10);
+        let first = nonces.reserve(2, U64::from(100));
+        let second = nonces.reserve(1, U64::from(100));
+        assert_eq!((first.nonce(0), first.nonce(1), second.nonce(0)), (10.into(), 11.into(), 12.into()));
+
+        // the gap stays while a later reservation is pending
+        nonces.release(&first);
+        assert_eq!(nonces.next(), U256::from(13));
+
+        nonces.release(&second);
+        assert_eq!(nonces.next(), U256::from(10));
+    }
+
+    #[test]
+    fn reconciles_with_the_chain_on_each_block() {
+        let nonces = manager(10);
+        // two bundles for block 100, one for 101
+        nonces.reserve(2, U64::from(100));
+        nonces.reserve(1, U64::from(100));
+        let later = nonces.reserve(1, U64::from(101));
+
+        // block 100 included the first bundle only
+        nonces.reconcile(U256::from(12), U64::from(100));
+        assert_eq!(nonces.next(), later.end());
+
+        // block 101 included nothing, the wallet's count wins
+        nonces.reconcile(U256::from(12), U64::from(101));
+        assert_eq!(nonces.next(), U256::from(12));
+
+        // a landed bundle advances the nonce before the next block is seen
+        let next = nonces.reserve(3, U64::from(102));
+        nonces.confirm(&next);
+        assert_eq!(nonces.next(), U256::from(15));
+    }
+
+    #[test]
+    fn relayed_bundles_resolve_their_nonces_once() {
+        let nonces = manager(10);
+
+        // one relay lands it between two misses, the nonces stay used
+        let landed = RelayedReservation::new(nonces.reserve(2, U64::from(100)), 3);
+        assert!(!landed.missed(&nonces));
+        assert!(landed.landed(&nonces));
+        assert!(!landed.missed(&nonces));
+        assert_eq!(nonces.next(), U256::from(12));
+        assert!(nonces.is_idle());
+
+        // nothing is given back while a relay may still land it
+        let missed = RelayedReservation::new(nonces.reserve(1, U64::from(101)), 2);
+        assert!(!missed.missed(&nonces));
+        assert_eq!(nonces.next(), U256::from(13));
+        assert!(missed.missed(&nonces));
+        assert_eq!(nonces.next(), U256::from(12));
+    }
+
+    #[tokio::test]
+    async fn syncs_from_the_provider() {
+        let (provider, mock) = Provider::mocked();
+        mock.push::<U256, _>(U256::from(42)).unwrap();
+
+        let nonces = manager(0);
+        nonces.reserve(1, U64::from(5));
+        nonces.sync(&provider, U64::from(5)).await.unwrap();
+
+        assert_eq!(nonces.next(), U256::from(42));
+    }
+}
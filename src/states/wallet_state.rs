@@ -0,0 +1,211 @@
+use std::sync::{Arc, RwLock};
+
+use ethers::prelude::*;
+use ethers::utils::format_units;
+use futures::future::join_all;
+
+use crate::signer::BotSigner;
+use crate::states::nonce_state::{NonceManager, NonceReservation};
+
+/// A wallet signing bundles, with its own nonces
+#[derive(Debug)]
+pub struct SearcherWallet {
+    pub signer: BotSigner,
+    pub nonces: NonceManager,
+    // ETH held, refreshed every block
+    balance: RwLock<U256>,
+}
+
+impl SearcherWallet {
+    pub fn new(signer: BotSigner, nonce: U256, balance: U256) -> Self {
+        Self {
+            nonces: NonceManager::new(signer.address(), nonce),
+            signer,
+            balance: RwLock::new(balance),
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.signer.address()
+    }
+
+    pub fn balance(&self) -> U256 {
+        *self.balance.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Reconcile the nonces and refresh the balance as of `block`
+    pub async fn sync<M: Middleware>(&self, client: &M, block: U64) -> Result<(), M::Error> {
+        self.nonces.sync(client, block).await?;
+        let balance = client
+            .get_balance(self.address(), Some(BlockId::from(block)))
+            .await?;
+
+        *self.balance.write().unwrap_or_else(|e| e.into_inner()) = balance;
+        Ok(())
+    }
+}
+
+/// Searcher wallets bundles are spread over
+///
+/// A wallet has at most one bundle pending, so bundles sent for the same block never wait
+/// on each other's nonces.
+#[derive(Debug)]
+pub struct WalletPool {
+    wallets: Vec<Arc<SearcherWallet>>,
+}
+
+impl WalletPool {
+    pub fn new(wallets: Vec<SearcherWallet>) -> Self {
+        Self {
+            wallets: wallets.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// Pool of `signers` with their current nonces and balances
+    pub async fn connect<M: Middleware>(signers: Vec<BotSigner>, client: &M) -> Result<Self, M::Error> {
+        let mut wallets = Vec::with_capacity(signers.len());
+        for signer in signers {
+            let nonce = client.get_transaction_count(signer.address(), None).await?;
+            let balance = client.get_balance(signer.address(), None).await?;
+            wallets.push(SearcherWallet::new(signer, nonce, balance));
+        }
+
+        Ok(Self::new(wallets))
+    }
+
+    pub fn wallets(&self) -> &[Arc<SearcherWallet>] {
+        &self.wallets
+    }
+
+    /// The richest idle wallet holding at least `cost`, without claiming it
+    pub fn idle(&self, cost: U256) -> Option<Arc<SearcherWallet>> {
+        self.by_balance()
+            .into_iter()
+            .find(|wallet| wallet.balance() >= cost && wallet.nonces.is_idle())
+    }
+
+    /// Claim an idle wallet holding at least `cost` for a bundle of `count` transactions
+    /// targeting `target_block`, richest first
+    pub fn claim(
+        &self,
+        count: usize,
+        target_block: U64,
+        cost: U256,
+    ) -> Option<(Arc<SearcherWallet>, NonceReservation)> {
+        self.by_balance()
+            .into_iter()
+            .filter(|wallet| wallet.balance() >= cost)
+            .find_map(|wallet| {
+                let reservation = wallet.nonces.reserve_if_idle(count, target_block)?;
+                Some((wallet, reservation))
+            })
+    }
+
+    /// Sync every wallet at `block` and report its balance, warning about those that can't
+    /// pay `gas_cost`
+    pub async fn sync<M: Middleware>(&self, client: &M, block: U64, gas_cost: U256) {
+        let synced = join_all(self.wallets.iter().map(|wallet| wallet.sync(client, block))).await;
+
+        for (wallet, synced) in self.wallets.iter().zip(synced) {
+            if let Err(e) = synced {
+                log::warn!("Failed to sync searcher wallet {:?}: {}", wallet.address(), e);
+                continue;
+            }
+
+            let balance = format_units(wallet.balance(), "ether").unwrap_or_default();
+            if wallet.balance() < gas_cost {
+                log::warn!(
+                    "Searcher wallet {:?} holds {} ETH, not enough to pay gas",
+                    wallet.address(),
+                    balance
+                );
+            } else {
+                log::info!("Searcher wallet {:?}: {} ETH", wallet.address(), balance);
+            }
+        }
+    }
+
+    fn by_balance(&self) -> Vec<Arc<SearcherWallet>> {
+        let mut wallets = self.wallets.clone();
+        wallets.sort_by_key(|wallet| std::cmp::Reverse(wallet.balance()));
+        wallets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(balances: &[u64]) -> Arc<WalletPool> {
+        let wallets = balances
+            .iter()
+            .map(|balance| {
+                let signer = LocalWallet::new(&mut rand::thread_rng());
+                SearcherWallet::new(signer.into(), U256::zero(), U256::from(*balance))
+            })
+            .collect();
+        Arc::new(WalletPool::new(wallets))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_bundles_get_their_own_wallet() {
+        let wallets = pool(&[100; 4]);
+
+        let sends = (0..8)
+            .map(|_| {
+                let wallets = wallets.clone();
+                tokio::spawn(async move {
+                    let claimed = wallets.claim(2, U64::from(100), U256::from(10));
+                    tokio::task::yield_now().await;
+                    claimed.map(|(wallet, reservation)| (wallet.address(), reservation))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut claimed = Vec::new();
+        for send in sends {
+            claimed.extend(send.await.unwrap());
+        }
+
+        // one bundle per wallet, the rest find none idle
+        assert_eq!(claimed.len(), 4);
+        claimed.sort_by_key(|(address, _)| *address);
+        claimed.dedup_by_key(|(address, _)| *address);
+        assert_eq!(claimed.len(), 4);
+        assert!(claimed.iter().all(|(_, reservation)| reservation.first.is_zero()));
+        assert!(wallets.idle(U256::zero()).is_none());
+    }
+
+    #[test]
+    fn picks_idle_wallets_that_can_pay() {
+        let wallets = pool(&[5, 50, 20]);
+        let richest = wallets.wallets()[1].address();
+
+        let (first, reservation) = wallets.claim(1, U64::from(100), U256::from(10)).unwrap();
+        assert_eq!(first.address(), richest);
+
+        // the poorest can't pay, the richest is busy
+        let (second, _) = wallets.claim(1, U64::from(100), U256::from(10)).unwrap();
+        assert_eq!(second.address(), wallets.wallets()[2].address());
+        assert!(wallets.claim(1, U64::from(100), U256::from(10)).is_none());
+
+        // a missed bundle frees its wallet
+        first.nonces.release(&reservation);
+        assert_eq!(wallets.idle(U256::from(10)).unwrap().address(), richest);
+    }
+
+    #[tokio::test]
+    async fn syncs_nonce_and_balance() {
+        let (provider, mock) = Provider::mocked();
+        // answered last in, first out: the transaction count, then the balance
+        mock.push::<U256, _>(U256::exp10(18)).unwrap();
+        mock.push::<U256, _>(U256::from(9)).unwrap();
+
+        let wallets = pool(&[0]);
+        wallets.sync(&provider, U64::from(100), U256::exp10(15)).await;
+
+        let wallet = &wallets.wallets()[0];
+        assert_eq!(wallet.balance(), U256::exp10(18));
+        assert_eq!(wallet.nonces.next(), U256::from(9));
+    }
+}
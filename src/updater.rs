@@ -1,19 +1,22 @@
 use ethers::prelude::*;
+use ethers::providers::JsonRpcClient;
+use ethers::types::Log;
 use hex;
 use log::*;
-use std::{sync::Arc, time::Instant};
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{collections::HashMap, sync::Arc, time::Instant};
 
-use crate::{constants::SYNC_TOPIC, state::State};
+use crate::contract_modules::uniswap_v2::types::UniV2Pool;
+use crate::reorg::{JournalEntry, ReorgJournal, REORG_JOURNAL_DEPTH};
+use crate::{constants::SYNC_TOPIC, state::SharedState};
 
-pub async fn start_updater(ws_provider: Arc<Provider<Ws>>, state: Arc<Mutex<State>>, from: U64) {
-    let now = Instant::now();
+/// Blocks per eth_getLogs request
+const SYNC_LOG_RANGE: u64 = 1000;
 
-    let decoded = hex::decode(SYNC_TOPIC).unwrap();
-    let sync_topic = H256::from_slice(&decoded);
+pub async fn start_updater(mut updater: BlockUpdater<Ws>, from: U64) {
+    let now = Instant::now();
 
-    let mut from = from;
-    let block = match ws_provider.get_block_number().await {
+    let block = match updater.provider.get_block_number().await {
         Ok(d) => d,
         Err(error) => {
             error!("An error occurred: {}", error);
@@ -21,83 +24,552 @@ pub async fn start_updater(ws_provider: Arc<Provider<Ws>>, state: Arc<Mutex<Stat
         }
     };
 
-    while from < block {
-        update_block(ws_provider.clone(), state.clone(), from, sync_topic).await;
-        from += U64::one();
+    if let Err(error) = updater.catch_up(from, block).await {
+        error!("Failed to sync blocks {} to {}: {}", from, block, error);
     }
 
     info!(
         "State updates from bot sync completed | Took: {:?}",
         now.elapsed()
     );
-    loop_blocks(ws_provider, state, sync_topic).await;
+    loop_blocks(updater).await;
 }
 
-pub async fn loop_blocks(
-    ws_provider: Arc<Provider<Ws>>,
-    state: Arc<Mutex<State>>,
-    sync_topic: H256,
-) {
+pub async fn loop_blocks(mut updater: BlockUpdater<Ws>) {
     info!("Block updater started");
-    let mut subscription = ws_provider.subscribe_blocks().await.unwrap();
-    loop {
-        if let Some(block) = subscription.next().await {
-            update_block(
-                ws_provider.clone(),
-                state.clone(),
-                block.number.unwrap(),
-                sync_topic,
-            )
-            .await;
-            // info!("------{:?}------",block.number);
+    let provider = updater.provider.clone();
+    let mut subscription = provider.subscribe_blocks().await.unwrap();
+    while let Some(block) = subscription.next().await {
+        let (number, hash) = match (block.number, block.hash) {
+            (Some(number), Some(hash)) => (number, hash),
+            _ => continue,
+        };
+
+        if let Err(error) = updater.on_new_head(number, hash, block.parent_hash).await {
+            error!("Failed to apply block {}: {}", number, error);
         }
     }
 }
 
-async fn update_block(
-    ws_provider: Arc<Provider<Ws>>,
-    state: Arc<Mutex<State>>,
-    block: U64,
-    sync_topic: H256,
-) {
-    let block = match ws_provider.get_block(block).await {
-        Ok(Some(d)) => d,
-        Ok(None) => return,
-        Err(error) => {
-            println!("An error occurred: {}", error);
-            return;
+/// Keeps the reserves in `State` on the canonical chain
+///
+/// Every applied block is journaled with the reserves it overwrote. When a head does not
+/// build on the journal, its ancestors are fetched until one matches, the orphaned blocks
+/// are rolled back and the canonical ones applied in their place.
+pub struct BlockUpdater<P> {
+    provider: Arc<Provider<P>>,
+    state: Arc<SharedState>,
+    journal: ReorgJournal,
+    reorgs: Arc<AtomicU64>,
+}
+
+impl<P: JsonRpcClient> BlockUpdater<P> {
+    pub fn new(provider: Arc<Provider<P>>, state: Arc<SharedState>) -> Self {
+        Self {
+            provider,
+            state,
+            journal: ReorgJournal::new(REORG_JOURNAL_DEPTH),
+            reorgs: Arc::new(AtomicU64::new(0)),
         }
-    };
-    let txes = block.transactions;
+    }
+
+    /// Number of reorgs rolled back so far
+    pub fn reorg_counter(&self) -> Arc<AtomicU64> {
+        self.reorgs.clone()
+    }
+
+    /// Apply `from..=to` with ranged queries and restart the journal at `to`
+    pub async fn catch_up(&mut self, from: U64, to: U64) -> Result<(), ProviderError> {
+        update_range(&self.provider, &self.state, from, to).await?;
 
-    for tx in txes {
-        let tx_receipt = match ws_provider.get_transaction_receipt(tx).await {
-            Ok(tx) => tx,
-            Err(_) => continue,
+        let block = self
+            .provider
+            .get_block(to)
+            .await?
+            .ok_or_else(|| ProviderError::CustomError(format!("Block {} not found", to)))?;
+
+        self.journal.clear();
+        self.journal.push(JournalEntry::new(
+            to,
+            block.hash.unwrap_or_default(),
+            block.parent_hash,
+        ));
+        Ok(())
+    }
+
+    pub async fn on_new_head(
+        &mut self,
+        number: U64,
+        hash: H256,
+        parent_hash: H256,
+    ) -> Result<(), ProviderError> {
+        let journal_head = match self.journal.head() {
+            Some(head) => head.number,
+            None => return self.catch_up(number, number).await,
         };
-        if let Some(full_tx) = tx_receipt {
-            let state_unlocked: tokio::sync::MutexGuard<State> = state.lock().await;
 
-            let logs = full_tx.logs;
-            for log in logs {
-                let pointer = match state_unlocked.address_mapping.get(&log.address) {
+        if self.journal.hash_at(number) == Some(hash) {
+            return Ok(());
+        }
+
+        if number > journal_head + REORG_JOURNAL_DEPTH {
+            warn!(
+                "Missed {} blocks, catching up without reorg protection",
+                number - journal_head
+            );
+            return self.catch_up(journal_head + 1, number).await;
+        }
+
+        // canonical blocks missing from the journal, newest first
+        let mut blocks = vec![(number, hash, parent_hash)];
+        let mut deep = false;
+        loop {
+            let (child_number, _, parent) = *blocks.last().unwrap();
+            let parent_number = child_number - 1;
+
+            if self.journal.hash_at(parent_number) == Some(parent) {
+                break;
+            }
+            if self
+                .journal
+                .oldest()
+                .map_or(true, |oldest| parent_number < oldest.number)
+            {
+                deep = true;
+                break;
+            }
+
+            let block = self
+                .provider
+                .get_block(parent)
+                .await?
+                .ok_or_else(|| ProviderError::CustomError(format!("Block {:?} not found", parent)))?;
+            blocks.push((parent_number, parent, block.parent_hash));
+        }
+        blocks.reverse();
+
+        let mut block_logs = Vec::with_capacity(blocks.len());
+        for (_, hash, _) in blocks.iter() {
+            block_logs.push(fetch_block_sync_logs(&self.provider, *hash).await?);
+        }
+
+        let ancestor = blocks[0].0 - 1;
+        let mut state = self.state.writer().await;
+
+        let mut orphaned = 0;
+        while self.journal.head().is_some_and(|head| head.number > ancestor) {
+            if let Some(entry) = self.journal.pop() {
+                entry.rollback(&mut state);
+                orphaned += 1;
+            }
+        }
+
+        if orphaned > 0 {
+            let count = self.reorgs.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "Reorg #{}: rolled back {} blocks to {}, applying {} canonical blocks",
+                count,
+                orphaned,
+                ancestor,
+                blocks.len()
+            );
+        }
+        if deep {
+            error!(
+                "Reorg deeper than the {} block journal, reserves touched before block {} may be stale",
+                REORG_JOURNAL_DEPTH,
+                ancestor + 1
+            );
+        }
+
+        for ((block_number, block_hash, block_parent), logs) in blocks.into_iter().zip(block_logs) {
+            let mut entry = JournalEntry::new(block_number, block_hash, block_parent);
+            for log in logs.iter() {
+                let pointer = match state.address_mapping.get(&log.address) {
                     Some(d) => *d,
                     None => continue,
                 };
+                if let Some((reserve0, reserve1)) = decode_sync(log) {
+                    entry.apply_sync(&mut state, pointer, reserve0, reserve1);
+                }
+            }
+            self.journal.push(entry);
+        }
 
-                for topic in log.topics {
-                    if topic == sync_topic {
-                        let mut pair = match state_unlocked.pairs_mapping.get(&pointer) {
-                            Some(p) => p.borrow_mut(),
-                            None => continue,
-                        };
+        state.synced_block = number;
+        state.publish();
+        Ok(())
+    }
+}
 
-                        pair.reserve0 = U256::from_big_endian(&log.data[0..32]);
-                        pair.reserve1 = U256::from_big_endian(&log.data[32..]);
-                    }
-                }
+/// Apply the Sync logs of `from..=to` to `state` under a single lock
+pub async fn update_range<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    state: &SharedState,
+    from: U64,
+    to: U64,
+) -> Result<usize, ProviderError> {
+    let logs = fetch_sync_logs(provider, from, to).await?;
+
+    let mut state = state.writer().await;
+    let mut applied = 0;
+
+    for log in logs.iter() {
+        let pointer = match state.address_mapping.get(&log.address) {
+            Some(d) => *d,
+            None => continue,
+        };
+        let (reserve0, reserve1) = match decode_sync(log) {
+            Some(d) => d,
+            None => continue,
+        };
+
+        if state.set_reserves(pointer, reserve0, reserve1) {
+            applied += 1;
+        }
+    }
+
+    state.synced_block = to;
+    state.publish();
+    Ok(applied)
+}
+
+/// Every Sync log in `from..=to` in chain order
+pub async fn fetch_sync_logs<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    from: U64,
+    to: U64,
+) -> Result<Vec<Log>, ProviderError> {
+    let sync_topic = H256::from_slice(&hex::decode(SYNC_TOPIC).unwrap());
+    fetch_logs(provider, &Filter::new().topic0(sync_topic), from, to).await
+}
+
+/// Logs matching `filter` in `from..=to` in chain order, one eth_getLogs per `SYNC_LOG_RANGE`
+/// blocks
+///
+/// A range the provider rejects, usually for matching more logs than it returns at once, is
+/// split in half and each half fetched on its own. Only a single block that still fails is
+/// an error.
+pub async fn fetch_logs<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    filter: &Filter,
+    from: U64,
+    to: U64,
+) -> Result<Vec<Log>, ProviderError> {
+    let mut logs = Vec::new();
+    // ranges left to fetch, the next one last
+    let mut ranges = Vec::new();
+    let mut start = from.as_u64();
+
+    while start <= to.as_u64() {
+        let end = (start + SYNC_LOG_RANGE - 1).min(to.as_u64());
+        ranges.push((start, end));
+        start = end + 1;
+    }
+    ranges.reverse();
+
+    while let Some((start, end)) = ranges.pop() {
+        let range_filter = filter.clone().from_block(start).to_block(end);
+
+        match provider.get_logs(&range_filter).await {
+            Ok(found) => logs.extend(found),
+            Err(error) if start < end => {
+                let middle = start + (end - start) / 2;
+                debug!("Logs of blocks {} to {} rejected, splitting: {}", start, end, error);
+                ranges.push((middle + 1, end));
+                ranges.push((start, middle));
             }
-            
+            Err(error) => return Err(error),
         }
     }
+
+    // the last Sync of a pair has to win
+    logs.sort_by_key(|log| (log.block_number, log.log_index));
+    Ok(logs)
+}
+
+/// Reserves `pair` was left with by its last Sync log in `from..=to`, `None` without one
+pub async fn last_sync<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    pair: Address,
+    from: U64,
+    to: U64,
+) -> Result<Option<(U256, U256)>, ProviderError> {
+    let sync_topic = H256::from_slice(&hex::decode(SYNC_TOPIC).unwrap());
+    let filter = Filter::new().address(pair).topic0(sync_topic);
+
+    let logs = fetch_logs(provider, &filter, from, to).await?;
+    Ok(logs.iter().rev().find_map(decode_sync))
+}
+
+/// Sync logs of one block by hash, so logs of an orphaned block at that height are never mixed in
+pub async fn fetch_block_sync_logs<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    hash: H256,
+) -> Result<Vec<Log>, ProviderError> {
+    let sync_topic = H256::from_slice(&hex::decode(SYNC_TOPIC).unwrap());
+    let filter = Filter::new().at_block_hash(hash).topic0(sync_topic);
+
+    let mut logs = provider.get_logs(&filter).await?;
+    logs.sort_by_key(|log| log.log_index);
+    Ok(logs)
+}
+
+/// Apply every Sync log in `from..=to` to `pools`, used to bring a checkpoint up to date
+/// without enumerating the factories again
+pub async fn replay_sync_logs(
+    ws_provider: Arc<Provider<Ws>>,
+    pools: &mut [UniV2Pool],
+    from: U64,
+    to: U64,
+) -> Result<(), ProviderError> {
+    let now = Instant::now();
+
+    let pointers: HashMap<Address, usize> = pools
+        .iter()
+        .enumerate()
+        .map(|(index, pool)| (pool.address, index))
+        .collect();
+
+    let logs = fetch_sync_logs(&ws_provider, from, to).await?;
+    let mut applied = 0;
+
+    for log in logs.iter() {
+        let pointer = match pointers.get(&log.address) {
+            Some(d) => *d,
+            None => continue,
+        };
+        let (reserve0, reserve1) = match decode_sync(log) {
+            Some(d) => d,
+            None => continue,
+        };
+
+        pools[pointer].reserve0 = reserve0;
+        pools[pointer].reserve1 = reserve1;
+        applied += 1;
+    }
+
+    info!(
+        "Replayed {} Sync logs from block {} to {} | Took: {:?}",
+        applied,
+        from,
+        to,
+        now.elapsed()
+    );
+    Ok(())
+}
+
+fn decode_sync(log: &Log) -> Option<(U256, U256)> {
+    if log.data.len() < 64 {
+        return None;
+    }
+
+    Some((
+        U256::from_big_endian(&log.data[0..32]),
+        U256::from_big_endian(&log.data[32..64]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::WETH;
+    use ethers::providers::{JsonRpcError, MockResponse};
+    use crate::helpers::address;
+    use crate::cycles::CycleSearch;
+    use crate::state::State;
+
+    fn sync_log(pair: Address, block: u64, log_index: u64, reserve0: u64, reserve1: u64) -> Log {
+        Log {
+            address: pair,
+            topics: vec![H256::from_slice(&hex::decode(SYNC_TOPIC).unwrap())],
+            data: ethers::abi::encode(&[
+                ethers::abi::Token::Uint(U256::from(reserve0)),
+                ethers::abi::Token::Uint(U256::from(reserve1)),
+            ])
+            .into(),
+            block_number: Some(U64::from(block)),
+            log_index: Some(U256::from(log_index)),
+            ..Default::default()
+        }
+    }
+
+    fn pool(pair: Address) -> UniV2Pool {
+        UniV2Pool {
+            address: pair,
+            token0: address(WETH),
+            token1: Address::from_low_u64_be(2),
+            reserve0: U256::one(),
+            reserve1: U256::one(),
+            router_fee: U256::from(997),
+            router_fee_denominator: U256::from(1000),
+            fees0: U256::zero(),
+            fees1: U256::zero(),
+            tradeable: true,
+        }
+    }
+
+    fn block(number: u64, hash: u64, parent_hash: u64) -> Block<H256> {
+        Block {
+            number: Some(U64::from(number)),
+            hash: Some(H256::from_low_u64_be(hash)),
+            parent_hash: H256::from_low_u64_be(parent_hash),
+            ..Default::default()
+        }
+    }
+
+    fn reserves(state: &SharedState, pair: Address) -> (u64, u64) {
+        let state = state.snapshot();
+        let [reserve0, reserve1] = state.reserves(state.address_mapping[&pair]).unwrap();
+        (reserve0.as_u64(), reserve1.as_u64())
+    }
+
+    // updater with its journal started at block 100 (hash 0xa100)
+    async fn updater(pairs: &[Address]) -> (BlockUpdater<MockProvider>, MockProvider, Arc<SharedState>) {
+        let pools: Vec<UniV2Pool> = pairs.iter().map(|pair| pool(*pair)).collect();
+        let state = Arc::new(SharedState::new(State::new_state(&pools, U64::from(100), CycleSearch::default())));
+
+        let (provider, mock) = Provider::mocked();
+        let mut updater = BlockUpdater::new(Arc::new(provider), state.clone());
+
+        // the mock answers last pushed first
+        mock.push::<Block<H256>, _>(block(100, 0xa100, 0xa099)).unwrap();
+        mock.push::<Vec<Log>, _>(vec![]).unwrap();
+        updater.catch_up(U64::from(100), U64::from(100)).await.unwrap();
+
+        (updater, mock, state)
+    }
+
+    #[tokio::test]
+    async fn applies_range_in_log_order_with_one_request() {
+        let pair = Address::from_low_u64_be(1);
+        let state = SharedState::new(State::new_state(&[pool(pair)], U64::from(99), CycleSearch::default()));
+
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Vec<Log>, _>(vec![
+            sync_log(pair, 101, 0, 30, 40),
+            sync_log(pair, 100, 7, 10, 20),
+            sync_log(Address::from_low_u64_be(9), 101, 1, 1, 1),
+        ])
+        .unwrap();
+
+        let applied = update_range(&provider, &state, U64::from(100), U64::from(101))
+            .await
+            .unwrap();
+
+        assert_eq!(applied, 2);
+        assert_eq!(reserves(&state, pair), (30, 40));
+        assert_eq!(state.snapshot().synced_block, U64::from(101));
+
+        // nothing but the single ranged query was sent
+        let filter = Filter::new()
+            .from_block(100)
+            .to_block(101)
+            .topic0(H256::from_slice(&hex::decode(SYNC_TOPIC).unwrap()));
+        mock.assert_request("eth_getLogs", [filter]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_ranges_are_split_in_half() {
+        let pair = Address::from_low_u64_be(1);
+        let sync_topic = H256::from_slice(&hex::decode(SYNC_TOPIC).unwrap());
+        let (provider, mock) = Provider::mocked();
+
+        // answered last in, first out: 100..=103 is rejected, 100..=101 and then 102..=103 answer
+        mock.push::<Vec<Log>, _>(vec![sync_log(pair, 103, 0, 30, 40)]).unwrap();
+        mock.push::<Vec<Log>, _>(vec![sync_log(pair, 100, 0, 10, 20)]).unwrap();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32005,
+            message: "query returned more than 10000 results".to_string(),
+            data: None,
+        }));
+
+        let logs = fetch_sync_logs(&provider, U64::from(100), U64::from(103)).await.unwrap();
+        let blocks: Vec<Option<U64>> = logs.iter().map(|log| log.block_number).collect();
+        assert_eq!(blocks, vec![Some(U64::from(100)), Some(U64::from(103))]);
+
+        for (from, to) in [(100, 103), (100, 101), (102, 103)] {
+            let filter = Filter::new().from_block(from).to_block(to).topic0(sync_topic);
+            mock.assert_request("eth_getLogs", [filter]).unwrap();
+        }
+
+        // a single block the provider rejects is an error
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32005,
+            message: "query returned more than 10000 results".to_string(),
+            data: None,
+        }));
+        assert!(fetch_sync_logs(&provider, U64::from(100), U64::from(100)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn last_sync_of_a_pair_wins() {
+        let pair = Address::from_low_u64_be(1);
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Vec<Log>, _>(vec![sync_log(pair, 102, 3, 30, 40), sync_log(pair, 102, 1, 10, 20)])
+            .unwrap();
+
+        let sync = last_sync(&provider, pair, U64::from(101), U64::from(102)).await.unwrap();
+        assert_eq!(sync, Some((U256::from(30), U256::from(40))));
+
+        mock.push::<Vec<Log>, _>(Vec::<Log>::new()).unwrap();
+        assert_eq!(last_sync(&provider, pair, U64::from(103), U64::from(103)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reorg_rolls_back_orphaned_blocks() {
+        let orphaned_pair = Address::from_low_u64_be(1);
+        let canonical_pair = Address::from_low_u64_be(3);
+        let (mut updater, mock, state) = updater(&[orphaned_pair, canonical_pair]).await;
+
+        // 101 (0xb101) only touches the first pair
+        mock.push::<Vec<Log>, _>(vec![sync_log(orphaned_pair, 101, 0, 10, 10)]).unwrap();
+        updater
+            .on_new_head(U64::from(101), H256::from_low_u64_be(0xb101), H256::from_low_u64_be(0xa100))
+            .await
+            .unwrap();
+        assert_eq!(reserves(&state, orphaned_pair), (10, 10));
+
+        // 102 (0xc102) builds on a sibling 101 (0xc101) that only touches the second pair
+        mock.push::<Vec<Log>, _>(vec![sync_log(canonical_pair, 102, 0, 30, 40)]).unwrap();
+        mock.push::<Vec<Log>, _>(vec![]).unwrap();
+        mock.push::<Block<H256>, _>(block(101, 0xc101, 0xa100)).unwrap();
+        updater
+            .on_new_head(U64::from(102), H256::from_low_u64_be(0xc102), H256::from_low_u64_be(0xc101))
+            .await
+            .unwrap();
+
+        assert_eq!(reserves(&state, orphaned_pair), (1, 1));
+        assert_eq!(reserves(&state, canonical_pair), (30, 40));
+        assert_eq!(state.snapshot().synced_block, U64::from(102));
+        assert_eq!(updater.reorg_counter().load(Ordering::Relaxed), 1);
+        assert_eq!(
+            updater.journal.hash_at(U64::from(101)),
+            Some(H256::from_low_u64_be(0xc101))
+        );
+    }
+
+    #[tokio::test]
+    async fn replaced_head_is_rolled_back_and_duplicates_ignored() {
+        let pair = Address::from_low_u64_be(1);
+        let (mut updater, mock, state) = updater(&[pair]).await;
+        let (b101, d101, a100) = (
+            H256::from_low_u64_be(0xb101),
+            H256::from_low_u64_be(0xd101),
+            H256::from_low_u64_be(0xa100),
+        );
+
+        mock.push::<Vec<Log>, _>(vec![sync_log(pair, 101, 0, 10, 10)]).unwrap();
+        updater.on_new_head(U64::from(101), b101, a100).await.unwrap();
+
+        // a repeated head sends no requests, the mock would fail with nothing queued
+        updater.on_new_head(U64::from(101), b101, a100).await.unwrap();
+        assert_eq!(updater.reorg_counter().load(Ordering::Relaxed), 0);
+
+        mock.push::<Vec<Log>, _>(vec![]).unwrap();
+        updater.on_new_head(U64::from(101), d101, a100).await.unwrap();
+
+        assert_eq!(reserves(&state, pair), (1, 1));
+        assert_eq!(updater.reorg_counter().load(Ordering::Relaxed), 1);
+    }
 }
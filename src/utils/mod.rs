@@ -1,5 +1,3 @@
-pub mod dotenv;
-
 use ethers::{prelude::*, types::transaction::eip2718::TypedTransaction};
 use std::{
     collections::{btree_map::Entry, BTreeMap},
@@ -107,10 +105,10 @@ fn extract_logs(call_frame: &CallFrame, logs: &mut Vec<CallLogFrame>) {
 }
 
 // Sign eip1559 transactions
-pub async fn sign_eip1559(
+pub async fn sign_eip1559<S: Signer>(
     tx: Eip1559TransactionRequest,
-    signer_wallet: &LocalWallet,
-) -> Result<Bytes, WalletError> {
+    signer_wallet: &S,
+) -> Result<Bytes, S::Error> {
     let tx_typed = TypedTransaction::Eip1559(tx);
     let signed_frontrun_tx_sig = match signer_wallet.sign_transaction(&tx_typed).await {
         Ok(s) => s,
@@ -120,12 +118,6 @@ pub async fn sign_eip1559(
     Ok(tx_typed.rlp_signed(&signed_frontrun_tx_sig))
 }
 
-/// Create Websocket Client
-pub async fn create_websocket_client() -> eyre::Result<Arc<Provider<Ws>>> {
-    let client = dotenv::get_ws_provider().await;
-    Ok(Arc::new(client))
-}
-
 pub async fn get_nonce(
     client: &Arc<Provider<Ws>>,
     address: Address,